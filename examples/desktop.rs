@@ -1,34 +1,58 @@
+use std::sync::Arc;
 use std::time::Instant;
 
+use embedded_graphics::mono_font::{ascii::FONT_6X10, MonoTextStyle};
+use embedded_graphics::pixelcolor::{Rgb888, RgbColor};
+use embedded_graphics::prelude::*;
+use embedded_graphics::text::Text;
 use rusterer::framebuffer::Framebuffer;
-use rusterer::geometry::{Mesh, AnimatedMesh};
+use rusterer::geometry::{Mesh, AnimatedMesh, Vec3d};
+use rusterer::light::{Light, ShadingMode};
 use minifb::{Key, Window, WindowOptions};
 use rusterer::renderer::Renderer;
+use rusterer::texture::{Sampler, WrapMode};
 use rusterer::texture_helper::get_texture_from_bmp;
 const WIDTH: usize = 1600;
 const HEIGHT: usize = 900;
 
 fn main() {
     let mut framebuffer: Framebuffer = Framebuffer::new(WIDTH, HEIGHT);
-    let mesh_texture = get_texture_from_bmp(include_bytes!("../demo_objects/floating_islands_demo_texture.bmp"));
-    let mut mesh_list: Vec<Mesh> = Vec::new();
-    mesh_list.push(Mesh::new(&mesh_texture, include_bytes!("../demo_objects/floating_islands_demo.obj")));
+    let mut mesh_texture = get_texture_from_bmp(include_bytes!("../demo_objects/floating_islands_demo_texture.bmp"));
+    //Build the mip chain so the distant islands don't shimmer.
+    mesh_texture.generate_mipmaps();
+    let mesh_texture = Arc::new(mesh_texture);
+    let mut islands = Mesh::new(mesh_texture, include_bytes!("../demo_objects/floating_islands_demo.obj"));
+    islands.transform.set_position(0.0, 0.0, 5.0);
+    //The demo objects have no vertex normals, so smooth them for Gouraud shading.
+    islands.compute_smooth_normals(1.0);
+    //Filter the low resolution island texture so it doesn't look blocky up close, and blend its
+    //mipmaps so it doesn't shimmer far away.
+    islands.sampler = Sampler::trilinear(WrapMode::Repeat);
+    let mesh_list: Vec<Mesh> = vec![islands];
 
-    let mut animated_mesh_list: Vec<AnimatedMesh> = Vec::new();
-    let mut animated_mech: Vec<Vec<u8>> = Vec::new();
     let human_frame_1 = include_bytes!("../demo_objects/low_poly_human/low_poly_human1.obj");
     let human_frame_2 = include_bytes!("../demo_objects/low_poly_human/low_poly_human2.obj");
     let human_frame_3 = include_bytes!("../demo_objects/low_poly_human/low_poly_human3.obj");
     let human_frame_4 = include_bytes!("../demo_objects/low_poly_human/low_poly_human4.obj");
-    let human_texture = get_texture_from_bmp(include_bytes!("../demo_objects/low_poly_human/low_poly_human_texture.bmp"));
-    animated_mech.push(human_frame_1.to_vec());
-    animated_mech.push(human_frame_2.to_vec());    
-    animated_mech.push(human_frame_3.to_vec());
-    animated_mech.push(human_frame_4.to_vec());
-    animated_mesh_list.push(AnimatedMesh::new(&human_texture,animated_mech,5.0, true));
+    let human_texture = Arc::new(get_texture_from_bmp(include_bytes!("../demo_objects/low_poly_human/low_poly_human_texture.bmp")));
+    let animated_mech: Vec<Vec<u8>> = vec![
+        human_frame_1.to_vec(),
+        human_frame_2.to_vec(),
+        human_frame_3.to_vec(),
+        human_frame_4.to_vec(),
+    ];
+    let mut human = AnimatedMesh::new(human_texture,animated_mech,5.0, true);
+    human.transform.set_position(0.0, 0.0, 5.0);
+    human.compute_smooth_normals(1.0);
+    let animated_mesh_list: Vec<AnimatedMesh> = vec![human];
 
     let mut renderer = Renderer::new(mesh_list, animated_mesh_list, WIDTH, HEIGHT, 0x00ace6);
 
+    //Light the scene with a soft ambient light and a sun shining past the camera.
+    renderer.set_shading_mode(ShadingMode::Gouraud);
+    renderer.add_light(Light::ambient(0.5));
+    renderer.add_light(Light::directional(Vec3d { x: 0.3, y: -0.5, z: 1.0, ..Default::default() }, 0.8));
+
     //Give some perspective for the demo.
     renderer.translate_camera_y(-12.0);
     renderer.translate_camera_backward(75.0,1.0);
@@ -43,6 +67,9 @@ fn main() {
         panic!("{}", e);
     });
 
+    //Overlay the controls on every frame with embedded-graphics.
+    let controls = Text::new("WASD to move, UP and DOWN to fly", Point::new(8, 16), MonoTextStyle::new(&FONT_6X10, Rgb888::WHITE));
+
     let mut now = Instant::now();
     while window.is_open() && !window.is_key_down(Key::Escape) {
 
@@ -77,6 +104,7 @@ fn main() {
         }
 
         renderer.render(time_elapsed, &mut framebuffer);
+        controls.draw(&mut framebuffer).unwrap();
 
         window
             .update_with_buffer(framebuffer.get_framebuffer(), WIDTH, HEIGHT)
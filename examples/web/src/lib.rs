@@ -1,27 +1,22 @@
 use minifb::{Window, WindowOptions};
-use rusterer::texture::Texture;
 use std::cell::RefCell;
 use std::panic;
 use std::rc::Rc;
+use std::sync::Arc;
 use instant::Instant;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
-use lazy_static::lazy_static;
 use rusterer::framebuffer::Framebuffer;
 use rusterer::geometry::Mesh;
 use rusterer::geometry::AnimatedMesh;
+use rusterer::geometry::Vec3d;
+use rusterer::light::{Light, ShadingMode};
 use minifb::Key;
 use rusterer::renderer::Renderer;
+use rusterer::texture::{Sampler, WrapMode};
 use rusterer::texture_helper::get_texture_from_bmp;
 const WIDTH: usize = 1000;
 const HEIGHT: usize = 1000;
-lazy_static!
-    {
-        static ref MESH_TEXTURE: Texture = get_texture_from_bmp(include_bytes!("../../../demo_objects/floating_islands_demo_texture.bmp"));
-        static ref MECH_TEXTURE: Texture = get_texture_from_bmp(include_bytes!("../../../demo_objects/low_poly_human/low_poly_human_texture.bmp"));
-
-    }
-
 
 fn window() -> web_sys::Window {
     web_sys::window().expect("no global `window` exists")
@@ -38,8 +33,20 @@ pub fn main() {
     panic::set_hook(Box::new(console_error_panic_hook::hook));
 
     let mut framebuffer: Framebuffer = Framebuffer::new(WIDTH, HEIGHT);
+    let mut mesh_texture = get_texture_from_bmp(include_bytes!("../../../demo_objects/floating_islands_demo_texture.bmp"));
+    //Build the mip chain so the distant islands don't shimmer.
+    mesh_texture.generate_mipmaps();
+    let mesh_texture = Arc::new(mesh_texture);
+    let human_texture = Arc::new(get_texture_from_bmp(include_bytes!("../../../demo_objects/low_poly_human/low_poly_human_texture.bmp")));
     let mut mesh_list: Vec<Mesh> = Vec::new();
-    mesh_list.push(Mesh::new(&MESH_TEXTURE, include_bytes!("../../../demo_objects/floating_islands_demo.obj")));
+    let mut islands = Mesh::new(mesh_texture, include_bytes!("../../../demo_objects/floating_islands_demo.obj"));
+    islands.transform.set_position(0.0, 0.0, 5.0);
+    //The demo objects have no vertex normals, so smooth them for Gouraud shading.
+    islands.compute_smooth_normals(1.0);
+    //Filter the low resolution island texture so it doesn't look blocky up close, and blend its
+    //mipmaps so it doesn't shimmer far away.
+    islands.sampler = Sampler::trilinear(WrapMode::Repeat);
+    mesh_list.push(islands);
 
     let mut animated_mesh_list: Vec<AnimatedMesh> = Vec::new();
     let mut animated_human: Vec<Vec<u8>> = Vec::new();
@@ -51,9 +58,17 @@ pub fn main() {
     animated_human.push(human_frame_2.to_vec());
     animated_human.push(human_frame_3.to_vec());
     animated_human.push(human_frame_4.to_vec());
-    animated_mesh_list.push(AnimatedMesh::new(&MECH_TEXTURE,animated_human.clone(),5.0, true));
+    let mut human = AnimatedMesh::new(human_texture,animated_human.clone(),5.0, true);
+    human.transform.set_position(0.0, 0.0, 5.0);
+    human.compute_smooth_normals(1.0);
+    animated_mesh_list.push(human);
 
     let mut renderer = Renderer::new(mesh_list, animated_mesh_list.clone(), WIDTH, HEIGHT, 0xffe6ac00);
+
+    //Light the scene with a soft ambient light and a sun shining past the camera.
+    renderer.set_shading_mode(ShadingMode::Gouraud);
+    renderer.add_light(Light::ambient(0.5));
+    renderer.add_light(Light::directional(Vec3d { x: 0.3, y: -0.5, z: 1.0, ..Default::default() }, 0.8));
     
     //Give some perspective for the demo.
     renderer.translate_camera_y(-12.0);
@@ -0,0 +1,205 @@
+use crate::geometry::{
+    matrix_make_orthographic, matrix_make_projection, matrix_make_rotation_x,
+    matrix_make_rotation_y, matrix_make_rotation_z, matrix_multiply_matrix, matrix_multiply_vector,
+    matrix_point_at, matrix_quick_inverse, vector_add, vector_cross_product, vector_mul,
+    vector_normalize, vector_sub, Mat4x4, Vec3d,
+};
+
+/// The projection a 'Camera' uses to flatten the scene onto the screen.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Projection {
+    /// A perspective projection using the camera's field of view.
+    Perspective,
+    /// A parallel projection showing 'height' world units from the bottom to the top of the view.
+    Orthographic { height: f32 },
+}
+
+/// A Camera object holding the position, orientation and lens settings the scene is viewed with.
+/// 'yaw', 'pitch' and 'roll' are in radians. A positive 'pitch' looks up and 'aspect_ratio' is the
+/// view height divided by the view width.
+#[derive(Debug, Clone)]
+pub struct Camera {
+    pub position: Vec3d,
+    pub yaw: f32,
+    pub pitch: f32,
+    pub roll: f32,
+    pub fov_degrees: f32,
+    pub near: f32,
+    pub far: f32,
+    pub aspect_ratio: f32,
+    pub projection: Projection,
+}
+
+impl Camera {
+    /// Creates a new `Camera` at the origin looking down the z axis, with a 90 degree field of view
+    /// and clip planes at 0.1 and 1000.0.
+    pub fn new(view_width: usize, view_height: usize) -> Camera {
+        Camera {
+            position: Vec3d::default(),
+            yaw: 0.0,
+            pitch: 0.0,
+            roll: 0.0,
+            fov_degrees: 90.0,
+            near: 0.1,
+            far: 1000.0,
+            aspect_ratio: view_height as f32 / view_width as f32,
+            projection: Projection::Perspective,
+        }
+    }
+
+    /// Sets the field of view of the camera to 'fov_degrees'.
+    pub fn set_fov(&mut self, fov_degrees: f32) {
+        self.fov_degrees = fov_degrees;
+    }
+
+    /// Switches the camera to a perspective projection.
+    pub fn set_perspective(&mut self) {
+        self.projection = Projection::Perspective;
+    }
+
+    /// Switches the camera to an orthographic projection showing 'height' world units vertically.
+    pub fn set_orthographic(&mut self, height: f32) {
+        self.projection = Projection::Orthographic { height };
+    }
+
+    /// Sets the near and far clip planes of the camera.
+    pub fn set_clip_planes(&mut self, near: f32, far: f32) {
+        self.near = near;
+        self.far = far;
+    }
+
+    /// Sets the aspect ratio of the camera to match a 'view_width' x 'view_height' viewport.
+    pub fn set_viewport(&mut self, view_width: usize, view_height: usize) {
+        self.aspect_ratio = view_height as f32 / view_width as f32;
+    }
+
+    /// Adjusts the camera yaw by 'yaw_adjustment'.
+    pub fn rotate_yaw(&mut self, yaw_adjustment: f32) {
+        self.yaw += yaw_adjustment;
+    }
+
+    /// Adjusts the camera pitch by 'pitch_adjustment'.
+    pub fn rotate_pitch(&mut self, pitch_adjustment: f32) {
+        self.pitch += pitch_adjustment;
+    }
+
+    /// Adjusts the camera roll by 'roll_adjustment'.
+    pub fn rotate_roll(&mut self, roll_adjustment: f32) {
+        self.roll += roll_adjustment;
+    }
+
+    /// Turns the camera to face 'target', keeping its current roll.
+    pub fn look_at(&mut self, target: &Vec3d) {
+        let direction = vector_normalize(&vector_sub(target, &self.position));
+        if direction.x.is_nan() {
+            return;
+        }
+        self.pitch = direction.y.clamp(-1.0, 1.0).asin();
+        self.yaw = (-direction.x).atan2(direction.z);
+    }
+
+    /// Moves the camera by 'x', 'y' and 'z' in world space.
+    pub fn translate(&mut self, x: f32, y: f32, z: f32) {
+        self.position.x += x;
+        self.position.y += y;
+        self.position.z += z;
+    }
+
+    /// Moves the camera 'distance' along its look direction.
+    pub fn move_forward(&mut self, distance: f32) {
+        let vec_forward = vector_mul(&self.look_direction(), distance);
+        self.position = vector_add(&self.position, &vec_forward);
+    }
+
+    /// Moves the camera 'distance' against its look direction.
+    pub fn move_backward(&mut self, distance: f32) {
+        self.move_forward(-distance);
+    }
+
+    /// Moves the camera 'distance' to its right.
+    pub fn strafe_right(&mut self, distance: f32) {
+        let vec_right = vector_mul(&self.right_direction(), distance);
+        self.position = vector_add(&self.position, &vec_right);
+    }
+
+    /// Moves the camera 'distance' to its left.
+    pub fn strafe_left(&mut self, distance: f32) {
+        self.strafe_right(-distance);
+    }
+
+    /// Returns the rotation matrix of the camera, applying roll, then pitch, then yaw.
+    pub fn rotation_matrix(&self) -> Mat4x4 {
+        let mat_roll = matrix_make_rotation_z(&self.roll);
+        let mat_pitch = matrix_make_rotation_x(&-self.pitch);
+        let mat_yaw = matrix_make_rotation_y(&self.yaw);
+        matrix_multiply_matrix(&matrix_multiply_matrix(&mat_roll, &mat_pitch), &mat_yaw)
+    }
+
+    /// Returns the unit vector the camera is looking along.
+    pub fn look_direction(&self) -> Vec3d {
+        let vec_forward = Vec3d {
+            x: 0.0,
+            y: 0.0,
+            z: 1.0,
+            ..Default::default()
+        };
+        matrix_multiply_vector(&self.rotation_matrix(), &vec_forward)
+    }
+
+    /// Returns the unit vector pointing out of the top of the camera.
+    pub fn up_direction(&self) -> Vec3d {
+        let vec_up = Vec3d {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+            ..Default::default()
+        };
+        matrix_multiply_vector(&self.rotation_matrix(), &vec_up)
+    }
+
+    /// Returns the unit vector pointing to the right of the camera, as seen on screen.
+    pub fn right_direction(&self) -> Vec3d {
+        vector_cross_product(&self.look_direction(), &self.up_direction())
+    }
+
+    /// Returns the view matrix, which moves world space into the camera's space.
+    pub fn view_matrix(&self) -> Mat4x4 {
+        let vec_target = vector_add(&self.position, &self.look_direction());
+        let mat_camera = matrix_point_at(&self.position, &vec_target, &self.up_direction());
+        matrix_quick_inverse(&mat_camera)
+    }
+
+    /// Returns the projection matrix for the camera's projection, aspect ratio and clip planes.
+    pub fn projection_matrix(&self) -> Mat4x4 {
+        match self.projection {
+            Projection::Perspective => {
+                matrix_make_projection(self.fov_degrees, self.aspect_ratio, self.near, self.far)
+            }
+            Projection::Orthographic { height } => {
+                let half_height = height * 0.5;
+                let half_width = half_height / self.aspect_ratio;
+                matrix_make_orthographic(
+                    -half_width,
+                    half_width,
+                    -half_height,
+                    half_height,
+                    self.near,
+                    self.far,
+                )
+            }
+        }
+    }
+
+    /// Converts a value from the renderer's depth buffer back into the distance in front of the
+    /// camera along its look direction, in world units. The depth buffer holds 1 minus the
+    /// normalised depth from the projection matrix, so larger values are nearer, and this undoes
+    /// it. A depth of 0.0, where nothing was drawn, gives the far clip plane.
+    pub fn linear_depth(&self, depth: f32) -> f32 {
+        match self.projection {
+            Projection::Perspective => {
+                self.near * self.far / (self.near + depth * (self.far - self.near))
+            }
+            Projection::Orthographic { .. } => self.near + (1.0 - depth) * (self.far - self.near),
+        }
+    }
+}
@@ -0,0 +1,243 @@
+/// The layout of the pixels in a 'Framebuffer' or 'Texture'. Every pixel is held in a u32, with
+/// formats narrower than 32 bits in the low bits. The default is 'Argb8888', or 'Abgr8888' when
+/// the 'web' feature is enabled so that the little endian bytes can be handed straight to a canvas.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PixelFormat {
+    /// 8 bits each of alpha, red, green and blue, from the most significant byte down.
+    #[cfg_attr(not(feature = "web"), default)]
+    Argb8888,
+    /// 8 bits each of alpha, blue, green and red, from the most significant byte down.
+    #[cfg_attr(feature = "web", default)]
+    Abgr8888,
+    /// 5 bits of red, 6 of green and 5 of blue in the low 16 bits, with no alpha.
+    Rgb565,
+    /// 8 bits of brightness in the low byte, with no alpha.
+    Gray8,
+}
+
+impl PixelFormat {
+    /// Returns the number of bytes each pixel takes up when packed tightly.
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            PixelFormat::Argb8888 | PixelFormat::Abgr8888 => 4,
+            PixelFormat::Rgb565 => 2,
+            PixelFormat::Gray8 => 1,
+        }
+    }
+
+    /// Returns true if pixels in this format have an alpha channel.
+    pub fn has_alpha(&self) -> bool {
+        matches!(self, PixelFormat::Argb8888 | PixelFormat::Abgr8888)
+    }
+
+    /// Packs 8 bit 'r', 'g', 'b' and 'a' channels into a pixel in this format, dropping the
+    /// channels and precision the format doesn't hold.
+    pub fn pack(&self, r: u8, g: u8, b: u8, a: u8) -> u32 {
+        match self {
+            PixelFormat::Argb8888 => {
+                (a as u32) << 24 | (r as u32) << 16 | (g as u32) << 8 | b as u32
+            }
+            PixelFormat::Abgr8888 => {
+                (a as u32) << 24 | (b as u32) << 16 | (g as u32) << 8 | r as u32
+            }
+            PixelFormat::Rgb565 => (r as u32 >> 3) << 11 | (g as u32 >> 2) << 5 | b as u32 >> 3,
+            // Rec. 601 luma weights, scaled to add up to 256.
+            PixelFormat::Gray8 => (r as u32 * 77 + g as u32 * 150 + b as u32 * 29 + 128) >> 8,
+        }
+    }
+
+    /// Unpacks a pixel in this format into its 8 bit 'r', 'g', 'b' and 'a' channels. Formats
+    /// without alpha are fully opaque.
+    pub fn unpack(&self, color: u32) -> (u8, u8, u8, u8) {
+        match self {
+            PixelFormat::Argb8888 => (
+                (color >> 16) as u8,
+                (color >> 8) as u8,
+                color as u8,
+                (color >> 24) as u8,
+            ),
+            PixelFormat::Abgr8888 => (
+                color as u8,
+                (color >> 8) as u8,
+                (color >> 16) as u8,
+                (color >> 24) as u8,
+            ),
+            PixelFormat::Rgb565 => {
+                let (r, g, b) = ((color >> 11) & 0x1f, (color >> 5) & 0x3f, color & 0x1f);
+                (
+                    (r << 3 | r >> 2) as u8,
+                    (g << 2 | g >> 4) as u8,
+                    (b << 3 | b >> 2) as u8,
+                    0xff,
+                )
+            }
+            PixelFormat::Gray8 => (color as u8, color as u8, color as u8, 0xff),
+        }
+    }
+
+    /// Converts the pixel 'color' from this format to 'format'.
+    pub fn convert(&self, color: u32, format: PixelFormat) -> u32 {
+        if *self == format {
+            return color;
+        }
+        let (r, g, b, a) = self.unpack(color);
+        format.pack(r, g, b, a)
+    }
+
+    /// Returns the alpha of the pixel 'color' in this format, between 0 and 255.
+    pub fn alpha(&self, color: u32) -> u8 {
+        match self {
+            PixelFormat::Argb8888 | PixelFormat::Abgr8888 => (color >> 24) as u8,
+            PixelFormat::Rgb565 | PixelFormat::Gray8 => 0xff,
+        }
+    }
+
+    /// Multiplies the red, green and blue channels of the pixel 'color' in this format by
+    /// 'light', see 'color_modulate'.
+    pub fn modulate(&self, color: u32, light: &[f32; 3]) -> u32 {
+        let (r, g, b, a) = self.unpack(color);
+        let scale =
+            |channel: u8, factor: f32| (channel as f32 * factor).round().clamp(0.0, 255.0) as u8;
+        self.pack(
+            scale(r, light[0]),
+            scale(g, light[1]),
+            scale(b, light[2]),
+            a,
+        )
+    }
+
+    /// Combines the pixel 'color' with the pixel 'destination' using 'mode', both in this
+    /// format, or returns 'None' if the pixel is discarded. Pixels in formats without alpha are
+    /// fully opaque. See 'color_blend'.
+    pub fn blend(&self, color: u32, destination: u32, mode: BlendMode) -> Option<u32> {
+        let alpha = self.alpha(color);
+        match mode {
+            BlendMode::Opaque => Some(self.opaque(color)),
+            BlendMode::AlphaTest(cutoff) => {
+                (alpha as f32 / 255.0 >= cutoff).then(|| self.opaque(color))
+            }
+            BlendMode::AlphaBlend => Some(self.mix(color, destination, alpha)),
+            BlendMode::Additive => {
+                let (r, g, b, _) = self.unpack(color);
+                let (dr, dg, db, da) = self.unpack(destination);
+                let alpha = alpha as u32;
+                let add = |s: u8, d: u8| (d as u32 + (s as u32 * alpha + 127) / 255).min(255) as u8;
+                Some(self.pack(add(r, dr), add(g, dg), add(b, db), da))
+            }
+        }
+    }
+
+    /// Mixes the pixel 'color' over the pixel 'destination', both in this format, by 'alpha'
+    /// from 0 for just 'destination' to 255 for just 'color'. The alpha of 'color' is ignored.
+    pub fn mix(&self, color: u32, destination: u32, alpha: u8) -> u32 {
+        let alpha = alpha as u32;
+        let (r, g, b, _) = self.unpack(color);
+        let (dr, dg, db, da) = self.unpack(destination);
+        let mix = |s: u8, d: u8| ((s as u32 * alpha + d as u32 * (255 - alpha) + 127) / 255) as u8;
+        self.pack(mix(r, dr), mix(g, dg), mix(b, db), mix(0xff, da))
+    }
+
+    /// Returns the pixel 'color' in this format with its alpha set to fully opaque.
+    fn opaque(&self, color: u32) -> u32 {
+        match self {
+            PixelFormat::Argb8888 | PixelFormat::Abgr8888 => color | 0xff00_0000,
+            PixelFormat::Rgb565 | PixelFormat::Gray8 => color,
+        }
+    }
+}
+
+/// Packs 8 bit 'r', 'g', 'b' and 'a' channels into a pixel in the default 'PixelFormat'.
+pub fn color_pack(r: u8, g: u8, b: u8, a: u8) -> u32 {
+    PixelFormat::default().pack(r, g, b, a)
+}
+
+/// Unpacks a pixel in the default 'PixelFormat' into its 8 bit 'r', 'g', 'b' and 'a' channels.
+pub fn color_unpack(color: u32) -> (u8, u8, u8, u8) {
+    PixelFormat::default().unpack(color)
+}
+
+/// Multiplies the red, green and blue channels of 'color' in the default 'PixelFormat' by
+/// 'light', saturating at full brightness. Alpha is left untouched.
+pub fn color_modulate(color: u32, light: &[f32; 3]) -> u32 {
+    PixelFormat::default().modulate(color, light)
+}
+
+/// How the pixels of a triangle are combined with the pixels already in the framebuffer. The
+/// alpha channel of the texture or fragment shader color gives the coverage of each pixel.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum BlendMode {
+    /// Pixels replace the framebuffer color and are drawn fully opaque, ignoring their alpha.
+    #[default]
+    Opaque,
+    /// Pixels with an alpha below the cutoff, between 0 and 1, are discarded and the rest are
+    /// drawn opaque. Suits foliage and fences with hard edged cutouts.
+    AlphaTest(f32),
+    /// Pixels are mixed with the framebuffer color by their alpha, like glass.
+    AlphaBlend,
+    /// Pixels scaled by their alpha are added to the framebuffer color, like fire or glows.
+    Additive,
+}
+
+impl BlendMode {
+    /// Returns true if triangles drawn with this mode let the pixels behind them show through.
+    /// These triangles don't write to the depth buffer and are drawn back-to-front after every
+    /// other triangle.
+    pub fn is_transparent(&self) -> bool {
+        matches!(self, BlendMode::AlphaBlend | BlendMode::Additive)
+    }
+}
+
+/// Combines the pixel 'color' with the framebuffer pixel 'destination' using 'mode', or returns
+/// 'None' if the pixel is discarded. Both pixels are in the default 'PixelFormat'.
+pub fn color_blend(color: u32, destination: u32, mode: BlendMode) -> Option<u32> {
+    PixelFormat::default().blend(color, destination, mode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blend_modes_in_argb() {
+        let format = PixelFormat::Argb8888;
+        let color = format.pack(200, 100, 0, 128);
+        let destination = format.pack(0, 0, 200, 0xff);
+        assert_eq!(
+            format.blend(color, destination, BlendMode::Opaque),
+            Some(format.pack(200, 100, 0, 0xff))
+        );
+        assert_eq!(
+            format.blend(color, destination, BlendMode::AlphaTest(0.6)),
+            None
+        );
+        assert_eq!(
+            format.blend(color, destination, BlendMode::AlphaBlend),
+            Some(format.pack(100, 50, 100, 0xff))
+        );
+        assert_eq!(
+            format.blend(color, destination, BlendMode::Additive),
+            Some(format.pack(100, 50, 200, 0xff))
+        );
+    }
+
+    #[test]
+    fn blend_formats_without_alpha_are_opaque() {
+        for format in [PixelFormat::Rgb565, PixelFormat::Gray8] {
+            let color = format.pack(255, 255, 255, 0);
+            let destination = format.pack(0, 0, 0, 0xff);
+            assert_eq!(
+                format.blend(color, destination, BlendMode::AlphaBlend),
+                Some(color)
+            );
+            assert_eq!(
+                format.mix(color, destination, 0),
+                destination,
+                "{:?}",
+                format
+            );
+        }
+        let format = PixelFormat::Rgb565;
+        let mixed = format.mix(format.pack(248, 0, 0, 0), format.pack(0, 0, 248, 0), 128);
+        assert_eq!(format.unpack(mixed), (132, 0, 123, 0xff));
+    }
+}
@@ -1,7 +1,121 @@
-use crate::{framebuffer::Framebuffer, texture::Texture};
+use std::{borrow::Cow, ops::Range};
+
+use crate::{
+    color::{BlendMode, PixelFormat},
+    framebuffer::Framebuffer,
+    geometry::{Triangle, Vec2d, Vec3d},
+    shader::{Fragment, FragmentShader, ATTRIBUTE_COUNT},
+    texture::{Sampler, Texture},
+};
+
+/// The algorithm used to fill triangles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RasteriserMode {
+    /// Walk the rows of each triangle between its edges, see 'draw_textured_triangle'.
+    #[default]
+    Scanline,
+    /// Test every pixel in each triangle's bounding box, see 'draw_edge_function_triangle'.
+    EdgeFunction,
+}
+
+/// A band of rows of a framebuffer and its matching depth buffer for the rasterisers to draw
+/// into. Pixels outside the band are skipped, so separate bands can be filled at the same time.
+/// 'pixels' are laid out in 'format'.
+pub(crate) struct RasterTarget<'a> {
+    pub pixels: &'a mut [u32],
+    pub format: PixelFormat,
+    pub depth: &'a mut [f32],
+    pub width: i64,
+    pub rows: Range<i64>,
+}
+
+impl<'a> RasterTarget<'a> {
+    /// Creates a new `RasterTarget` over 'rows' of a 'width' wide image. 'pixels' and 'depth'
+    /// hold just those rows.
+    pub fn new(
+        pixels: &'a mut [u32],
+        format: PixelFormat,
+        depth: &'a mut [f32],
+        width: i64,
+        rows: Range<i64>,
+    ) -> Self {
+        RasterTarget {
+            pixels,
+            format,
+            depth,
+            width,
+            rows,
+        }
+    }
+
+    /// Creates a new `RasterTarget` covering every row of 'framebuffer'. 'depth' must be the same
+    /// size as the framebuffer, which is 'width' pixels wide.
+    pub fn full(framebuffer: &'a mut Framebuffer, depth: &'a mut [f32], width: i64) -> Self {
+        let height = depth.len() as i64 / width;
+        let format = framebuffer.format();
+        RasterTarget::new(
+            framebuffer.get_framebuffer_mut(),
+            format,
+            depth,
+            width,
+            0..height,
+        )
+    }
+
+    /// Returns the index of pixel 'x', 'y' in the band, or 'None' if it lies outside it.
+    pub fn index(&self, x: i64, y: i64) -> Option<usize> {
+        if x < 0 || x >= self.width || !self.rows.contains(&y) {
+            return None;
+        }
+        Some(((y - self.rows.start) * self.width + x) as usize)
+    }
+
+    /// Combines 'color', laid out in the format of the target, with the pixel at 'index' using
+    /// 'blend'. The depth buffer is set to 'depth' unless the pixel is discarded or 'blend' is
+    /// transparent.
+    pub fn put_pixel(&mut self, index: usize, color: u32, depth: f32, blend: BlendMode) {
+        let destination = self.pixels[index];
+        if let Some(color) = self.format.blend(color, destination, blend) {
+            self.pixels[index] = color;
+            if !blend.is_transparent() {
+                self.depth[index] = depth;
+            }
+        }
+    }
+}
+
+/// Fill 'tri' into the rows of 'target' using the rasteriser for 'mode', coloring the pixels
+/// with 'shader' if there is one and combining them with the framebuffer using 'blend'. 'tex' is
+/// read through 'sampler'.
+pub(crate) fn fill_triangle(
+    mode: RasteriserMode,
+    tri: &Triangle,
+    tex: &Texture,
+    sampler: Sampler,
+    shader: Option<&dyn FragmentShader>,
+    blend: BlendMode,
+    target: &mut RasterTarget,
+) {
+    match (mode, shader) {
+        (RasteriserMode::EdgeFunction, shader) => {
+            fill_edge_function_triangle(tri, tex, sampler, shader, blend, target)
+        }
+        (RasteriserMode::Scanline, Some(shader)) => {
+            fill_shaded_triangle(tri, tex, sampler, shader, blend, target)
+        }
+        (RasteriserMode::Scanline, None) => {
+            fill_textured_triangle(tri, tex, sampler, blend, target)
+        }
+    }
+}
+
+/// The number of fractional bits kept in the fixed point screen coordinates used by
+/// 'draw_edge_function_triangle'.
+pub const SUBPIXEL_BITS: u32 = 8;
 
 /// A function for drawing a 2d colored line between (x0,y0) and (x1,y1) and outputting the result to
-/// a framebuffer.
+/// a framebuffer. The line is clipped to the framebuffer first, so only its visible pixels are
+/// visited.
 pub fn draw_line(
     x0_param: i32,
     y0_param: i32,
@@ -9,6 +123,302 @@ pub fn draw_line(
     y1_param: i32,
     framebuffer: &mut Framebuffer,
     color: u32,
+) {
+    let rect = ClipRect::framebuffer(framebuffer, 0.0);
+    let clipped = rect.clip_line(
+        &point_2d(x0_param as f32, y0_param as f32),
+        &point_2d(x1_param as f32, y1_param as f32),
+    );
+    let (a, b) = match clipped {
+        Some(clipped) => clipped,
+        None => return,
+    };
+    let (x0, y0) = (a.x.round() as i32, a.y.round() as i32);
+    let (x1, y1) = (b.x.round() as i32, b.y.round() as i32);
+    walk_line(x0, y0, x1, y1, |x, y, _| {
+        framebuffer.set_color_at(&(x as u32), &(y as u32), color);
+    });
+}
+
+/// Draw an anti-aliased 2d line between (x0,y0) and (x1,y1) with Xiaolin Wu's algorithm. Pixels
+/// are alpha blended with the framebuffer by how much of them the line covers. 'color' is in the
+/// format of the framebuffer and its own alpha is ignored.
+pub fn draw_line_aa(x0: f32, y0: f32, x1: f32, y1: f32, framebuffer: &mut Framebuffer, color: u32) {
+    // Wu's algorithm touches the pixels either side of the line, so keep a pixel of margin.
+    let rect = ClipRect::framebuffer(framebuffer, 1.0);
+    let (a, b) = match rect.clip_line(&point_2d(x0, y0), &point_2d(x1, y1)) {
+        Some(clipped) => clipped,
+        None => return,
+    };
+    let (mut x0, mut y0, mut x1, mut y1) = (a.x, a.y, b.x, b.y);
+    let steep = (y1 - y0).abs() > (x1 - x0).abs();
+    if steep {
+        (x0, y0) = (y0, x0);
+        (x1, y1) = (y1, x1);
+    }
+    if x0 > x1 {
+        (x0, x1) = (x1, x0);
+        (y0, y1) = (y1, y0);
+    }
+    let dx = x1 - x0;
+    let gradient = if dx > 0.0 { (y1 - y0) / dx } else { 1.0 };
+    let mut plot = |x: f32, y: f32, coverage: f32| {
+        let (x, y) = if steep { (y, x) } else { (x, y) };
+        blend_coverage(framebuffer, x as i64, y as i64, color, coverage);
+    };
+
+    // The end points cover their pixels by how far the line reaches into them.
+    let mut end_point = |x: f32, y: f32, reach: f32| {
+        let x_end = x.round();
+        let y_end = y + gradient * (x_end - x);
+        plot(x_end, y_end.floor(), (1.0 - fract_floor(y_end)) * reach);
+        plot(x_end, y_end.floor() + 1.0, fract_floor(y_end) * reach);
+        (x_end, y_end)
+    };
+    let (x_start, y_start) = end_point(x0, y0, 1.0 - fract_floor(x0 + 0.5));
+    // A line starting and ending in the same column is drawn by its start point alone, so its
+    // pixels aren't blended twice.
+    if x1.round() == x_start {
+        return;
+    }
+    let (x_end, _) = end_point(x1, y1, fract_floor(x1 + 0.5));
+
+    let mut y = y_start + gradient;
+    let mut x = x_start + 1.0;
+    while x < x_end {
+        plot(x, y.floor(), 1.0 - fract_floor(y));
+        plot(x, y.floor() + 1.0, fract_floor(y));
+        y += gradient;
+        x += 1.0;
+    }
+}
+
+/// The shape of the ends of a thick line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LineCap {
+    /// The line stops square at its end points.
+    #[default]
+    Butt,
+    /// The line ends in a half circle around each end point, half the line width across.
+    Round,
+}
+
+/// The width and end caps of the lines drawn by 'draw_thick_line'.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LineStyle {
+    pub width: f32,
+    pub cap: LineCap,
+}
+
+impl LineStyle {
+    /// Creates a new `LineStyle` 'width' pixels wide ending in 'cap'.
+    pub fn new(width: f32, cap: LineCap) -> Self {
+        LineStyle { width, cap }
+    }
+}
+
+/// Draw a 2d line between (x0,y0) and (x1,y1) that is 'style.width' pixels wide, with
+/// anti-aliased edges alpha blended with the framebuffer. 'color' is in the format of the
+/// framebuffer and its own alpha is ignored. A butt capped line with no length draws nothing.
+pub fn draw_thick_line(
+    x0: f32,
+    y0: f32,
+    x1: f32,
+    y1: f32,
+    style: &LineStyle,
+    framebuffer: &mut Framebuffer,
+    color: u32,
+) {
+    let radius = style.width * 0.5;
+    if !(radius > 0.0 && radius.is_finite()) {
+        return;
+    }
+    // Clip with enough margin that the ends of a clipped line and its soft edges stay out of view.
+    let rect = ClipRect::framebuffer(framebuffer, radius + 1.0);
+    let (a, b) = match rect.clip_line(&point_2d(x0, y0), &point_2d(x1, y1)) {
+        Some(clipped) => clipped,
+        None => return,
+    };
+    let (dx, dy) = (b.x - a.x, b.y - a.y);
+    let length = (dx * dx + dy * dy).sqrt();
+    if length <= f32::EPSILON && style.cap == LineCap::Butt {
+        return;
+    }
+    // How far outside the line a pixel centre is, negative inside it.
+    let distance = |x: f32, y: f32| {
+        let (px, py) = (x - a.x, y - a.y);
+        if length <= f32::EPSILON {
+            return (px * px + py * py).sqrt() - radius;
+        }
+        let along = (px * dx + py * dy) / length;
+        let across = (px * dy - py * dx).abs() / length;
+        match style.cap {
+            LineCap::Butt => (across - radius).max(-along).max(along - length),
+            LineCap::Round if along < 0.0 => (px * px + py * py).sqrt() - radius,
+            LineCap::Round if along > length => {
+                let (qx, qy) = (x - b.x, y - b.y);
+                (qx * qx + qy * qy).sqrt() - radius
+            }
+            LineCap::Round => across - radius,
+        }
+    };
+
+    let reach = radius + 1.0;
+    let max_x = framebuffer.width() as f32 - 1.0;
+    let max_y = framebuffer.height() as f32 - 1.0;
+    let top = (a.y.min(b.y) - reach).floor().max(0.0) as i64;
+    let bottom = (a.y.max(b.y) + reach).ceil().min(max_y) as i64;
+    for y in top..=bottom {
+        // Only the part of the line within reach of this row can cover its pixels.
+        let (mut t0, mut t1) = (0.0f32, 1.0f32);
+        if dy.abs() > f32::EPSILON {
+            let ta = (y as f32 - reach - a.y) / dy;
+            let tb = (y as f32 + reach - a.y) / dy;
+            t0 = ta.min(tb).max(0.0);
+            t1 = ta.max(tb).min(1.0);
+            if t0 > t1 {
+                continue;
+            }
+        }
+        let (xa, xb) = (a.x + dx * t0, a.x + dx * t1);
+        let left = (xa.min(xb) - reach).floor().max(0.0) as i64;
+        let right = (xa.max(xb) + reach).ceil().min(max_x) as i64;
+        for x in left..=right {
+            let coverage = (0.5 - distance(x as f32, y as f32)).clamp(0.0, 1.0);
+            blend_coverage(framebuffer, x, y, color, coverage);
+        }
+    }
+}
+
+/// Alpha blend 'color', in the format of the framebuffer, over pixel 'x', 'y' by 'coverage' from
+/// 0.0 to 1.0. Pixels outside the framebuffer are skipped.
+fn blend_coverage(framebuffer: &mut Framebuffer, x: i64, y: i64, color: u32, coverage: f32) {
+    let (width, height) = (framebuffer.width() as i64, framebuffer.height() as i64);
+    if coverage <= 0.0 || x < 0 || y < 0 || x >= width || y >= height {
+        return;
+    }
+    let format = framebuffer.format();
+    let alpha = (coverage.min(1.0) * 255.0).round() as u8;
+    let index = (y * width + x) as usize;
+    let pixels = framebuffer.get_framebuffer_mut();
+    pixels[index] = format.mix(color, pixels[index], alpha);
+}
+
+/// An axis aligned rectangle that lines are clipped to with the Cohen-Sutherland algorithm.
+struct ClipRect {
+    min_x: f32,
+    min_y: f32,
+    max_x: f32,
+    max_y: f32,
+}
+
+const OUTCODE_LEFT: u8 = 1;
+const OUTCODE_RIGHT: u8 = 2;
+const OUTCODE_TOP: u8 = 4;
+const OUTCODE_BOTTOM: u8 = 8;
+
+impl ClipRect {
+    /// Creates a new `ClipRect` around the pixel centres of 'framebuffer', grown by 'margin' on
+    /// every side.
+    fn framebuffer(framebuffer: &Framebuffer, margin: f32) -> Self {
+        ClipRect {
+            min_x: -margin,
+            min_y: -margin,
+            max_x: framebuffer.width() as f32 - 1.0 + margin,
+            max_y: framebuffer.height() as f32 - 1.0 + margin,
+        }
+    }
+
+    /// Returns the sides of the rectangle that 'point' lies beyond.
+    fn outcode(&self, point: &Vec3d) -> u8 {
+        let mut code = 0;
+        if point.x < self.min_x {
+            code |= OUTCODE_LEFT;
+        } else if point.x > self.max_x {
+            code |= OUTCODE_RIGHT;
+        }
+        if point.y < self.min_y {
+            code |= OUTCODE_TOP;
+        } else if point.y > self.max_y {
+            code |= OUTCODE_BOTTOM;
+        }
+        code
+    }
+
+    /// Clips the line from 'a' to 'b' to the rectangle by x and y, interpolating z along with
+    /// them. Returns 'None' if none of the line is inside the rectangle.
+    fn clip_line(&self, a: &Vec3d, b: &Vec3d) -> Option<(Vec3d, Vec3d)> {
+        let finite = |point: &Vec3d| point.x.is_finite() && point.y.is_finite();
+        if !finite(a) || !finite(b) || self.min_x > self.max_x || self.min_y > self.max_y {
+            return None;
+        }
+        let (mut a, mut b) = (a.clone(), b.clone());
+        let (mut code_a, mut code_b) = (self.outcode(&a), self.outcode(&b));
+        // Each end point needs at most two moves, one onto an x edge and one onto a y edge. Give
+        // up on lines that keep missing the corners by rounding errors.
+        for _ in 0..8 {
+            if code_a | code_b == 0 {
+                return Some((a, b));
+            }
+            if code_a & code_b != 0 {
+                return None;
+            }
+            // Move an end point outside the rectangle onto the edge it lies beyond.
+            let code = if code_a != 0 { code_a } else { code_b };
+            let (edge, t) = if code & OUTCODE_LEFT != 0 {
+                (self.min_x, (self.min_x - a.x) / (b.x - a.x))
+            } else if code & OUTCODE_RIGHT != 0 {
+                (self.max_x, (self.max_x - a.x) / (b.x - a.x))
+            } else if code & OUTCODE_TOP != 0 {
+                (self.min_y, (self.min_y - a.y) / (b.y - a.y))
+            } else {
+                (self.max_y, (self.max_y - a.y) / (b.y - a.y))
+            };
+            let mut point = Vec3d {
+                x: a.x + (b.x - a.x) * t,
+                y: a.y + (b.y - a.y) * t,
+                z: a.z + (b.z - a.z) * t,
+                ..Default::default()
+            };
+            if code & (OUTCODE_LEFT | OUTCODE_RIGHT) != 0 {
+                point.x = edge;
+            } else {
+                point.y = edge;
+            }
+            if code == code_a {
+                a = point;
+                code_a = self.outcode(&a);
+            } else {
+                b = point;
+                code_b = self.outcode(&b);
+            }
+        }
+        None
+    }
+}
+
+/// Returns the fractional part of 'value' above its floor, from 0.0 up to 1.0.
+fn fract_floor(value: f32) -> f32 {
+    value - value.floor()
+}
+
+/// Returns a 'Vec3d' at 'x', 'y' for the 2d line functions.
+fn point_2d(x: f32, y: f32) -> Vec3d {
+    Vec3d {
+        x,
+        y,
+        ..Default::default()
+    }
+}
+
+/// Step along the line between (x0,y0) and (x1,y1) with Bresenham's algorithm, calling 'plot'
+/// with each pixel and how far along the line from (x0,y0) it is, from 0.0 to 1.0.
+fn walk_line(
+    x0_param: i32,
+    y0_param: i32,
+    x1_param: i32,
+    y1_param: i32,
+    mut plot: impl FnMut(i32, i32, f32),
 ) {
     let mut x0 = x0_param;
     let mut y0 = y0_param;
@@ -20,7 +430,8 @@ pub fn draw_line(
         (x1, y1) = (y1, x1);
         steep = true;
     }
-    if x0 > x1 {
+    let reversed = x0 > x1;
+    if reversed {
         (x0, x1) = (x1, x0);
         (y0, y1) = (y1, y0);
     }
@@ -30,10 +441,18 @@ pub fn draw_line(
     let mut error2: i32 = 0;
     let mut y = y0;
     for x in x0..(x1 + 1) {
+        let mut t = if dx > 0 {
+            (x - x0) as f32 / dx as f32
+        } else {
+            0.0
+        };
+        if reversed {
+            t = 1.0 - t;
+        }
         if steep {
-            framebuffer.set_color_at(&(y as u32), &(x as u32), color);
+            plot(y, x, t);
         } else {
-            framebuffer.set_color_at(&(x as u32), &(y as u32), color);
+            plot(x, y, t);
         }
         error2 += derror2;
         if error2 > dx {
@@ -47,8 +466,73 @@ pub fn draw_line(
     }
 }
 
-/// A function for filling a triangle with a texture (based on mapped texture co-ordinates) and 
+/// How much further away than the depth buffer a depth tested line may be and still be drawn, as
+/// a fraction of its depth. Lets lines along the edges of triangles show over the triangles.
+pub const LINE_DEPTH_BIAS: f32 = 0.01;
+
+/// Draw the line from 'a' to 'b' into the rows of 'target' in 'color', laid out in the format of
+/// the target. 'a' and 'b' hold screen space x and y coordinates and a depth value in z like the
+/// points of a triangle, and pixels of the line behind the depth buffer are skipped, allowing for
+/// 'LINE_DEPTH_BIAS'. The depth buffer is left untouched.
+pub(crate) fn fill_depth_tested_line(a: &Vec3d, b: &Vec3d, color: u32, target: &mut RasterTarget) {
+    let rect = ClipRect {
+        min_x: 0.0,
+        min_y: target.rows.start as f32,
+        max_x: (target.width - 1) as f32,
+        max_y: (target.rows.end - 1) as f32,
+    };
+    let (a, b) = match rect.clip_line(a, b) {
+        Some(clipped) => clipped,
+        None => return,
+    };
+    let (x0, y0) = (a.x.round() as i32, a.y.round() as i32);
+    let (x1, y1) = (b.x.round() as i32, b.y.round() as i32);
+    walk_line(x0, y0, x1, y1, |x, y, t| {
+        let depth = a.z + (b.z - a.z) * t;
+        fill_depth_tested_pixel(x as i64, y as i64, depth, color, target);
+    });
+}
+
+/// Draw a 'size' pixel wide square centred on 'point' into the rows of 'target' in 'color',
+/// depth tested like 'fill_depth_tested_line'.
+pub(crate) fn fill_depth_tested_point(
+    point: &Vec3d,
+    size: u32,
+    color: u32,
+    target: &mut RasterTarget,
+) {
+    let size = size.max(1) as i64;
+    let left = (point.x - size as f32 * 0.5).round() as i64;
+    let top = (point.y - size as f32 * 0.5).round() as i64;
+    let columns = left.max(0)..(left + size).min(target.width);
+    let rows = top.max(target.rows.start)..(top + size).min(target.rows.end);
+    for y in rows {
+        for x in columns.clone() {
+            fill_depth_tested_pixel(x, y, point.z, color, target);
+        }
+    }
+}
+
+/// Write 'color' to pixel 'x', 'y' of 'target' if it is in the band and 'depth' passes the depth
+/// test of the depth tested lines.
+fn fill_depth_tested_pixel(x: i64, y: i64, depth: f32, color: u32, target: &mut RasterTarget) {
+    if let Some(index) = target.index(x, y) {
+        if depth * (1.0 + LINE_DEPTH_BIAS) >= target.depth[index] {
+            target.pixels[index] = color;
+        }
+    }
+}
+
+/// A function for filling a triangle with a texture (based on mapped texture co-ordinates) and
 /// outputing the result to a framebuffer. A depth buffer must be passed in for pixel ordering.
+///
+/// Each point is given as screen space x and y coordinates with u/w, v/w and 1/w texture
+/// coordinates, and 1/w doubles as the depth value, where larger values are nearer the camera.
+/// The texture is read with the default 'Sampler' and drawn unlit, see 'draw_blended_triangle'
+/// for drawing a lit 'Triangle'.
+///
+/// The framebuffer and depth buffer must both be 'screen_width' pixels wide.
+#[allow(clippy::too_many_arguments)]
 pub fn draw_textured_triangle(
     x1_param: i64,
     y1_param: i64,
@@ -67,73 +551,172 @@ pub fn draw_textured_triangle(
     w3_param: f32,
     tex: &Texture,
     framebuffer: &mut Framebuffer,
-    p_depth_buffer: &mut Vec<f32>,
+    p_depth_buffer: &mut [f32],
     screen_width: &i64,
 ) {
-    let mut x1 = x1_param;
-    let mut y1 = y1_param;
-    let mut u1 = u1_param;
-    let mut v1 = v1_param;
-    let mut w1 = w1_param;
-    let mut x2 = x2_param;
-    let mut y2 = y2_param;
-    let mut u2 = u2_param;
-    let mut v2 = v2_param;
-    let mut w2 = w2_param;
-    let mut x3 = x3_param;
-    let mut y3 = y3_param;
-    let mut u3 = u3_param;
-    let mut v3 = v3_param;
-    let mut w3 = w3_param;
+    let point = |x: i64, y: i64, w: f32| Vec3d {
+        x: x as f32,
+        y: y as f32,
+        z: w,
+        ..Default::default()
+    };
+    let texture_point = |u: f32, v: f32, w: f32| Vec2d { u, v, w };
+    let tri = Triangle {
+        points: [
+            point(x1_param, y1_param, w1_param),
+            point(x2_param, y2_param, w2_param),
+            point(x3_param, y3_param, w3_param),
+        ],
+        texture_points: [
+            texture_point(u1_param, v1_param, w1_param),
+            texture_point(u2_param, v2_param, w2_param),
+            texture_point(u3_param, v3_param, w3_param),
+        ],
+        ..Default::default()
+    };
+    draw_blended_triangle(
+        &tri,
+        tex,
+        BlendMode::Opaque,
+        framebuffer,
+        p_depth_buffer,
+        screen_width,
+    );
+}
+
+/// A function for filling a 'Triangle' with a texture and outputing the result to a framebuffer,
+/// combining each pixel with the framebuffer using 'blend'. A depth buffer must be passed in for
+/// pixel ordering. Transparent blend modes leave the depth buffer untouched, so transparent
+/// triangles should be drawn back-to-front after every opaque one.
+///
+/// The triangle points hold screen space x and y coordinates and a depth value in z, where larger
+/// values are nearer the camera. The texture points hold u/w, v/w and 1/w for perspective
+/// correct texturing. The texture is read with the default 'Sampler' and its color is multiplied
+/// by the light level, which is interpolated with the same perspective correction. A texture in
+/// a different 'PixelFormat' to the framebuffer is converted on every call, so textures drawn
+/// often are best converted once with 'Texture::convert'.
+///
+/// The framebuffer and depth buffer must both be 'screen_width' pixels wide.
+pub fn draw_blended_triangle(
+    tri: &Triangle,
+    tex: &Texture,
+    blend: BlendMode,
+    framebuffer: &mut Framebuffer,
+    p_depth_buffer: &mut [f32],
+    screen_width: &i64,
+) {
+    let tex = &texture_in_format(tex, framebuffer.format());
+    let mut target = RasterTarget::full(framebuffer, p_depth_buffer, *screen_width);
+    fill_textured_triangle(tri, tex, Sampler::default(), blend, &mut target);
+}
+
+/// Returns 'tex' in 'format', borrowing it when no conversion is needed.
+fn texture_in_format(tex: &Texture, format: PixelFormat) -> Cow<'_, Texture> {
+    if tex.format == format {
+        return Cow::Borrowed(tex);
+    }
+    let mut converted = tex.clone();
+    converted.convert(format);
+    Cow::Owned(converted)
+}
+
+/// Fill 'tri' into the rows of 'target', see 'draw_blended_triangle'.
+pub(crate) fn fill_textured_triangle(
+    tri: &Triangle,
+    tex: &Texture,
+    sampler: Sampler,
+    blend: BlendMode,
+    target: &mut RasterTarget,
+) {
+    let texture = TriangleTexture::new(tri, tex, sampler);
+    let mut x1 = tri.points[0].x.round() as i64;
+    let mut y1 = tri.points[0].y.round() as i64;
+    let mut z1 = tri.points[0].z;
+    let mut u1 = tri.texture_points[0].u;
+    let mut v1 = tri.texture_points[0].v;
+    let mut w1 = tri.texture_points[0].w;
+    let mut l1 = light_scale(&tri.light[0], w1);
+    let mut x2 = tri.points[1].x.round() as i64;
+    let mut y2 = tri.points[1].y.round() as i64;
+    let mut z2 = tri.points[1].z;
+    let mut u2 = tri.texture_points[1].u;
+    let mut v2 = tri.texture_points[1].v;
+    let mut w2 = tri.texture_points[1].w;
+    let mut l2 = light_scale(&tri.light[1], w2);
+    let mut x3 = tri.points[2].x.round() as i64;
+    let mut y3 = tri.points[2].y.round() as i64;
+    let mut z3 = tri.points[2].z;
+    let mut u3 = tri.texture_points[2].u;
+    let mut v3 = tri.texture_points[2].v;
+    let mut w3 = tri.texture_points[2].w;
+    let mut l3 = light_scale(&tri.light[2], w3);
 
     if y2 < y1 {
         (y1, y2) = (y2, y1);
         (x1, x2) = (x2, x1);
+        (z1, z2) = (z2, z1);
         (u1, u2) = (u2, u1);
         (v1, v2) = (v2, v1);
         (w1, w2) = (w2, w1);
+        (l1, l2) = (l2, l1);
     }
 
     if y3 < y1 {
         (y1, y3) = (y3, y1);
         (x1, x3) = (x3, x1);
+        (z1, z3) = (z3, z1);
         (u1, u3) = (u3, u1);
         (v1, v3) = (v3, v1);
         (w1, w3) = (w3, w1);
+        (l1, l3) = (l3, l1);
     }
 
     if y3 < y2 {
         (y2, y3) = (y3, y2);
         (x2, x3) = (x3, x2);
+        (z2, z3) = (z3, z2);
         (u2, u3) = (u3, u2);
         (v2, v3) = (v3, v2);
         (w2, w3) = (w3, w2);
+        (l2, l3) = (l3, l2);
     }
 
     let mut dy1: i64 = y2 - y1;
     let mut dx1: i64 = x2 - x1;
+    let mut dz1: f32 = z2 - z1;
     let mut dv1: f32 = v2 - v1;
     let mut du1: f32 = u2 - u1;
     let mut dw1: f32 = w2 - w1;
+    let mut dl1: [f32; 3] = light_sub(&l2, &l1);
 
     let dy2: i64 = y3 - y1;
     let dx2: i64 = x3 - x1;
+    let dz2: f32 = z3 - z1;
     let dv2: f32 = v3 - v1;
     let du2: f32 = u3 - u1;
     let dw2: f32 = w3 - w1;
+    let dl2: [f32; 3] = light_sub(&l3, &l1);
 
     let mut tex_u: f32;
     let mut tex_v: f32;
     let mut tex_w: f32;
+    let mut depth: f32;
+
+    // Triangles lit at full brightness are drawn with the texture color as-is.
+    let lit = tri.light.iter().any(|l| *l != [1.0, 1.0, 1.0]);
 
     let mut dax_step: f32 = 0.0;
     let mut dbx_step: f32 = 0.0;
+    let mut dz1_step: f32 = 0.0;
+    let mut dz2_step: f32 = 0.0;
     let mut du1_step: f32 = 0.0;
     let mut dv1_step: f32 = 0.0;
     let mut du2_step: f32 = 0.0;
     let mut dv2_step: f32 = 0.0;
     let mut dw1_step: f32 = 0.0;
     let mut dw2_step: f32 = 0.0;
+    let mut dl1_step: [f32; 3] = [0.0; 3];
+    let mut dl2_step: [f32; 3] = [0.0; 3];
 
     if dy1 != 0 {
         dax_step = (dx1 as f32) / ((dy1).abs() as f32)
@@ -142,6 +725,9 @@ pub fn draw_textured_triangle(
         dbx_step = (dx2 as f32) / ((dy2).abs() as f32)
     };
 
+    if dy1 != 0 {
+        dz1_step = dz1 / ((dy1).abs() as f32)
+    };
     if dy1 != 0 {
         du1_step = du1 / ((dy1).abs() as f32)
     };
@@ -151,7 +737,13 @@ pub fn draw_textured_triangle(
     if dy1 != 0 {
         dw1_step = dw1 / ((dy1).abs() as f32)
     };
+    if dy1 != 0 {
+        dl1_step = light_scale(&dl1, 1.0 / ((dy1).abs() as f32))
+    };
 
+    if dy2 != 0 {
+        dz2_step = dz2 / ((dy2).abs() as f32)
+    };
     if dy2 != 0 {
         du2_step = du2 / ((dy2).abs() as f32)
     };
@@ -161,42 +753,53 @@ pub fn draw_textured_triangle(
     if dy2 != 0 {
         dw2_step = dw2 / ((dy2).abs() as f32)
     };
+    if dy2 != 0 {
+        dl2_step = light_scale(&dl2, 1.0 / ((dy2).abs() as f32))
+    };
 
     if dy1 != 0 {
-        for i in y1..(y2) {
+        for i in y1.max(target.rows.start)..y2.min(target.rows.end) {
             let mut ax: i64 = (x1 as f32 + ((i - y1) as f32) * dax_step).round() as i64;
             let mut bx: i64 = (x1 as f32 + ((i - y1) as f32) * dbx_step).round() as i64;
 
+            let mut tex_sz: f32 = z1 + ((i - y1) as f32) * dz1_step;
             let mut tex_su: f32 = u1 + ((i - y1) as f32) * du1_step;
             let mut tex_sv: f32 = v1 + ((i - y1) as f32) * dv1_step;
             let mut tex_sw: f32 = w1 + ((i - y1) as f32) * dw1_step;
+            let mut tex_sl: [f32; 3] = light_add_scaled(&l1, &dl1_step, (i - y1) as f32);
 
+            let mut tex_ez: f32 = z1 + ((i - y1) as f32) * dz2_step;
             let mut tex_eu: f32 = u1 + ((i - y1) as f32) * du2_step;
             let mut tex_ev: f32 = v1 + ((i - y1) as f32) * dv2_step;
             let mut tex_ew: f32 = w1 + ((i - y1) as f32) * dw2_step;
+            let mut tex_el: [f32; 3] = light_add_scaled(&l1, &dl2_step, (i - y1) as f32);
 
             if ax > bx {
                 (ax, bx) = (bx, ax);
+                (tex_sz, tex_ez) = (tex_ez, tex_sz);
                 (tex_su, tex_eu) = (tex_eu, tex_su);
                 (tex_sv, tex_ev) = (tex_ev, tex_sv);
                 (tex_sw, tex_ew) = (tex_ew, tex_sw);
+                (tex_sl, tex_el) = (tex_el, tex_sl);
             }
 
             let tstep: f32 = 1.0 / ((bx - ax) as f32);
             let mut t: f32 = 0.0;
 
             for j in ax..bx {
+                depth = (1.0 - t) * tex_sz + t * tex_ez;
                 tex_u = (1.0 - t) * tex_su + t * tex_eu;
                 tex_v = (1.0 - t) * tex_sv + t * tex_ev;
                 tex_w = (1.0 - t) * tex_sw + t * tex_ew;
-                if tex_w > p_depth_buffer[(i * screen_width + j) as usize] {
-                    framebuffer.set_color_at(
-                        &(j as u32),
-                        &(i as u32),
-                        *tex.get_color_at_normalized_coord(&(tex_u / tex_w), &(tex_v / tex_w)),
-                    );
-
-                    p_depth_buffer[(i * screen_width + j) as usize] = tex_w;
+                if let Some(index) = target.index(j, i).filter(|k| depth > target.depth[*k]) {
+                    let mut color = texture.sample(tex_u / tex_w, tex_v / tex_w, tex_w);
+                    if lit {
+                        let light = light_add_scaled(&light_scale(&tex_sl, 1.0 - t), &tex_el, t);
+                        color = tex
+                            .format
+                            .modulate(color, &light_scale(&light, 1.0 / tex_w));
+                    }
+                    target.put_pixel(index, color, depth, blend);
                 }
                 t += tstep;
             }
@@ -205,9 +808,11 @@ pub fn draw_textured_triangle(
 
     dy1 = y3 - y2;
     dx1 = x3 - x2;
+    dz1 = z3 - z2;
     dv1 = v3 - v2;
     du1 = u3 - u2;
     dw1 = w3 - w2;
+    dl1 = light_sub(&l3, &l2);
 
     if dy1 != 0 {
         dax_step = (dx1 as f32) / ((dy1).abs() as f32)
@@ -218,6 +823,9 @@ pub fn draw_textured_triangle(
 
     du1_step = 0.0;
     dv1_step = 0.0;
+    if dy1 != 0 {
+        dz1_step = dz1 / (dy1.abs() as f32)
+    };
     if dy1 != 0 {
         du1_step = du1 / (dy1.abs() as f32)
     };
@@ -227,45 +835,533 @@ pub fn draw_textured_triangle(
     if dy1 != 0 {
         dw1_step = dw1 / (dy1.abs() as f32)
     };
+    if dy1 != 0 {
+        dl1_step = light_scale(&dl1, 1.0 / (dy1.abs() as f32))
+    };
 
     if dy1 != 0 {
-        for i in y2..(y3) {
+        for i in y2.max(target.rows.start)..y3.min(target.rows.end) {
             let mut ax: i64 = (x2 as f32 + ((i - y2) as f32) * dax_step).round() as i64;
             let mut bx: i64 = (x1 as f32 + ((i - y1) as f32) * dbx_step).round() as i64;
 
+            let mut tex_sz: f32 = z2 + ((i - y2) as f32) * dz1_step;
             let mut tex_su: f32 = u2 + ((i - y2) as f32) * du1_step;
             let mut tex_sv: f32 = v2 + ((i - y2) as f32) * dv1_step;
             let mut tex_sw: f32 = w2 + ((i - y2) as f32) * dw1_step;
+            let mut tex_sl: [f32; 3] = light_add_scaled(&l2, &dl1_step, (i - y2) as f32);
 
+            let mut tex_ez: f32 = z1 + ((i - y1) as f32) * dz2_step;
             let mut tex_eu: f32 = u1 + ((i - y1) as f32) * du2_step;
             let mut tex_ev: f32 = v1 + ((i - y1) as f32) * dv2_step;
             let mut tex_ew: f32 = w1 + ((i - y1) as f32) * dw2_step;
+            let mut tex_el: [f32; 3] = light_add_scaled(&l1, &dl2_step, (i - y1) as f32);
 
             if ax > bx {
                 (ax, bx) = (bx, ax);
+                (tex_sz, tex_ez) = (tex_ez, tex_sz);
                 (tex_su, tex_eu) = (tex_eu, tex_su);
                 (tex_sv, tex_ev) = (tex_ev, tex_sv);
                 (tex_sw, tex_ew) = (tex_ew, tex_sw);
+                (tex_sl, tex_el) = (tex_el, tex_sl);
             }
 
             let tstep: f32 = 1.0 / ((bx - ax) as f32);
             let mut t: f32 = 0.0;
 
             for j in ax..bx {
+                depth = (1.0 - t) * tex_sz + t * tex_ez;
                 tex_u = (1.0 - t) * tex_su + t * tex_eu;
                 tex_v = (1.0 - t) * tex_sv + t * tex_ev;
                 tex_w = (1.0 - t) * tex_sw + t * tex_ew;
 
-                if tex_w > p_depth_buffer[(i * screen_width + j) as usize] {
-                    framebuffer.set_color_at(
-                        &(j as u32),
-                        &(i as u32),
-                        *tex.get_color_at_normalized_coord(&(tex_u / tex_w), &(tex_v / tex_w)),
-                    );
-                    p_depth_buffer[(i * screen_width + j) as usize] = tex_w;
+                if let Some(index) = target.index(j, i).filter(|k| depth > target.depth[*k]) {
+                    let mut color = texture.sample(tex_u / tex_w, tex_v / tex_w, tex_w);
+                    if lit {
+                        let light = light_add_scaled(&light_scale(&tex_sl, 1.0 - t), &tex_el, t);
+                        color = tex
+                            .format
+                            .modulate(color, &light_scale(&light, 1.0 / tex_w));
+                    }
+                    target.put_pixel(index, color, depth, blend);
                 }
                 t += tstep;
             }
         }
     }
 }
+
+/// A function for filling a triangle using a 'FragmentShader' to color each pixel, and outputting
+/// the result to a framebuffer. A depth buffer must be passed in for pixel ordering.
+///
+/// The triangle covers the same pixels as it would with 'draw_textured_triangle'. Every varying
+/// is interpolated with perspective correction, and the shader is only run for pixels that pass
+/// the depth test. Discarded pixels leave the depth buffer untouched.
+pub fn draw_shaded_triangle(
+    tri: &Triangle,
+    tex: &Texture,
+    shader: &dyn FragmentShader,
+    framebuffer: &mut Framebuffer,
+    p_depth_buffer: &mut [f32],
+    screen_width: &i64,
+) {
+    let tex = &texture_in_format(tex, framebuffer.format());
+    let mut target = RasterTarget::full(framebuffer, p_depth_buffer, *screen_width);
+    fill_shaded_triangle(
+        tri,
+        tex,
+        Sampler::default(),
+        shader,
+        BlendMode::Opaque,
+        &mut target,
+    );
+}
+
+/// Fill 'tri' into the rows of 'target', see 'draw_shaded_triangle'.
+pub(crate) fn fill_shaded_triangle(
+    tri: &Triangle,
+    tex: &Texture,
+    sampler: Sampler,
+    shader: &dyn FragmentShader,
+    blend: BlendMode,
+    target: &mut RasterTarget,
+) {
+    let xs = tri.points.clone().map(|p| p.x.round() as i64);
+    let ys = tri.points.clone().map(|p| p.y.round() as i64);
+
+    // Twice the signed area of the triangle, used to normalise the barycentric weights.
+    let area = ((xs[1] - xs[0]) * (ys[2] - ys[0]) - (xs[2] - xs[0]) * (ys[1] - ys[0])) as f32;
+    if area == 0.0 {
+        return;
+    }
+
+    let texture = TriangleTexture::new(tri, tex, sampler);
+    let mut order: [usize; 3] = [0, 1, 2];
+    order.sort_by_key(|&i| ys[i]);
+    let [top, middle, bottom] = order;
+
+    // The x coordinate where the edge from point 'a' to point 'b' crosses row 'y'.
+    let edge_x = |a: usize, b: usize, y: i64| -> i64 {
+        let step = (xs[b] - xs[a]) as f32 / (ys[b] - ys[a]).abs() as f32;
+        (xs[a] as f32 + ((y - ys[a]) as f32) * step).round() as i64
+    };
+
+    for i in ys[top].max(target.rows.start)..ys[bottom].min(target.rows.end) {
+        let long_x = edge_x(top, bottom, i);
+        let short_x = if i < ys[middle] {
+            edge_x(top, middle, i)
+        } else {
+            edge_x(middle, bottom, i)
+        };
+        let (ax, bx) = (long_x.min(short_x), long_x.max(short_x));
+
+        for j in ax..bx {
+            // Screen space barycentric weights of the pixel.
+            let b0 = ((xs[1] - j) * (ys[2] - i) - (xs[2] - j) * (ys[1] - i)) as f32 / area;
+            let b1 = ((xs[2] - j) * (ys[0] - i) - (xs[0] - j) * (ys[2] - i)) as f32 / area;
+            let b2 = 1.0 - b0 - b1;
+
+            let depth = b0 * tri.points[0].z + b1 * tri.points[1].z + b2 * tri.points[2].z;
+            let index = match target.index(j, i).filter(|k| depth > target.depth[*k]) {
+                Some(index) => index,
+                None => continue,
+            };
+
+            let fragment = fragment_at(tri, &texture, j, i, &[b0, b1, b2], depth);
+            if let Some(color) = shader.shade(&fragment) {
+                target.put_pixel(index, color, depth, blend);
+            }
+        }
+    }
+}
+
+/// A function for filling a triangle by testing every pixel in its bounding box against the
+/// triangle's edge functions, and outputting the result to a framebuffer. A depth buffer must be
+/// passed in for pixel ordering.
+///
+/// Unlike 'draw_textured_triangle' the points keep 'SUBPIXEL_BITS' bits of subpixel precision,
+/// pixels are sampled at their centres and a top-left fill rule is applied, so triangles sharing
+/// an edge cover every pixel along it exactly once. Every value is interpolated from the
+/// barycentric weights of the pixel with perspective correction. Pixels are colored by
+/// 'shader' if there is one, otherwise by the texture multiplied by the light level.
+pub fn draw_edge_function_triangle(
+    tri: &Triangle,
+    tex: &Texture,
+    shader: Option<&dyn FragmentShader>,
+    framebuffer: &mut Framebuffer,
+    p_depth_buffer: &mut [f32],
+    screen_width: &i64,
+) {
+    let tex = &texture_in_format(tex, framebuffer.format());
+    let mut target = RasterTarget::full(framebuffer, p_depth_buffer, *screen_width);
+    fill_edge_function_triangle(
+        tri,
+        tex,
+        Sampler::default(),
+        shader,
+        BlendMode::Opaque,
+        &mut target,
+    );
+}
+
+/// Fill 'tri' into the rows of 'target', see 'draw_edge_function_triangle'.
+pub(crate) fn fill_edge_function_triangle(
+    tri: &Triangle,
+    tex: &Texture,
+    sampler: Sampler,
+    shader: Option<&dyn FragmentShader>,
+    blend: BlendMode,
+    target: &mut RasterTarget,
+) {
+    let scale = (1i64 << SUBPIXEL_BITS) as f32;
+    let xs = tri.points.clone().map(|p| (p.x * scale).round() as i64);
+    let ys = tri.points.clone().map(|p| (p.y * scale).round() as i64);
+
+    // Order the points so the edge functions are positive inside the triangle.
+    let mut order: [usize; 3] = [0, 1, 2];
+    let area = edge_function(xs[0], ys[0], xs[1], ys[1], xs[2], ys[2]);
+    if area == 0 {
+        return;
+    } else if area < 0 {
+        order = [0, 2, 1];
+    }
+    let area = area.abs() as f32;
+
+    // The edge from point 'a' to point 'b' with the point opposite it, and the bias which
+    // excludes pixels lying exactly on edges that are neither top nor left edges.
+    let edges = [(1, 2), (2, 0), (0, 1)].map(|(a, b)| {
+        let (ax, ay, bx, by) = (xs[order[a]], ys[order[a]], xs[order[b]], ys[order[b]]);
+        let top_left = (ay == by && bx > ax) || by < ay;
+        (ax, ay, bx, by, if top_left { 0 } else { -1 })
+    });
+
+    let min_x = (xs.iter().min().unwrap() >> SUBPIXEL_BITS).max(0);
+    let min_y = (ys.iter().min().unwrap() >> SUBPIXEL_BITS).max(target.rows.start);
+    let max_x = (xs.iter().max().unwrap() >> SUBPIXEL_BITS).min(target.width - 1);
+    let max_y = (ys.iter().max().unwrap() >> SUBPIXEL_BITS).min(target.rows.end - 1);
+    let lit = tri.light.iter().any(|l| *l != [1.0, 1.0, 1.0]);
+    let texture = TriangleTexture::new(tri, tex, sampler);
+
+    let half = 1i64 << (SUBPIXEL_BITS - 1);
+    for i in min_y..=max_y {
+        let py = (i << SUBPIXEL_BITS) + half;
+        for j in min_x..=max_x {
+            let px = (j << SUBPIXEL_BITS) + half;
+            let mut weights = [0i64; 3];
+            let mut inside = true;
+            for (weight, (ax, ay, bx, by, bias)) in weights.iter_mut().zip(edges.iter()) {
+                *weight = edge_function(*ax, *ay, *bx, *by, px, py);
+                inside &= *weight + bias >= 0;
+            }
+            if !inside {
+                continue;
+            }
+
+            // Barycentric weights of the pixel for the points in their original order.
+            let mut b = [0.0; 3];
+            for (k, weight) in weights.iter().enumerate() {
+                b[order[k]] = *weight as f32 / area;
+            }
+
+            let depth = b[0] * tri.points[0].z + b[1] * tri.points[1].z + b[2] * tri.points[2].z;
+            let index = match target.index(j, i).filter(|k| depth > target.depth[*k]) {
+                Some(index) => index,
+                None => continue,
+            };
+
+            let color = match shader {
+                Some(shader) => {
+                    let fragment = fragment_at(tri, &texture, j, i, &b, depth);
+                    match shader.shade(&fragment) {
+                        Some(color) => color,
+                        None => continue,
+                    }
+                }
+                None => {
+                    let inv_w = b[0] * tri.texture_points[0].w
+                        + b[1] * tri.texture_points[1].w
+                        + b[2] * tri.texture_points[2].w;
+                    let u = (b[0] * tri.texture_points[0].u
+                        + b[1] * tri.texture_points[1].u
+                        + b[2] * tri.texture_points[2].u)
+                        / inv_w;
+                    let v = (b[0] * tri.texture_points[0].v
+                        + b[1] * tri.texture_points[1].v
+                        + b[2] * tri.texture_points[2].v)
+                        / inv_w;
+                    let mut color = texture.sample(u, v, inv_w);
+                    if lit {
+                        let weights = perspective_weights(tri, &b, inv_w);
+                        color = tex
+                            .format
+                            .modulate(color, &array_weighted_sum(&tri.light, &weights));
+                    }
+                    color
+                }
+            };
+            target.put_pixel(index, color, depth, blend);
+        }
+    }
+}
+
+/// Twice the signed area of the triangle 'a', 'b', 'p'. Zero when 'p' lies on the line through
+/// 'a' and 'b', and positive on the same side as the inside of a clockwise screen triangle.
+fn edge_function(ax: i64, ay: i64, bx: i64, by: i64, px: i64, py: i64) -> i64 {
+    (bx - ax) * (py - ay) - (by - ay) * (px - ax)
+}
+
+/// Build the 'Fragment' for pixel 'x', 'y' of 'tri' from the screen space barycentric weights
+/// 'b' of the pixel.
+fn fragment_at<'a>(
+    tri: &Triangle,
+    texture: &TriangleTexture<'a>,
+    x: i64,
+    y: i64,
+    b: &[f32; 3],
+    depth: f32,
+) -> Fragment<'a> {
+    let inv_w = b[0] * tri.texture_points[0].w
+        + b[1] * tri.texture_points[1].w
+        + b[2] * tri.texture_points[2].w;
+    let weights = perspective_weights(tri, b, inv_w);
+    let u = (b[0] * tri.texture_points[0].u
+        + b[1] * tri.texture_points[1].u
+        + b[2] * tri.texture_points[2].u)
+        / inv_w;
+    let v = (b[0] * tri.texture_points[0].v
+        + b[1] * tri.texture_points[1].v
+        + b[2] * tri.texture_points[2].v)
+        / inv_w;
+    Fragment {
+        x: x as u32,
+        y: y as u32,
+        u,
+        v,
+        depth,
+        normal: vector_weighted_sum(&tri.normals, &weights),
+        world_position: vector_weighted_sum(&tri.world_points, &weights),
+        light: array_weighted_sum(&tri.light, &weights),
+        attributes: array_weighted_sum::<ATTRIBUTE_COUNT>(&tri.attributes, &weights),
+        texture: texture.texture,
+        sampler: texture.sampler,
+        lod: texture.lod(u, v, inv_w),
+    }
+}
+
+/// The texture of a triangle with the sampler it is read through. When the sampler reads
+/// mipmaps it also holds the screen space gradients across the triangle of u/w, v/w and 1/w,
+/// which are linear in screen space, used to estimate how many texture pixels each screen pixel
+/// covers.
+struct TriangleTexture<'a> {
+    texture: &'a Texture,
+    sampler: Sampler,
+    gradients: Option<TextureGradients>,
+}
+
+/// The x and y gradients of u/w, v/w and 1/w, with the size of the texture in pixels.
+struct TextureGradients {
+    u: [f32; 2],
+    v: [f32; 2],
+    w: [f32; 2],
+    width: f32,
+    height: f32,
+}
+
+impl<'a> TriangleTexture<'a> {
+    /// Creates a new `TriangleTexture` reading 'texture' across 'tri' through 'sampler'.
+    fn new(tri: &Triangle, texture: &'a Texture, sampler: Sampler) -> TriangleTexture<'a> {
+        let gradients = if sampler.uses_mipmaps(texture) {
+            texture_gradients(tri, texture)
+        } else {
+            None
+        };
+        TriangleTexture {
+            texture,
+            sampler,
+            gradients,
+        }
+    }
+
+    /// Returns the color of the texture at 'u', 'v' for a pixel with the interpolated 1/w 'inv_w'.
+    fn sample(&self, u: f32, v: f32, inv_w: f32) -> u32 {
+        self.sampler
+            .sample_lod(self.texture, u, v, self.lod(u, v, inv_w))
+    }
+
+    /// Returns the level of detail at a pixel with the texture coordinates 'u', 'v' and the
+    /// interpolated 1/w 'inv_w', the base 2 logarithm of the larger of the distances in texture
+    /// pixels moved by stepping one screen pixel across or down. Zero without mipmaps.
+    fn lod(&self, u: f32, v: f32, inv_w: f32) -> f32 {
+        let Some(g) = &self.gradients else {
+            return 0.0;
+        };
+        // The derivative of u = (u/w) / (1/w) along each axis, by the quotient rule.
+        let footprint = |axis: usize| {
+            let du = (g.u[axis] - u * g.w[axis]) / inv_w;
+            let dv = (g.v[axis] - v * g.w[axis]) / inv_w;
+            (du * g.width).hypot(dv * g.height)
+        };
+        footprint(0).max(footprint(1)).log2()
+    }
+}
+
+/// Returns the screen space gradients of the texture points of 'tri', or 'None' if the triangle
+/// has no area.
+fn texture_gradients(tri: &Triangle, tex: &Texture) -> Option<TextureGradients> {
+    let [p0, p1, p2] = &tri.points;
+    let (dx1, dy1, dx2, dy2) = (p1.x - p0.x, p1.y - p0.y, p2.x - p0.x, p2.y - p0.y);
+    let area = dx1 * dy2 - dx2 * dy1;
+    if area == 0.0 {
+        return None;
+    }
+    // The x and y gradients of the plane through the value 'a' at each point.
+    let gradient = |a: [f32; 3]| {
+        let (da1, da2) = (a[1] - a[0], a[2] - a[0]);
+        [
+            (da1 * dy2 - da2 * dy1) / area,
+            (da2 * dx1 - da1 * dx2) / area,
+        ]
+    };
+    let t = &tri.texture_points;
+    Some(TextureGradients {
+        u: gradient([t[0].u, t[1].u, t[2].u]),
+        v: gradient([t[0].v, t[1].v, t[2].v]),
+        w: gradient([t[0].w, t[1].w, t[2].w]),
+        width: tex.width as f32,
+        height: tex.height as f32,
+    })
+}
+
+/// Turn the screen space barycentric weights 'b' into perspective correct weights, using the
+/// 1/w held in the texture points. 'inv_w' is the interpolated 1/w at the pixel.
+fn perspective_weights(tri: &Triangle, b: &[f32; 3], inv_w: f32) -> [f32; 3] {
+    [
+        b[0] * tri.texture_points[0].w / inv_w,
+        b[1] * tri.texture_points[1].w / inv_w,
+        b[2] * tri.texture_points[2].w / inv_w,
+    ]
+}
+
+/// Blend the three vectors 'v' by 'weights'.
+fn vector_weighted_sum(v: &[Vec3d; 3], weights: &[f32; 3]) -> Vec3d {
+    Vec3d {
+        x: v[0].x * weights[0] + v[1].x * weights[1] + v[2].x * weights[2],
+        y: v[0].y * weights[0] + v[1].y * weights[1] + v[2].y * weights[2],
+        z: v[0].z * weights[0] + v[1].z * weights[1] + v[2].z * weights[2],
+        ..Default::default()
+    }
+}
+
+/// Blend the three arrays 'a' by 'weights'.
+fn array_weighted_sum<const N: usize>(a: &[[f32; N]; 3], weights: &[f32; 3]) -> [f32; N] {
+    let mut sum = [0.0; N];
+    for (i, value) in sum.iter_mut().enumerate() {
+        *value = a[0][i] * weights[0] + a[1][i] * weights[1] + a[2][i] * weights[2];
+    }
+    sum
+}
+
+/// Subtract the light level 'l2' from 'l1'.
+fn light_sub(l1: &[f32; 3], l2: &[f32; 3]) -> [f32; 3] {
+    [l1[0] - l2[0], l1[1] - l2[1], l1[2] - l2[2]]
+}
+
+/// Multiply the light level 'l' by a constant 'k'.
+fn light_scale(l: &[f32; 3], k: f32) -> [f32; 3] {
+    [l[0] * k, l[1] * k, l[2] * k]
+}
+
+/// Add the light level 'step' multiplied by 'k' to 'l'.
+fn light_add_scaled(l: &[f32; 3], step: &[f32; 3], k: f32) -> [f32; 3] {
+    [l[0] + step[0] * k, l[1] + step[1] * k, l[2] + step[2] * k]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn draw_line_aa_blends_a_one_pixel_line_once() {
+        let mut framebuffer = Framebuffer::new(10, 10);
+        let format = framebuffer.format();
+        draw_line_aa(
+            5.0,
+            5.0,
+            5.0,
+            5.0,
+            &mut framebuffer,
+            format.pack(255, 255, 255, 0xff),
+        );
+        // The line reaches half way across its pixel, so covers half of it.
+        let (r, g, b, _) = format.unpack(*framebuffer.get_color_at(&5, &5));
+        assert_eq!((r, g, b), (128, 128, 128));
+        let drawn = framebuffer
+            .get_framebuffer()
+            .iter()
+            .filter(|&&color| color != 0);
+        assert_eq!(drawn.count(), 1);
+    }
+
+    /// Draws the quad 'corners' as two triangles split along its diagonal, adding 1 to every
+    /// pixel each triangle covers, and returns the 16x16 framebuffer's blue channel.
+    fn additive_quad(corners: [(f32, f32); 4]) -> Vec<u8> {
+        let mut framebuffer = Framebuffer::new(16, 16);
+        let format = framebuffer.format();
+        let mut depth = vec![0.0; 16 * 16];
+        let tex = Texture {
+            pixels: vec![format.pack(1, 1, 1, 0xff)],
+            width: 1,
+            height: 1,
+            mipmaps: Vec::new(),
+            format,
+        };
+        let point = |(x, y): (f32, f32)| Vec3d {
+            x,
+            y,
+            z: 0.5,
+            ..Default::default()
+        };
+        for [a, b, c] in [[0, 1, 2], [0, 2, 3]] {
+            let tri = Triangle {
+                points: [point(corners[a]), point(corners[b]), point(corners[c])],
+                ..Default::default()
+            };
+            let mut target = RasterTarget::full(&mut framebuffer, &mut depth, 16);
+            fill_edge_function_triangle(
+                &tri,
+                &tex,
+                Sampler::default(),
+                None,
+                BlendMode::Additive,
+                &mut target,
+            );
+        }
+        framebuffer
+            .get_framebuffer()
+            .iter()
+            .map(|&color| format.unpack(color).2)
+            .collect()
+    }
+
+    #[test]
+    fn edge_function_quads_write_every_pixel_once() {
+        // The diagonal of an axis aligned square passes exactly through pixel centres.
+        let drawn = additive_quad([(2.0, 2.0), (10.0, 2.0), (10.0, 10.0), (2.0, 10.0)]);
+        for (index, &count) in drawn.iter().enumerate() {
+            let (x, y) = (index % 16, index / 16);
+            let inside = (2..10).contains(&x) && (2..10).contains(&y);
+            assert_eq!(count, inside as u8, "pixel {}, {}", x, y);
+        }
+
+        // An irregular convex quad with subpixel corners.
+        let corners = [(1.3, 0.7), (13.6, 2.2), (12.9, 14.4), (0.5, 9.6)];
+        let drawn = additive_quad(corners);
+        for (index, &count) in drawn.iter().enumerate() {
+            let (x, y) = ((index % 16) as f32 + 0.5, (index / 16) as f32 + 0.5);
+            let inside = (0..4).all(|k| {
+                let (a, b) = (corners[k], corners[(k + 1) % 4]);
+                (b.0 - a.0) * (y - a.1) - (b.1 - a.1) * (x - a.0) > 0.0
+            });
+            assert_eq!(count, inside as u8, "pixel {}, {}", x, y);
+        }
+    }
+}
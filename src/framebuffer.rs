@@ -1,23 +1,62 @@
 
-/// A 'Framebuffer' object
+use std::{convert::Infallible, io::Write};
+
+use embedded_graphics::{
+    draw_target::DrawTarget,
+    geometry::{Dimensions, OriginDimensions, Point, Size},
+    pixelcolor::{Rgb888, RgbColor},
+    primitives::Rectangle,
+    Pixel,
+};
+
+use crate::color::PixelFormat;
+use crate::png_writer::png_encode;
+#[cfg(feature = "loader_helper")]
+use crate::texture_helper::{load_texture_auto, TextureLoadError};
+
+/// A 'Framebuffer' object. Every pixel is held in a u32 laid out in the framebuffer's
+/// 'PixelFormat', and the renderer writes pixels in that format directly. Colors passed to and
+/// read from the framebuffer are in its format too.
 pub struct Framebuffer {
     buffer: Vec<u32>,
     width: usize,
     height: usize,
+    format: PixelFormat,
 }
 
 impl Framebuffer {
-    /// Creates a new 'Framebuffer' object with a width and height.
+    /// Creates a new 'Framebuffer' object with a width and height, in the default 'PixelFormat'.
     pub fn new(width: usize, height: usize) -> Self {
+        Framebuffer::with_format(width, height, PixelFormat::default())
+    }
+
+    /// Creates a new 'Framebuffer' object with a width and height, holding pixels in 'format'.
+    pub fn with_format(width: usize, height: usize, format: PixelFormat) -> Self {
         let buffer: Vec<u32> = vec![0; width * height];
 
         Framebuffer {
             buffer,
             width,
             height,
+            format,
         }
     }
 
+    /// Returns the layout of the pixels in the framebuffer.
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    /// Returns the width of the framebuffer in pixels.
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Returns the height of the framebuffer in pixels.
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
     /// Returns the color at a particular x and y coord in the framebuffer.
     pub fn get_color_at(&self, x_coord: &u32, y_coord: &u32) -> &u32 {
         &self.buffer[(x_coord + (y_coord * self.width as u32)) as usize]
@@ -37,6 +76,23 @@ impl Framebuffer {
         &self.buffer
     }
 
+    /// Returns a mutable reference to the internal framebuffer
+    pub fn get_framebuffer_mut(&mut self) -> &mut [u32] {
+        &mut self.buffer
+    }
+
+    /// Returns the pixels of the framebuffer packed tightly in row order, with
+    /// 'PixelFormat::bytes_per_pixel' little endian bytes per pixel. Suits handing the frame
+    /// straight to a display or canvas expecting the framebuffer's format.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let bytes_per_pixel = self.format.bytes_per_pixel();
+        let mut bytes = Vec::with_capacity(self.buffer.len() * bytes_per_pixel);
+        for pixel in self.buffer.iter() {
+            bytes.extend_from_slice(&pixel.to_le_bytes()[..bytes_per_pixel]);
+        }
+        bytes
+    }
+
     /// Sets the entire framebuffer to 0.
     pub fn clear_buffer(&mut self) {
         for i in self.buffer.iter_mut() {
@@ -50,4 +106,152 @@ impl Framebuffer {
             *i = *color;
         }
     }
+
+    /// Creates a new 'Framebuffer' object holding pixels in 'format' from an image file in any
+    /// format 'load_texture_auto' can decode, such as a reference image to compare a frame with.
+    #[cfg(feature = "loader_helper")]
+    pub fn from_image(image_bytes: &[u8], format: PixelFormat) -> Result<Self, TextureLoadError> {
+        let texture = load_texture_auto(image_bytes)?;
+        let buffer = texture
+            .pixels
+            .iter()
+            .map(|color| texture.format.convert(*color, format))
+            .collect();
+        Ok(Framebuffer {
+            buffer,
+            width: texture.width as usize,
+            height: texture.height as usize,
+            format,
+        })
+    }
+
+    /// Writes the framebuffer to 'writer' as a PNG image. Grayscale framebuffers are written as
+    /// grayscale images and all others as rgb, dropping the alpha channel. PNG images can't be
+    /// empty, so framebuffers with no width or height return an 'InvalidInput' error.
+    pub fn write_png<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        if self.width == 0 || self.height == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "a PNG image needs a width and height of at least 1",
+            ));
+        }
+        let (channels, pixels) = self.to_image_bytes();
+        let png = png_encode(self.width as u32, self.height as u32, channels, &pixels);
+        writer.write_all(&png)
+    }
+
+    /// Writes the framebuffer to 'writer' as a 24 bit BMP image, dropping the alpha channel.
+    pub fn write_bmp<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        // Rows are stored bottom row first and padded to a multiple of four bytes.
+        let stride = (self.width * 3).div_ceil(4) * 4;
+        let image_size = (stride * self.height) as u32;
+        let mut bmp = Vec::with_capacity(54 + image_size as usize);
+        bmp.extend_from_slice(b"BM");
+        bmp.extend_from_slice(&(54 + image_size).to_le_bytes());
+        bmp.extend_from_slice(&0u32.to_le_bytes());
+        bmp.extend_from_slice(&54u32.to_le_bytes());
+        bmp.extend_from_slice(&40u32.to_le_bytes());
+        bmp.extend_from_slice(&(self.width as i32).to_le_bytes());
+        bmp.extend_from_slice(&(self.height as i32).to_le_bytes());
+        bmp.extend_from_slice(&1u16.to_le_bytes());
+        bmp.extend_from_slice(&24u16.to_le_bytes());
+        bmp.extend_from_slice(&0u32.to_le_bytes());
+        bmp.extend_from_slice(&image_size.to_le_bytes());
+        // 72 dots per inch, in pixels per metre.
+        bmp.extend_from_slice(&2835u32.to_le_bytes());
+        bmp.extend_from_slice(&2835u32.to_le_bytes());
+        bmp.extend_from_slice(&[0; 8]);
+        for row in self.buffer.chunks_exact(self.width.max(1)).rev() {
+            for color in row {
+                let (r, g, b, _) = self.format.unpack(*color);
+                bmp.extend_from_slice(&[b, g, r]);
+            }
+            bmp.resize(bmp.len() + stride - self.width * 3, 0);
+        }
+        writer.write_all(&bmp)
+    }
+
+    /// Writes the framebuffer to 'writer' as a binary PPM image, or a binary PGM image if the
+    /// framebuffer is grayscale, dropping the alpha channel.
+    pub fn write_ppm<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        let (channels, pixels) = self.to_image_bytes();
+        let magic = if channels == 1 { "P5" } else { "P6" };
+        write!(writer, "{}\n{} {}\n255\n", magic, self.width, self.height)?;
+        writer.write_all(&pixels)
+    }
+
+    /// Returns the pixels in row order as one gray byte per pixel for grayscale framebuffers, or
+    /// three rgb bytes for all others, with the number of bytes per pixel.
+    fn to_image_bytes(&self) -> (u8, Vec<u8>) {
+        if self.format == PixelFormat::Gray8 {
+            return (1, self.buffer.iter().map(|color| *color as u8).collect());
+        }
+        let mut bytes = Vec::with_capacity(self.buffer.len() * 3);
+        for color in self.buffer.iter() {
+            let (r, g, b, _) = self.format.unpack(*color);
+            bytes.extend_from_slice(&[r, g, b]);
+        }
+        (3, bytes)
+    }
+
+    /// Sends the whole frame to the embedded-graphics 'target' at its top left corner, converting
+    /// every pixel to the target's color type. The pixels are sent in a single 'fill_contiguous'
+    /// call, so display drivers can stream them straight out.
+    pub fn draw_to_target<D>(&self, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget,
+        D::Color: From<Rgb888>,
+    {
+        let area = Rectangle::new(Point::zero(), self.size());
+        target.fill_contiguous(
+            &area,
+            self.buffer.iter().map(|color| {
+                let (r, g, b, _) = self.format.unpack(*color);
+                Rgb888::new(r, g, b).into()
+            }),
+        )
+    }
+
+    /// Converts the embedded-graphics 'color' to a fully opaque pixel in the framebuffer's format.
+    fn pack_rgb888(&self, color: Rgb888) -> u32 {
+        self.format.pack(color.r(), color.g(), color.b(), 0xff)
+    }
+}
+
+impl OriginDimensions for Framebuffer {
+    fn size(&self) -> Size {
+        Size::new(self.width as u32, self.height as u32)
+    }
+}
+
+/// Lets embedded-graphics text, shapes and images be drawn over a rendered frame. Colors are
+/// converted to the framebuffer's 'PixelFormat' and drawn fully opaque, and pixels outside the
+/// framebuffer are skipped.
+impl DrawTarget for Framebuffer {
+    type Color = Rgb888;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            if point.x >= 0 && point.y >= 0 {
+                let color = self.pack_rgb888(color);
+                self.set_color_at(&(point.x as u32), &(point.y as u32), color);
+            }
+        }
+        Ok(())
+    }
+
+    fn fill_solid(&mut self, area: &Rectangle, color: Self::Color) -> Result<(), Self::Error> {
+        let area = area.intersection(&self.bounding_box());
+        let color = self.pack_rgb888(color);
+        let columns = area.columns();
+        for y in area.rows() {
+            let row = y as usize * self.width;
+            self.buffer[row + columns.start as usize..row + columns.end as usize].fill(color);
+        }
+        Ok(())
+    }
 }
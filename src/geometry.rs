@@ -1,4 +1,4 @@
-use std::f32::consts::PI;
+use std::{collections::HashMap, f32::consts::PI, sync::Arc};
 
 /// A 3D vector object with a W component, normalized and set to 1.0 by default.
 #[derive(Debug, Clone)]
@@ -19,7 +19,12 @@ impl Default for Vec3d {
     }
 }
 
-use crate::texture::Texture;
+use crate::{
+    color::BlendMode,
+    material::{parse_mtl, Material},
+    shader::{FragmentShader, ATTRIBUTE_COUNT},
+    texture::{Sampler, Texture},
+};
 
 /// A 2D vector object with a W component, normalized and set to 1.0 by default.
 #[derive(Debug, Clone)]
@@ -37,58 +42,266 @@ impl Default for Vec2d {
         }
     }
 }
-/// A Triangle object holding 3 Vec3d coordinates and 3 Vec2d texture coordinates.
-#[derive(Default, Debug, Clone)]
+/// A Triangle object holding 3 Vec3d coordinates and 3 Vec2d texture coordinates, along with the
+/// normal and the rgb light level at each point. The texture color is multiplied by the light
+/// level, and a zero length normal means the point has no normal of its own.
+///
+/// 'world_points' and 'attributes' are filled in by the renderer with the world space position
+/// of each point and the custom attributes written by a 'VertexShader'.
+#[derive(Debug, Clone)]
 pub struct Triangle {
     pub points: [Vec3d; 3],
     pub texture_points: [Vec2d; 3],
+    pub normals: [Vec3d; 3],
+    pub light: [[f32; 3]; 3],
+    pub world_points: [Vec3d; 3],
+    pub attributes: [[f32; ATTRIBUTE_COUNT]; 3],
+}
+
+impl Default for Triangle {
+    fn default() -> Triangle {
+        Triangle {
+            points: Default::default(),
+            texture_points: Default::default(),
+            normals: Default::default(),
+            light: [[1.0, 1.0, 1.0]; 3],
+            world_points: Default::default(),
+            attributes: [[0.0; ATTRIBUTE_COUNT]; 3],
+        }
+    }
 }
 
-/// A Mesh object holding a vector of Triangle objects and a reference to a texture.
+/// A Mesh object holding a vector of Triangle objects and a shared texture, sampler and blend
+/// mode. Triangles covered by one of the 'groups' are drawn with that group's material instead. A
+/// 'fragment_shader' replaces the renderer's fragment shader for this mesh.
 #[derive(Clone)]
-pub struct Mesh<'a> {
+pub struct Mesh {
     pub tris: Vec<Triangle>,
+    pub texture: Arc<Texture>,
+    pub materials: Vec<Material>,
+    pub groups: Vec<MaterialGroup>,
+    pub transform: Transform,
+    pub fragment_shader: Option<Arc<dyn FragmentShader>>,
+    pub sampler: Sampler,
+    pub blend_mode: BlendMode,
+}
+
+/// A run of consecutive triangles in a 'Mesh' drawn with the same texture, sampler and blend
+/// mode, see 'Mesh::batches'.
+#[derive(Debug, Clone)]
+pub struct MeshBatch<'a> {
+    pub tris: &'a [Triangle],
     pub texture: &'a Texture,
+    pub sampler: Sampler,
+    pub blend_mode: BlendMode,
+}
+
+/// A run of consecutive triangles in a 'Mesh' that share the material at index 'material'.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MaterialGroup {
+    pub material: usize,
+    pub tris: std::ops::Range<usize>,
 }
 
-/// An Animated Mesh object holding a vector of keyframe meshes and a reference to a texture.
+/// An Animated Mesh object holding a vector of keyframe meshes and a shared texture. A
+/// 'fragment_shader' replaces the renderer's fragment shader for this mesh, and 'sampler' and
+/// 'blend_mode' replace those of its keyframes.
 #[derive(Clone)]
-pub struct AnimatedMesh<'a> {
-    pub meshes: Vec<Mesh<'a>>,
-    pub texture: &'a Texture,
-    pub current_frame: Mesh<'a>,
+pub struct AnimatedMesh {
+    pub meshes: Vec<Mesh>,
+    pub texture: Arc<Texture>,
+    pub current_frame: Mesh,
     pub frames_per_second: f32,
     pub running: bool,
+    pub transform: Transform,
+    pub fragment_shader: Option<Arc<dyn FragmentShader>>,
+    pub sampler: Sampler,
+    pub blend_mode: BlendMode,
     current_frame_numeric: f32,
 }
 
-impl AnimatedMesh<'_> {
+/// A Transform object holding the translation, rotation and scale of a mesh instance in the world.
+/// 'rotation' holds the angles in radians about the x, y and z axes, applied in that order.
+#[derive(Debug, Clone)]
+pub struct Transform {
+    pub translation: Vec3d,
+    pub rotation: Vec3d,
+    pub scale: Vec3d,
+}
+
+impl Default for Transform {
+    fn default() -> Transform {
+        Transform {
+            translation: Vec3d::default(),
+            rotation: Vec3d::default(),
+            scale: Vec3d {
+                x: 1.0,
+                y: 1.0,
+                z: 1.0,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl Transform {
+    /// Sets the translation of the transform to 'x', 'y' and 'z'.
+    pub fn set_position(&mut self, x: f32, y: f32, z: f32) {
+        self.translation = Vec3d {
+            x,
+            y,
+            z,
+            ..Default::default()
+        };
+    }
+
+    /// Sets the rotation of the transform to 'x', 'y' and 'z' radians.
+    pub fn set_rotation(&mut self, x: f32, y: f32, z: f32) {
+        self.rotation = Vec3d {
+            x,
+            y,
+            z,
+            ..Default::default()
+        };
+    }
+
+    /// Sets the scale of the transform along each axis to 'x', 'y' and 'z'.
+    pub fn set_scale(&mut self, x: f32, y: f32, z: f32) {
+        self.scale = Vec3d {
+            x,
+            y,
+            z,
+            ..Default::default()
+        };
+    }
+
+    /// Moves the transform by 'x', 'y' and 'z'.
+    pub fn translate(&mut self, x: f32, y: f32, z: f32) {
+        self.translation.x += x;
+        self.translation.y += y;
+        self.translation.z += z;
+    }
+
+    /// Rotates the transform by a further 'x', 'y' and 'z' radians.
+    pub fn rotate(&mut self, x: f32, y: f32, z: f32) {
+        self.rotation.x += x;
+        self.rotation.y += y;
+        self.rotation.z += z;
+    }
+
+    /// Returns the world matrix of the transform, applying scale, then rotation, then translation.
+    pub fn matrix(&self) -> Mat4x4 {
+        let mut matrix = matrix_make_scale(self.scale.x, self.scale.y, self.scale.z);
+        matrix = matrix_multiply_matrix(&matrix, &matrix_make_rotation_x(&self.rotation.x));
+        matrix = matrix_multiply_matrix(&matrix, &matrix_make_rotation_y(&self.rotation.y));
+        matrix = matrix_multiply_matrix(&matrix, &matrix_make_rotation_z(&self.rotation.z));
+        matrix_multiply_matrix(
+            &matrix,
+            &matrix_make_translation(self.translation.x, self.translation.y, self.translation.z),
+        )
+    }
+
+    /// Returns the matrix that moves normals into world space. Unlike 'Transform::matrix' this
+    /// divides by the scale rather than multiplying by it, so normals stay perpendicular to
+    /// non-uniformly scaled surfaces, and it has no translation. The results need normalizing.
+    pub fn normal_matrix(&self) -> Mat4x4 {
+        let mut matrix =
+            matrix_make_scale(1.0 / self.scale.x, 1.0 / self.scale.y, 1.0 / self.scale.z);
+        matrix = matrix_multiply_matrix(&matrix, &matrix_make_rotation_x(&self.rotation.x));
+        matrix = matrix_multiply_matrix(&matrix, &matrix_make_rotation_y(&self.rotation.y));
+        matrix_multiply_matrix(&matrix, &matrix_make_rotation_z(&self.rotation.z))
+    }
+}
+
+/// The error returned when an obj file can not be loaded into a 'Mesh' or 'AnimatedMesh'.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+    /// The file is not valid UTF-8. 'line' is the line holding the first invalid byte.
+    InvalidUtf8 { line: usize },
+    /// The statement 'token' on 'line' is missing one of its components.
+    MissingComponent { line: usize, token: String },
+    /// 'token' on 'line' could not be parsed as a number.
+    InvalidNumber { line: usize, token: String },
+    /// 'token' on 'line' refers to a vertex or texture coordinate that does not exist.
+    IndexOutOfRange { line: usize, token: String },
+    /// The file 'token' named on 'line' could not be found by the caller's resolver.
+    UnresolvedFile { line: usize, token: String },
+    /// An 'AnimatedMesh' was created without any keyframes.
+    NoFrames,
+    /// Keyframe 'frame' of an 'AnimatedMesh' has a different triangle count to the first keyframe.
+    MismatchedFrame { frame: usize },
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::InvalidUtf8 { line } => write!(f, "line {}: invalid UTF-8", line),
+            LoadError::MissingComponent { line, token } => {
+                write!(
+                    f,
+                    "line {}: '{}' statement is missing a component",
+                    line, token
+                )
+            }
+            LoadError::InvalidNumber { line, token } => {
+                write!(f, "line {}: '{}' is not a valid number", line, token)
+            }
+            LoadError::IndexOutOfRange { line, token } => {
+                write!(f, "line {}: index '{}' is out of range", line, token)
+            }
+            LoadError::UnresolvedFile { line, token } => {
+                write!(f, "line {}: file '{}' could not be resolved", line, token)
+            }
+            LoadError::NoFrames => write!(f, "animated mesh has no keyframes"),
+            LoadError::MismatchedFrame { frame } => write!(
+                f,
+                "keyframe {} has a different triangle count to the first keyframe",
+                frame
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl AnimatedMesh {
     /// Creates a new `AnimatedMesh`.
-    pub fn new<'a>(
-        tex: &'a Texture,
+    ///
+    /// Panics if any keyframe can not be loaded, see 'AnimatedMesh::try_new' for a fallible version.
+    pub fn new(tex: Arc<Texture>, files: Vec<Vec<u8>>, fps: f32, running: bool) -> AnimatedMesh {
+        AnimatedMesh::try_new(tex, files, fps, running).unwrap()
+    }
+
+    /// Creates a new `AnimatedMesh`, returning a 'LoadError' if any keyframe can not be loaded.
+    pub fn try_new(
+        tex: Arc<Texture>,
         files: Vec<Vec<u8>>,
         fps: f32,
         running: bool,
-    ) -> AnimatedMesh<'a> {
-        let mut meshes: Vec<Mesh<'a>> = Vec::new();
-        let running = running;
-        files.into_iter().for_each(|i| {
-            let mut mesh = Mesh {
-                tris: Vec::new(),
-                texture: tex,
-            };
-            mesh.load_from_object_file(&i, true);
-            meshes.push(mesh.clone());
-        });
-        let current_frame = meshes.get(0).unwrap().clone();
-        AnimatedMesh {
-            meshes: meshes,
+    ) -> Result<AnimatedMesh, LoadError> {
+        let meshes = files
+            .iter()
+            .map(|file| Mesh::try_new(tex.clone(), file))
+            .collect::<Result<Vec<Mesh>, LoadError>>()?;
+        let current_frame = meshes.first().ok_or(LoadError::NoFrames)?.clone();
+        if let Some(frame) = meshes
+            .iter()
+            .position(|mesh| mesh.tris.len() != current_frame.tris.len())
+        {
+            return Err(LoadError::MismatchedFrame { frame });
+        }
+        Ok(AnimatedMesh {
+            meshes,
             texture: tex,
-            current_frame: current_frame,
+            current_frame,
             frames_per_second: fps,
-            running: running,
+            running,
+            transform: Transform::default(),
+            fragment_shader: None,
+            sampler: Sampler::default(),
+            blend_mode: BlendMode::Opaque,
             current_frame_numeric: 0.0,
-        }
+        })
     }
 
     /// Interpolates the 'current_frame' object based on the 'time_elapsed_seconds'.
@@ -107,7 +320,7 @@ impl AnimatedMesh<'_> {
                 next_frame = 0;
             }
             let interpolation_amount = new_frame_time - new_frame_time.floor();
-            let mut current_frame_mesh: Mesh<'_> = self.meshes.get(base_frame).unwrap().clone();
+            let mut current_frame_mesh: Mesh = self.meshes.get(base_frame).unwrap().clone();
             let mut next_frame_mesh = self.meshes.get(next_frame).unwrap().clone();
 
             for i in 0..current_frame_mesh.tris.len() {
@@ -119,19 +332,36 @@ impl AnimatedMesh<'_> {
                     let mut z_diff = next_frame_mesh.tris.get_mut(i).unwrap().points[j].z
                         - current_frame_mesh.tris.get_mut(i).unwrap().points[j].z;
 
-                    x_diff = x_diff * interpolation_amount;
-                    y_diff = y_diff * interpolation_amount;
-                    z_diff = z_diff * interpolation_amount;
+                    x_diff *= interpolation_amount;
+                    y_diff *= interpolation_amount;
+                    z_diff *= interpolation_amount;
 
                     current_frame_mesh.tris.get_mut(i).unwrap().points[j].x += x_diff;
                     current_frame_mesh.tris.get_mut(i).unwrap().points[j].y += y_diff;
                     current_frame_mesh.tris.get_mut(i).unwrap().points[j].z += z_diff;
+
+                    let current_normal = &current_frame_mesh.tris[i].normals[j];
+                    let next_normal = &next_frame_mesh.tris[i].normals[j];
+                    let normal_diff = vector_mul(
+                        &vector_sub(next_normal, current_normal),
+                        interpolation_amount,
+                    );
+                    current_frame_mesh.tris[i].normals[j] =
+                        vector_add(current_normal, &normal_diff);
                 }
             }
             self.current_frame = current_frame_mesh;
             self.current_frame_numeric = new_frame_time;
         }
     }
+
+    /// Computes smooth vertex normals for every keyframe, see 'Mesh::compute_smooth_normals'.
+    pub fn compute_smooth_normals(&mut self, angle_threshold: f32) {
+        for mesh in self.meshes.iter_mut() {
+            mesh.compute_smooth_normals(angle_threshold);
+        }
+        self.current_frame.compute_smooth_normals(angle_threshold);
+    }
 }
 
 /// A 4x4 matrix object.
@@ -140,104 +370,455 @@ pub struct Mat4x4 {
     pub m: [[f32; 4]; 4],
 }
 
-impl Mesh<'_> {
+impl Mesh {
     /// Creates a new 'Mesh' Object
-    pub fn new<'a>(tex: &'a Texture, file: &'a [u8]) -> Mesh<'a> {
+    ///
+    /// Panics if the obj file can not be loaded, see 'Mesh::try_new' for a fallible version.
+    pub fn new(tex: Arc<Texture>, file: &[u8]) -> Mesh {
+        Mesh::try_new(tex, file).unwrap()
+    }
+
+    /// Creates a new 'Mesh' Object, returning a 'LoadError' if the obj file can not be loaded.
+    pub fn try_new(tex: Arc<Texture>, file: &[u8]) -> Result<Mesh, LoadError> {
         let mut mesh = Mesh {
             tris: Vec::new(),
             texture: tex,
+            materials: Vec::new(),
+            groups: Vec::new(),
+            transform: Transform::default(),
+            fragment_shader: None,
+            sampler: Sampler::default(),
+            blend_mode: BlendMode::Opaque,
         };
-        mesh.load_from_object_file(file, true);
-        mesh
+        mesh.try_load_from_object_file(file, true)?;
+        Ok(mesh)
     }
 
-    /// Reads the obj data into a Vector containing lines.
-    fn read_lines_from_file(file: &[u8]) -> Vec<String> {
-        let mut result = Vec::new();
-        for line in std::io::read_to_string(file).unwrap().lines() {
-            result.push(line.to_string())
+    /// Creates a new 'Mesh' Object along with the materials of every 'mtllib' the obj file names.
+    /// 'mtl_resolver' returns the contents of a named mtl file and 'texture_resolver' returns the
+    /// texture for a named 'map_Kd' image, so both can be served from 'include_bytes!'.
+    pub fn try_new_with_materials<M, T>(
+        tex: Arc<Texture>,
+        file: &[u8],
+        mut mtl_resolver: M,
+        mut texture_resolver: T,
+    ) -> Result<Mesh, LoadError>
+    where
+        M: FnMut(&str) -> Option<Vec<u8>>,
+        T: FnMut(&str) -> Option<Arc<Texture>>,
+    {
+        let mut mesh = Mesh {
+            tris: Vec::new(),
+            texture: tex,
+            materials: Vec::new(),
+            groups: Vec::new(),
+            transform: Transform::default(),
+            fragment_shader: None,
+            sampler: Sampler::default(),
+            blend_mode: BlendMode::Opaque,
+        };
+        for (line_index, line) in Self::read_lines_from_file(file)?.iter().enumerate() {
+            let statement = line.split('#').next().unwrap_or_default();
+            let mut parts = statement.split_whitespace();
+            if parts.next() != Some("mtllib") {
+                continue;
+            }
+            for library in parts {
+                let mtl = mtl_resolver(library).ok_or_else(|| LoadError::UnresolvedFile {
+                    line: line_index + 1,
+                    token: library.to_string(),
+                })?;
+                mesh.materials
+                    .append(&mut parse_mtl(&mtl, &mut texture_resolver)?);
+            }
+        }
+        mesh.try_load_from_object_file(file, true)?;
+        Ok(mesh)
+    }
+
+    /// Splits the mesh into runs of triangles that share a texture, sampler and blend mode, in
+    /// triangle order.
+    pub fn batches(&self) -> Vec<MeshBatch<'_>> {
+        let mesh_batch = |tris: std::ops::Range<usize>| MeshBatch {
+            tris: &self.tris[tris],
+            texture: &self.texture,
+            sampler: self.sampler,
+            blend_mode: self.blend_mode,
+        };
+        let mut batches: Vec<MeshBatch> = Vec::new();
+        let mut start: usize = 0;
+        for group in self.groups.iter() {
+            if group.tris.start > start {
+                batches.push(mesh_batch(start..group.tris.start));
+            }
+            let material = &self.materials[group.material];
+            batches.push(MeshBatch {
+                tris: &self.tris[group.tris.clone()],
+                texture: material.diffuse_texture.as_ref().unwrap_or(&self.texture),
+                sampler: material.sampler.unwrap_or(self.sampler),
+                blend_mode: material.blend_mode,
+            });
+            start = group.tris.end;
+        }
+        if start < self.tris.len() {
+            batches.push(mesh_batch(start..self.tris.len()));
+        }
+        batches
+    }
+
+    /// Returns the mesh texture followed by the diffuse texture of every material that has one.
+    pub(crate) fn textures(&self) -> impl Iterator<Item = &Arc<Texture>> {
+        std::iter::once(&self.texture).chain(
+            self.materials
+                .iter()
+                .filter_map(|material| material.diffuse_texture.as_ref()),
+        )
+    }
+
+    /// Replaces the vertex normals of every triangle with the average of the normals of the faces
+    /// sharing that vertex, weighted by face area. Faces meeting at an angle greater than
+    /// 'angle_threshold' radians are not averaged together, so hard edges stay sharp.
+    pub fn compute_smooth_normals(&mut self, angle_threshold: f32) {
+        let face_normals: Vec<Vec3d> = self
+            .tris
+            .iter()
+            .map(|tri| {
+                let line1 = vector_sub(&tri.points[1], &tri.points[0]);
+                let line2 = vector_sub(&tri.points[2], &tri.points[0]);
+                vector_cross_product(&line1, &line2)
+            })
+            .collect();
+        let unit_normals: Vec<Vec3d> = face_normals.iter().map(normal_normalize).collect();
+
+        // Triangles touching each vertex, found by matching positions exactly.
+        let mut shared: HashMap<[u32; 3], Vec<usize>> = HashMap::new();
+        for (i, tri) in self.tris.iter().enumerate() {
+            for point in tri.points.iter() {
+                shared.entry(vertex_key(point)).or_default().push(i);
+            }
         }
-        result
+
+        let cos_threshold = angle_threshold.cos();
+        for (i, tri) in self.tris.iter_mut().enumerate() {
+            for j in 0..3 {
+                let mut sum = Vec3d::default();
+                for &other in shared[&vertex_key(&tri.points[j])].iter() {
+                    if vector_dot_product(&unit_normals[i], &unit_normals[other]) >= cos_threshold {
+                        sum = vector_add(&sum, &face_normals[other]);
+                    }
+                }
+                tri.normals[j] = normal_normalize(&sum);
+            }
+        }
+    }
+
+    /// Reads the obj data into a Vector containing lines.
+    pub(crate) fn read_lines_from_file(file: &[u8]) -> Result<Vec<String>, LoadError> {
+        let text = std::str::from_utf8(file).map_err(|e| LoadError::InvalidUtf8 {
+            line: file[..e.valid_up_to()]
+                .iter()
+                .filter(|b| **b == b'\n')
+                .count()
+                + 1,
+        })?;
+        Ok(text.lines().map(|line| line.to_string()).collect())
     }
 
     /// Populate the mesh object with vertices and texture coordinates from the obj file.
+    ///
+    /// Panics if the obj file can not be loaded, see 'Mesh::try_load_from_object_file' for a
+    /// fallible version.
     pub fn load_from_object_file(&mut self, file: &[u8], b_has_texture: bool) {
+        self.try_load_from_object_file(file, b_has_texture).unwrap()
+    }
+
+    /// Populate the mesh object with vertices and texture coordinates from the obj file, returning
+    /// a 'LoadError' describing the first malformed line. The mesh is left untouched on failure.
+    ///
+    /// Faces following a 'usemtl' statement naming one of the mesh's 'materials' are added to a
+    /// 'MaterialGroup' for that material. Unknown materials fall back to the mesh texture.
+    pub fn try_load_from_object_file(
+        &mut self,
+        file: &[u8],
+        b_has_texture: bool,
+    ) -> Result<(), LoadError> {
         let mut verts: Vec<Vec3d> = Vec::new();
         let mut texs: Vec<Vec2d> = Vec::new();
-        let lines = Self::read_lines_from_file(file);
-        for line in lines {
-            if line.starts_with("v ") {
-                let mut parts = line.split(' ');
-                let mut vert: Vec3d = Vec3d::default();
-                parts.next();
-                vert.x = parts.next().unwrap().parse::<f32>().unwrap();
-                vert.y = parts.next().unwrap().parse::<f32>().unwrap();
-                vert.z = parts.next().unwrap().parse::<f32>().unwrap();
-                verts.push(vert);
-            }
-            if line.starts_with("vt") {
-                let mut parts = line.split(' ');
-                let mut vert: Vec2d = Vec2d::default();
-                parts.next();
-                vert.u = parts.next().unwrap().parse::<f32>().unwrap();
-                vert.v = parts.next().unwrap().parse::<f32>().unwrap();
-                texs.push(vert);
-            }
-            if !b_has_texture {
-                if line.starts_with("f ") {
-                    let mut parts = line.split(' ');
-                    let mut f: [usize; 3] = [0, 0, 0];
-                    parts.next();
-                    f[0] = parts.next().unwrap().parse::<usize>().unwrap();
-                    f[1] = parts.next().unwrap().parse::<usize>().unwrap();
-                    f[2] = parts.next().unwrap().parse::<usize>().unwrap();
-                    self.tris.push(Triangle {
-                        points: [
-                            verts[f[0] - 1].clone(),
-                            verts[f[1] - 1].clone(),
-                            verts[f[2] - 1].clone(),
-                        ],
+        let mut norms: Vec<Vec3d> = Vec::new();
+        let mut tris: Vec<Triangle> = Vec::new();
+        let mut groups: Vec<MaterialGroup> = Vec::new();
+        let mut current_material: Option<usize> = None;
+        let first_tri = self.tris.len();
+        let lines = Self::read_lines_from_file(file)?;
+        for (line_index, line) in lines.iter().enumerate() {
+            let line_number = line_index + 1;
+            // Everything after a '#' is a comment.
+            let statement = line.split('#').next().unwrap_or_default();
+            let mut parts = statement.split_whitespace();
+            match parts.next() {
+                Some("v") => {
+                    verts.push(Vec3d {
+                        x: parse_component(parts.next(), line_number, "v")?,
+                        y: parse_component(parts.next(), line_number, "v")?,
+                        z: parse_component(parts.next(), line_number, "v")?,
+                        ..Default::default()
+                    });
+                }
+                Some("vt") => {
+                    let u = parse_component(parts.next(), line_number, "vt")?;
+                    // The v component is optional and defaults to 0.
+                    let v = match parts.next() {
+                        Some(token) => parse_component(Some(token), line_number, "vt")?,
+                        None => 0.0,
+                    };
+                    texs.push(Vec2d {
+                        u,
+                        v,
+                        ..Default::default()
+                    });
+                }
+                Some("vn") => {
+                    norms.push(Vec3d {
+                        x: parse_component(parts.next(), line_number, "vn")?,
+                        y: parse_component(parts.next(), line_number, "vn")?,
+                        z: parse_component(parts.next(), line_number, "vn")?,
                         ..Default::default()
-                    })
+                    });
+                }
+                Some("usemtl") => {
+                    let name = parts.collect::<Vec<&str>>().join(" ");
+                    current_material = self.materials.iter().position(|m| m.name == name);
                 }
-            } else if line.starts_with("f ") {
-                let mut parts = line.split(' ');
-                let mut f: [usize; 3] = [0, 0, 0];
-                let mut textoks: [usize; 3] = [0, 0, 0];
-                parts.next();
-                let mut counter: usize = 0;
-                for i in parts {
-                    let mut vec_comps = i.split('/');
-                    f[counter] = vec_comps.next().unwrap().parse::<usize>().unwrap();
-                    textoks[counter] = vec_comps.next().unwrap().parse::<usize>().unwrap();
-                    counter += 1;
+                Some("f") => {
+                    let corners = parts
+                        .map(|token| {
+                            parse_face_corner(
+                                token,
+                                verts.len(),
+                                texs.len(),
+                                norms.len(),
+                                line_number,
+                            )
+                        })
+                        .collect::<Result<Vec<FaceCorner>, LoadError>>()?;
+                    if corners.len() < 3 {
+                        return Err(LoadError::MissingComponent {
+                            line: line_number,
+                            token: "f".to_string(),
+                        });
+                    }
+                    let face_start = tris.len();
+                    let polygon: Vec<Vec3d> = corners
+                        .iter()
+                        .map(|corner| verts[corner.vertex].clone())
+                        .collect();
+                    for [a, b, c] in triangulate_polygon(&polygon) {
+                        let mut tri = Triangle {
+                            points: [polygon[a].clone(), polygon[b].clone(), polygon[c].clone()],
+                            ..Default::default()
+                        };
+                        for (point, corner) in [a, b, c].into_iter().enumerate() {
+                            if let Some(texture) = corners[corner].texture.filter(|_| b_has_texture)
+                            {
+                                tri.texture_points[point] = texs[texture].clone();
+                            }
+                            if let Some(normal) = corners[corner].normal {
+                                tri.normals[point] = norms[normal].clone();
+                            }
+                        }
+                        tris.push(tri);
+                    }
+                    if let Some(material) = current_material {
+                        let face_tris = (first_tri + face_start)..(first_tri + tris.len());
+                        match groups.last_mut() {
+                            Some(group)
+                                if group.material == material
+                                    && group.tris.end == face_tris.start =>
+                            {
+                                group.tris.end = face_tris.end
+                            }
+                            _ => groups.push(MaterialGroup {
+                                material,
+                                tris: face_tris,
+                            }),
+                        }
+                    }
                 }
-                self.tris.push(Triangle {
-                    points: [
-                        verts[f[0] - 1].clone(),
-                        verts[f[1] - 1].clone(),
-                        verts[f[2] - 1].clone(),
-                    ],
-                    texture_points: [
-                        texs[textoks[0] - 1].clone(),
-                        texs[textoks[1] - 1].clone(),
-                        texs[textoks[2] - 1].clone(),
-                    ],
-                    ..Default::default()
+                _ => {}
+            }
+        }
+        self.tris.append(&mut tris);
+        self.groups.append(&mut groups);
+        Ok(())
+    }
+}
+
+/// The vertex, texture coordinate and normal indices of a single corner of an obj face.
+struct FaceCorner {
+    vertex: usize,
+    texture: Option<usize>,
+    normal: Option<usize>,
+}
+
+/// Parse a face corner in any of the 'v', 'v/vt', 'v//vn' or 'v/vt/vn' forms.
+fn parse_face_corner(
+    token: &str,
+    vertex_count: usize,
+    texture_count: usize,
+    normal_count: usize,
+    line: usize,
+) -> Result<FaceCorner, LoadError> {
+    let mut comps = token.split('/');
+    let vertex = parse_index(comps.next().unwrap_or_default(), vertex_count, line)?;
+    let texture = match comps.next() {
+        Some("") | None => None,
+        Some(index) => Some(parse_index(index, texture_count, line)?),
+    };
+    let normal = match comps.next() {
+        Some("") | None => None,
+        Some(index) => Some(parse_index(index, normal_count, line)?),
+    };
+    if comps.next().is_some() {
+        return Err(LoadError::InvalidNumber {
+            line,
+            token: token.to_string(),
+        });
+    }
+    Ok(FaceCorner {
+        vertex,
+        texture,
+        normal,
+    })
+}
+
+/// Normalize a normal, leaving zero length normals at zero rather than dividing by zero.
+fn normal_normalize(normal: &Vec3d) -> Vec3d {
+    if vector_length(normal) > 0.0 {
+        vector_normalize(normal)
+    } else {
+        Vec3d::default()
+    }
+}
+
+/// The key identifying vertices at exactly the same position. Adding 0.0 folds -0.0 into 0.0.
+fn vertex_key(point: &Vec3d) -> [u32; 3] {
+    [
+        (point.x + 0.0).to_bits(),
+        (point.y + 0.0).to_bits(),
+        (point.z + 0.0).to_bits(),
+    ]
+}
+
+/// Split a planar polygon into triangles by ear clipping, preserving the polygon's winding.
+/// Returns indices into 'polygon'. Falls back to a fan if the polygon is degenerate.
+fn triangulate_polygon(polygon: &[Vec3d]) -> Vec<[usize; 3]> {
+    if polygon.len() == 3 {
+        return vec![[0, 1, 2]];
+    }
+    let fan = || (1..polygon.len() - 1).map(|i| [0, i, i + 1]).collect();
+
+    // Project the polygon onto the plane its Newell normal is most aligned with.
+    let mut normal = Vec3d::default();
+    for (i, p) in polygon.iter().enumerate() {
+        let q = &polygon[(i + 1) % polygon.len()];
+        normal.x += (p.y - q.y) * (p.z + q.z);
+        normal.y += (p.z - q.z) * (p.x + q.x);
+        normal.z += (p.x - q.x) * (p.y + q.y);
+    }
+    let (abs_x, abs_y, abs_z) = (normal.x.abs(), normal.y.abs(), normal.z.abs());
+    let (points, orientation): (Vec<(f32, f32)>, f32) = if abs_x >= abs_y && abs_x >= abs_z {
+        (polygon.iter().map(|p| (p.y, p.z)).collect(), normal.x)
+    } else if abs_y >= abs_z {
+        (polygon.iter().map(|p| (p.z, p.x)).collect(), normal.y)
+    } else {
+        (polygon.iter().map(|p| (p.x, p.y)).collect(), normal.z)
+    };
+    if orientation == 0.0 {
+        return fan();
+    }
+    let cross = |o: usize, a: usize, b: usize| {
+        let (o, a, b) = (points[o], points[a], points[b]);
+        ((a.0 - o.0) * (b.1 - o.1) - (a.1 - o.1) * (b.0 - o.0)) * orientation.signum()
+    };
+
+    let mut remaining: Vec<usize> = (0..polygon.len()).collect();
+    let mut triangles: Vec<[usize; 3]> = Vec::new();
+    while remaining.len() > 3 {
+        let count = remaining.len();
+        let ear = (0..count).find(|&i| {
+            let (prev, cur, next) = (
+                remaining[(i + count - 1) % count],
+                remaining[i],
+                remaining[(i + 1) % count],
+            );
+            cross(prev, cur, next) > 0.0
+                && remaining.iter().all(|&other| {
+                    other == prev
+                        || other == cur
+                        || other == next
+                        || cross(prev, cur, other) < 0.0
+                        || cross(cur, next, other) < 0.0
+                        || cross(next, prev, other) < 0.0
                 })
+        });
+        match ear {
+            Some(i) => {
+                triangles.push([
+                    remaining[(i + count - 1) % count],
+                    remaining[i],
+                    remaining[(i + 1) % count],
+                ]);
+                remaining.remove(i);
             }
+            None => return fan(),
         }
     }
+    triangles.push([remaining[0], remaining[1], remaining[2]]);
+    triangles
+}
+
+/// Parse a single numeric component of an obj or mtl statement.
+pub(crate) fn parse_component<T: std::str::FromStr>(
+    token: Option<&str>,
+    line: usize,
+    statement: &str,
+) -> Result<T, LoadError> {
+    let token = token.ok_or_else(|| LoadError::MissingComponent {
+        line,
+        token: statement.to_string(),
+    })?;
+    token.parse::<T>().map_err(|_| LoadError::InvalidNumber {
+        line,
+        token: token.to_string(),
+    })
+}
+
+/// Parse a one-based obj index into a zero-based index, checking it against 'count' elements.
+/// Negative indices are relative to the end of the elements read so far.
+fn parse_index(token: &str, count: usize, line: usize) -> Result<usize, LoadError> {
+    let index: i64 = parse_component(Some(token), line, "f")?;
+    let resolved = if index < 0 {
+        count as i64 + index
+    } else {
+        index - 1
+    };
+    if resolved < 0 || resolved >= count as i64 {
+        return Err(LoadError::IndexOutOfRange {
+            line,
+            token: token.to_string(),
+        });
+    }
+    Ok(resolved as usize)
 }
 
 /// Multiply a 'Mat4x4' by a 'Vec3d'.
 pub fn matrix_multiply_vector(m: &Mat4x4, i: &Vec3d) -> Vec3d {
-    let mut v: Vec3d = Vec3d::default();
-    v.x = i.x * m.m[0][0] + i.y * m.m[1][0] + i.z * m.m[2][0] + i.w * m.m[3][0];
-    v.y = i.x * m.m[0][1] + i.y * m.m[1][1] + i.z * m.m[2][1] + i.w * m.m[3][1];
-    v.z = i.x * m.m[0][2] + i.y * m.m[1][2] + i.z * m.m[2][2] + i.w * m.m[3][2];
-    v.w = i.x * m.m[0][3] + i.y * m.m[1][3] + i.z * m.m[2][3] + i.w * m.m[3][3];
-    v
+    Vec3d {
+        x: i.x * m.m[0][0] + i.y * m.m[1][0] + i.z * m.m[2][0] + i.w * m.m[3][0],
+        y: i.x * m.m[0][1] + i.y * m.m[1][1] + i.z * m.m[2][1] + i.w * m.m[3][1],
+        z: i.x * m.m[0][2] + i.y * m.m[1][2] + i.z * m.m[2][2] + i.w * m.m[3][2],
+        w: i.x * m.m[0][3] + i.y * m.m[1][3] + i.z * m.m[2][3] + i.w * m.m[3][3],
+    }
 }
 
 /// Return a 'Mat4x4' identity matrix.
@@ -295,6 +876,16 @@ pub fn matrix_make_translation(x: f32, y: f32, z: f32) -> Mat4x4 {
     matrix
 }
 
+/// Return a 'Mat4x4' scale matrix based on inputs 'x', 'y' and 'z'.
+pub fn matrix_make_scale(x: f32, y: f32, z: f32) -> Mat4x4 {
+    let mut matrix: Mat4x4 = Mat4x4::default();
+    matrix.m[0][0] = x;
+    matrix.m[1][1] = y;
+    matrix.m[2][2] = z;
+    matrix.m[3][3] = 1.0;
+    matrix
+}
+
 /// Return a 'Mat4x4' projection matrix based on the viewport parameters.
 pub fn matrix_make_projection(
     f_fov_degrees: f32,
@@ -313,6 +904,28 @@ pub fn matrix_make_projection(
     matrix
 }
 
+/// Return a 'Mat4x4' orthographic projection matrix for the view volume bounded by 'left', 'right',
+/// 'bottom', 'top', 'near' and 'far'. Depth is mapped to the same 0.0 to 1.0 range as
+/// 'matrix_make_projection', but 'w' is always 1.0.
+pub fn matrix_make_orthographic(
+    left: f32,
+    right: f32,
+    bottom: f32,
+    top: f32,
+    near: f32,
+    far: f32,
+) -> Mat4x4 {
+    let mut matrix: Mat4x4 = Mat4x4::default();
+    matrix.m[0][0] = 2.0 / (right - left);
+    matrix.m[1][1] = 2.0 / (top - bottom);
+    matrix.m[2][2] = 1.0 / (far - near);
+    matrix.m[3][0] = -(right + left) / (right - left);
+    matrix.m[3][1] = -(top + bottom) / (top - bottom);
+    matrix.m[3][2] = -near / (far - near);
+    matrix.m[3][3] = 1.0;
+    matrix
+}
+
 /// Multiply a 'Mat4x4' matrix by a 'Mat4x4' matrix.
 pub fn matrix_multiply_matrix(m1: &Mat4x4, m2: &Mat4x4) -> Mat4x4 {
     let mut matrix: Mat4x4 = Mat4x4::default();
@@ -385,38 +998,42 @@ pub fn matrix_quick_inverse(m: &Mat4x4) -> Mat4x4 {
 
 /// Add a 'Vec3d' to a 'Vec3d'.
 pub fn vector_add(v1: &Vec3d, v2: &Vec3d) -> Vec3d {
-    let mut v_result = Vec3d::default();
-    v_result.x = v1.x + v2.x;
-    v_result.y = v1.y + v2.y;
-    v_result.z = v1.z + v2.z;
-    v_result
+    Vec3d {
+        x: v1.x + v2.x,
+        y: v1.y + v2.y,
+        z: v1.z + v2.z,
+        ..Default::default()
+    }
 }
 
 /// Subtract a 'Vec3d' from a 'Vec3d'.
 pub fn vector_sub(v1: &Vec3d, v2: &Vec3d) -> Vec3d {
-    let mut v_result = Vec3d::default();
-    v_result.x = v1.x - v2.x;
-    v_result.y = v1.y - v2.y;
-    v_result.z = v1.z - v2.z;
-    v_result
+    Vec3d {
+        x: v1.x - v2.x,
+        y: v1.y - v2.y,
+        z: v1.z - v2.z,
+        ..Default::default()
+    }
 }
 
 /// Multiply a 'Vec3d' by a constant 'k'
 pub fn vector_mul(v1: &Vec3d, k: f32) -> Vec3d {
-    let mut v_result = Vec3d::default();
-    v_result.x = v1.x * k;
-    v_result.y = v1.y * k;
-    v_result.z = v1.z * k;
-    v_result
+    Vec3d {
+        x: v1.x * k,
+        y: v1.y * k,
+        z: v1.z * k,
+        ..Default::default()
+    }
 }
 
 /// Divide a 'Vec3d' by a constant 'k'
 pub fn vector_div(v1: &Vec3d, k: f32) -> Vec3d {
-    let mut v_result = Vec3d::default();
-    v_result.x = v1.x / k;
-    v_result.y = v1.y / k;
-    v_result.z = v1.z / k;
-    v_result
+    Vec3d {
+        x: v1.x / k,
+        y: v1.y / k,
+        z: v1.z / k,
+        ..Default::default()
+    }
 }
 
 /// Calculate the dot product of 2 'Vec3d' objects.
@@ -442,12 +1059,12 @@ pub fn vector_normalize(v: &Vec3d) -> Vec3d {
 
 /// Calculate the cross product of 2 'Vec3d' objects
 pub fn vector_cross_product(v1: &Vec3d, v2: &Vec3d) -> Vec3d {
-    let mut v: Vec3d = Vec3d::default();
-    v.x = v1.y * v2.z - v1.z * v2.y;
-    v.y = v1.z * v2.x - v1.x * v2.z;
-    v.z = v1.x * v2.y - v1.y * v2.x;
-
-    v
+    Vec3d {
+        x: v1.y * v2.z - v1.z * v2.y,
+        y: v1.z * v2.x - v1.x * v2.z,
+        z: v1.x * v2.y - v1.y * v2.x,
+        ..Default::default()
+    }
 }
 
 /// Calculate the distance between 2 'Vec3d' objects
@@ -465,54 +1082,23 @@ pub fn triangle_clip_against_plane(
     out_tri1: &mut Triangle,
     out_tri2: &mut Triangle,
 ) -> u64 {
-    let mut plane_n: Vec3d = vector_normalize(plane_n_input);
-
-    let mut inside_points: [Vec3d; 3] = [Vec3d::default(), Vec3d::default(), Vec3d::default()];
-    let mut n_inside_point_count: i32 = 0;
-
-    let mut outside_points: [Vec3d; 3] = [Vec3d::default(), Vec3d::default(), Vec3d::default()];
-    let mut n_outside_point_count: i32 = 0;
-
-    let mut inside_tex: [Vec2d; 3] = [Vec2d::default(), Vec2d::default(), Vec2d::default()];
-    let mut n_inside_tex_count: i32 = 0;
+    let plane_n: Vec3d = vector_normalize(plane_n_input);
 
-    let mut outside_tex: [Vec2d; 3] = [Vec2d::default(), Vec2d::default(), Vec2d::default()];
-    let mut n_outside_tex_count: i32 = 0;
+    // Indices of the points of 'in_tri' on either side of the plane.
+    let mut inside: [usize; 3] = [0; 3];
+    let mut n_inside_point_count: usize = 0;
 
-    let d0: f32 = dist(&in_tri.points[0], &plane_n, plane_p);
-    let d1: f32 = dist(&in_tri.points[1], &plane_n, plane_p);
-    let d2: f32 = dist(&in_tri.points[2], &plane_n, plane_p);
+    let mut outside: [usize; 3] = [0; 3];
+    let mut n_outside_point_count: usize = 0;
 
-    if d0 >= 0.0 {
-        inside_points[n_inside_point_count as usize] = in_tri.points[0].clone();
-        n_inside_point_count += 1;
-        inside_tex[n_inside_tex_count as usize] = in_tri.texture_points[0].clone();
-        n_inside_tex_count += 1;
-    } else {
-        outside_points[n_outside_point_count as usize] = in_tri.points[0].clone();
-        n_outside_point_count += 1;
-        outside_tex[n_outside_tex_count as usize] = in_tri.texture_points[0].clone();
-        n_outside_tex_count += 1;
-    }
-    if d1 >= 0.0 {
-        inside_points[n_inside_point_count as usize] = in_tri.points[1].clone();
-        n_inside_point_count += 1;
-        inside_tex[n_inside_tex_count as usize] = in_tri.texture_points[1].clone();
-        n_inside_tex_count += 1;
-    } else {
-        outside_points[n_outside_point_count as usize] = in_tri.points[1].clone();
-        n_outside_point_count += 1;
-        outside_tex[n_outside_tex_count as usize] = in_tri.texture_points[1].clone();
-        n_outside_tex_count += 1;
-    }
-    if d2 >= 0.0 {
-        inside_points[n_inside_point_count as usize] = in_tri.points[2].clone();
-        n_inside_point_count += 1;
-        inside_tex[n_inside_tex_count as usize] = in_tri.texture_points[2].clone();
-    } else {
-        outside_points[n_outside_point_count as usize] = in_tri.points[2].clone();
-        n_outside_point_count += 1;
-        outside_tex[n_outside_tex_count as usize] = in_tri.texture_points[2].clone();
+    for (i, point) in in_tri.points.iter().enumerate() {
+        if dist(point, &plane_n, plane_p) >= 0.0 {
+            inside[n_inside_point_count] = i;
+            n_inside_point_count += 1;
+        } else {
+            outside[n_outside_point_count] = i;
+            n_outside_point_count += 1;
+        }
     }
 
     if n_inside_point_count == 0 {
@@ -526,67 +1112,29 @@ pub fn triangle_clip_against_plane(
     }
 
     if n_inside_point_count == 1 && n_outside_point_count == 2 {
-        out_tri1.points[0] = inside_points[0].clone();
-        out_tri1.texture_points[0] = inside_tex[0].clone();
-
-        let mut t: f32 = 0.0;
-        out_tri1.points[1] = vector_intersect_plane(
-            plane_p,
-            &mut plane_n,
-            inside_points[0].clone(),
-            outside_points[0].clone(),
-            &mut t,
+        triangle_copy_point(in_tri, inside[0], out_tri1, 0);
+        triangle_clip_edge(
+            plane_p, &plane_n, in_tri, inside[0], outside[0], out_tri1, 1,
         );
-        out_tri1.texture_points[1].u = t * (outside_tex[0].u - inside_tex[0].u) + inside_tex[0].u;
-        out_tri1.texture_points[1].v = t * (outside_tex[0].v - inside_tex[0].v) + inside_tex[0].v;
-        out_tri1.texture_points[1].w = t * (outside_tex[0].w - inside_tex[0].w) + inside_tex[0].w;
-
-        out_tri1.points[2] = vector_intersect_plane(
-            plane_p,
-            &mut plane_n,
-            inside_points[0].clone(),
-            outside_points[1].clone(),
-            &mut t,
+        triangle_clip_edge(
+            plane_p, &plane_n, in_tri, inside[0], outside[1], out_tri1, 2,
         );
-        out_tri1.texture_points[2].u = t * (outside_tex[1].u - inside_tex[0].u) + inside_tex[0].u;
-        out_tri1.texture_points[2].v = t * (outside_tex[1].v - inside_tex[0].v) + inside_tex[0].v;
-        out_tri1.texture_points[2].w = t * (outside_tex[1].w - inside_tex[0].w) + inside_tex[0].w;
 
         return 1;
     }
 
     if n_inside_point_count == 2 && n_outside_point_count == 1 {
-        out_tri1.points[0] = inside_points[0].clone();
-        out_tri1.points[1] = inside_points[1].clone();
-        out_tri1.texture_points[0] = inside_tex[0].clone();
-        out_tri1.texture_points[1] = inside_tex[1].clone();
-
-        let mut t: f32 = 0.0;
-        out_tri1.points[2] = vector_intersect_plane(
-            plane_p,
-            &mut plane_n,
-            inside_points[0].clone(),
-            outside_points[0].clone(),
-            &mut t,
+        triangle_copy_point(in_tri, inside[0], out_tri1, 0);
+        triangle_copy_point(in_tri, inside[1], out_tri1, 1);
+        triangle_clip_edge(
+            plane_p, &plane_n, in_tri, inside[0], outside[0], out_tri1, 2,
         );
-        out_tri1.texture_points[2].u = t * (outside_tex[0].u - inside_tex[0].u) + inside_tex[0].u;
-        out_tri1.texture_points[2].v = t * (outside_tex[0].v - inside_tex[0].v) + inside_tex[0].v;
-        out_tri1.texture_points[2].w = t * (outside_tex[0].w - inside_tex[0].w) + inside_tex[0].w;
-
-        out_tri2.points[0] = inside_points[1].clone();
-        out_tri2.texture_points[0] = inside_tex[1].clone();
-        out_tri2.points[1] = out_tri1.points[2].clone();
-        out_tri2.texture_points[1] = out_tri1.texture_points[2].clone();
-        out_tri2.points[2] = vector_intersect_plane(
-            plane_p,
-            &mut plane_n,
-            inside_points[1].clone(),
-            outside_points[0].clone(),
-            &mut t,
+
+        triangle_copy_point(in_tri, inside[1], out_tri2, 0);
+        triangle_copy_point(out_tri1, 2, out_tri2, 1);
+        triangle_clip_edge(
+            plane_p, &plane_n, in_tri, inside[1], outside[0], out_tri2, 2,
         );
-        out_tri2.texture_points[2].u = t * (outside_tex[0].u - inside_tex[1].u) + inside_tex[1].u;
-        out_tri2.texture_points[2].v = t * (outside_tex[0].v - inside_tex[1].v) + inside_tex[1].v;
-        out_tri2.texture_points[2].w = t * (outside_tex[0].w - inside_tex[1].w) + inside_tex[1].w;
 
         2
     } else {
@@ -594,6 +1142,92 @@ pub fn triangle_clip_against_plane(
     }
 }
 
+/// Copy point 'from' of 'in_tri', along with its attributes, into point 'to' of 'out_tri'.
+fn triangle_copy_point(in_tri: &Triangle, from: usize, out_tri: &mut Triangle, to: usize) {
+    out_tri.points[to] = in_tri.points[from].clone();
+    out_tri.texture_points[to] = in_tri.texture_points[from].clone();
+    out_tri.normals[to] = in_tri.normals[from].clone();
+    out_tri.light[to] = in_tri.light[from];
+    out_tri.world_points[to] = in_tri.world_points[from].clone();
+    out_tri.attributes[to] = in_tri.attributes[from];
+}
+
+/// Set point 'to' of 'out_tri' to where the edge from point 'inside' to point 'outside' of
+/// 'in_tri' crosses the plane, interpolating the point attributes along the edge.
+fn triangle_clip_edge(
+    plane_p: &Vec3d,
+    plane_n: &Vec3d,
+    in_tri: &Triangle,
+    inside: usize,
+    outside: usize,
+    out_tri: &mut Triangle,
+    to: usize,
+) {
+    let mut t: f32 = 0.0;
+    out_tri.points[to] = vector_intersect_plane(
+        plane_p,
+        plane_n,
+        in_tri.points[inside].clone(),
+        in_tri.points[outside].clone(),
+        &mut t,
+    );
+    let tex_in = &in_tri.texture_points[inside];
+    let tex_out = &in_tri.texture_points[outside];
+    out_tri.texture_points[to] = Vec2d {
+        u: t * (tex_out.u - tex_in.u) + tex_in.u,
+        v: t * (tex_out.v - tex_in.v) + tex_in.v,
+        w: t * (tex_out.w - tex_in.w) + tex_in.w,
+    };
+    let normal_in = &in_tri.normals[inside];
+    let normal_out = &in_tri.normals[outside];
+    out_tri.normals[to] = vector_add(
+        normal_in,
+        &vector_mul(&vector_sub(normal_out, normal_in), t),
+    );
+    let light_in = in_tri.light[inside];
+    let light_out = in_tri.light[outside];
+    for channel in 0..3 {
+        out_tri.light[to][channel] =
+            t * (light_out[channel] - light_in[channel]) + light_in[channel];
+    }
+    let world_in = &in_tri.world_points[inside];
+    let world_out = &in_tri.world_points[outside];
+    out_tri.world_points[to] =
+        vector_add(world_in, &vector_mul(&vector_sub(world_out, world_in), t));
+    let attributes_in = in_tri.attributes[inside];
+    let attributes_out = in_tri.attributes[outside];
+    for attribute in 0..ATTRIBUTE_COUNT {
+        out_tri.attributes[to][attribute] =
+            t * (attributes_out[attribute] - attributes_in[attribute]) + attributes_in[attribute];
+    }
+}
+
+/// Clip the line from 'start' to 'end' against a plane, keeping the part on the side the plane
+/// normal points to. Returns 'None' if the whole line lies on the other side.
+pub fn line_clip_against_plane(
+    plane_p: &Vec3d,
+    plane_n_input: &Vec3d,
+    start: &Vec3d,
+    end: &Vec3d,
+) -> Option<(Vec3d, Vec3d)> {
+    let plane_n: Vec3d = vector_normalize(plane_n_input);
+    let start_inside = dist(start, &plane_n, plane_p) >= 0.0;
+    let end_inside = dist(end, &plane_n, plane_p) >= 0.0;
+    let mut t: f32 = 0.0;
+    match (start_inside, end_inside) {
+        (true, true) => Some((start.clone(), end.clone())),
+        (false, false) => None,
+        (true, false) => Some((
+            start.clone(),
+            vector_intersect_plane(plane_p, &plane_n, start.clone(), end.clone(), &mut t),
+        )),
+        (false, true) => Some((
+            vector_intersect_plane(plane_p, &plane_n, start.clone(), end.clone(), &mut t),
+            end.clone(),
+        )),
+    }
+}
+
 /// Get the 'Vec3d' at point of intersection. Also return 't' for calculating the new texture coordinates.
 pub fn vector_intersect_plane(
     plane_p: &Vec3d,
@@ -611,3 +1245,206 @@ pub fn vector_intersect_plane(
     let line_to_intersect: Vec3d = vector_mul(&line_start_to_end, *t);
     vector_add(&line_start, &line_to_intersect)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(obj: &str) -> Result<Mesh, LoadError> {
+        Mesh::try_new(Arc::new(Texture::default()), obj.as_bytes())
+    }
+
+    fn positions(tri: &Triangle) -> [[f32; 3]; 3] {
+        tri.points.clone().map(|p| [p.x, p.y, p.z])
+    }
+
+    fn uvs(tri: &Triangle) -> [[f32; 2]; 3] {
+        tri.texture_points.clone().map(|t| [t.u, t.v])
+    }
+
+    #[test]
+    fn malformed_vertex_lines_report_line_and_token() {
+        assert_eq!(
+            load("v 0 0 0\nv 1.0 x 2.0\n").err(),
+            Some(LoadError::InvalidNumber {
+                line: 2,
+                token: "x".to_string()
+            })
+        );
+        assert_eq!(
+            load("# comment\nv 0 0 0\nv 1.0 2.0\n").err(),
+            Some(LoadError::MissingComponent {
+                line: 3,
+                token: "v".to_string()
+            })
+        );
+        assert_eq!(
+            load("vt 0.5 nan?\n").err(),
+            Some(LoadError::InvalidNumber {
+                line: 1,
+                token: "nan?".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn malformed_face_lines_report_line_and_token() {
+        let verts = "v 0 0 0\nv 1 0 0\nv 0 1 0\n";
+        assert_eq!(
+            load(&format!("{}f 1 2 9\n", verts)).err(),
+            Some(LoadError::IndexOutOfRange {
+                line: 4,
+                token: "9".to_string()
+            })
+        );
+        assert_eq!(
+            load(&format!("{}f 1 a 3\n", verts)).err(),
+            Some(LoadError::InvalidNumber {
+                line: 4,
+                token: "a".to_string()
+            })
+        );
+        assert_eq!(
+            load(&format!("{}f 1/1 2/1 3/1\n", verts)).err(),
+            Some(LoadError::IndexOutOfRange {
+                line: 4,
+                token: "1".to_string()
+            })
+        );
+        assert_eq!(
+            load(&format!("{}\nf 1 2\n", verts)).err(),
+            Some(LoadError::MissingComponent {
+                line: 5,
+                token: "f".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn failed_loads_leave_the_mesh_untouched() {
+        let mut mesh = load("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n").unwrap();
+        let result =
+            mesh.try_load_from_object_file(b"v 0 0 0\nv 1 0 0\nf 1 2 3\nf 1 2 3 4\n", false);
+        assert!(result.is_err());
+        assert_eq!(mesh.tris.len(), 1);
+    }
+
+    #[test]
+    fn valid_triangles_load_like_the_original_loader() {
+        let obj = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\n\
+                   vt 0 0\nvt 1 0\nvt 1 1\nvt 0 1\n\
+                   f 1/1 2/2 3/3\nf 1/1 3/3 4/4\n";
+        let mesh = Mesh::new(Arc::new(Texture::default()), obj.as_bytes());
+        assert_eq!(mesh.tris.len(), 2);
+        assert_eq!(
+            positions(&mesh.tris[0]),
+            [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0]]
+        );
+        assert_eq!(
+            positions(&mesh.tris[1]),
+            [[0.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]]
+        );
+        assert_eq!(uvs(&mesh.tris[0]), [[0.0, 0.0], [1.0, 0.0], [1.0, 1.0]]);
+        assert_eq!(uvs(&mesh.tris[1]), [[0.0, 0.0], [1.0, 1.0], [0.0, 1.0]]);
+        for tri in &mesh.tris {
+            assert!(tri.points.iter().all(|p| p.w == 1.0));
+            assert_eq!(tri.light, [[1.0; 3]; 3]);
+        }
+
+        // Without textures the original loader read plain 'f a b c' faces and no coordinates.
+        let mut untextured = load("").unwrap();
+        untextured.load_from_object_file(b"v 0 0 0\nv 1 0 0\nv 1 1 0\nf 1 2 3\n", false);
+        assert_eq!(
+            positions(&untextured.tris[0]),
+            [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0]]
+        );
+        assert_eq!(uvs(&untextured.tris[0]), [[0.0, 0.0]; 3]);
+    }
+
+    /// Twice the signed area of 'tri' projected onto the xy plane.
+    fn signed_area(tri: &Triangle) -> f32 {
+        let [a, b, c] = positions(tri);
+        (b[0] - a[0]) * (c[1] - a[1]) - (b[1] - a[1]) * (c[0] - a[0])
+    }
+
+    #[test]
+    fn quads_split_into_two_triangles_with_the_same_winding() {
+        let mesh = load("v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nf 1 2 3 4\n").unwrap();
+        assert_eq!(mesh.tris.len(), 2);
+        assert!(mesh.tris.iter().all(|tri| signed_area(tri) > 0.0));
+        let area: f32 = mesh.tris.iter().map(signed_area).sum();
+        assert_eq!(area, 2.0);
+    }
+
+    #[test]
+    fn concave_polygons_are_triangulated_inside_their_outline() {
+        // A square with a notch cut into its top edge. A fan from the first corner would cover
+        // the notch with a triangle of the opposite winding.
+        let obj = "v 0 2 0\nv 0 0 0\nv 2 0 0\nv 2 2 0\nv 1 1 0\nf 1 2 3 4 5\n";
+        let mesh = load(obj).unwrap();
+        assert_eq!(mesh.tris.len(), 3);
+        assert!(mesh.tris.iter().all(|tri| signed_area(tri) > 0.0));
+        let area: f32 = mesh.tris.iter().map(signed_area).sum();
+        assert_eq!(area, 6.0);
+        // No triangle may contain the centre of the notch.
+        let notch = (1.0, 1.5);
+        for tri in &mesh.tris {
+            let [a, b, c] = positions(tri);
+            let side = |p: [f32; 3], q: [f32; 3]| {
+                (q[0] - p[0]) * (notch.1 - p[1]) - (q[1] - p[1]) * (notch.0 - p[0])
+            };
+            assert!(side(a, b) < 0.0 || side(b, c) < 0.0 || side(c, a) < 0.0);
+        }
+    }
+
+    #[test]
+    fn negative_indices_are_relative_to_the_elements_read_so_far() {
+        let obj = "v 9 9 9\nv 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0 0\nvt 1 0\nvt 0 1\n\
+                   f -3/-3 -2/-2 -1/-1\nv 5 5 5\nf -4 -3 -1\n";
+        let mesh = load(obj).unwrap();
+        assert_eq!(
+            positions(&mesh.tris[0]),
+            [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]]
+        );
+        assert_eq!(uvs(&mesh.tris[0]), [[0.0, 0.0], [1.0, 0.0], [0.0, 1.0]]);
+        assert_eq!(
+            positions(&mesh.tris[1]),
+            [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [5.0, 5.0, 5.0]]
+        );
+        assert_eq!(
+            load("v 0 0 0\nv 1 0 0\nv 0 1 0\nf -4 -2 -1\n").err(),
+            Some(LoadError::IndexOutOfRange {
+                line: 4,
+                token: "-4".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn faces_with_normals_read_every_corner_form() {
+        let obj = "v 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0.25 0.5\nvt 0.75 0.5\nvt 0.5 1\n\
+                   vn 0 0 1\nvn 0 1 0\nvn 1 0 0\n\
+                   f 1//1 2//2 3//3\nf 1/1/3 2/2/2 3/3/1\n";
+        let mesh = load(obj).unwrap();
+        assert_eq!(mesh.tris.len(), 2);
+        let normals = |tri: &Triangle| tri.normals.clone().map(|n| [n.x, n.y, n.z]);
+
+        // 'v//vn' sets the normals and leaves the texture coordinates at their default.
+        assert_eq!(
+            normals(&mesh.tris[0]),
+            [[0.0, 0.0, 1.0], [0.0, 1.0, 0.0], [1.0, 0.0, 0.0]]
+        );
+        assert_eq!(uvs(&mesh.tris[0]), [[0.0, 0.0]; 3]);
+
+        // 'v/vt/vn' sets both.
+        assert_eq!(
+            normals(&mesh.tris[1]),
+            [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]
+        );
+        assert_eq!(uvs(&mesh.tris[1]), [[0.25, 0.5], [0.75, 0.5], [0.5, 1.0]]);
+        assert_eq!(
+            positions(&mesh.tris[1]),
+            [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]]
+        );
+    }
+}
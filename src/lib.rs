@@ -10,10 +10,18 @@
 
 
 
+pub mod camera;
+pub mod color;
 pub mod draw;
 pub mod framebuffer;
 pub mod geometry;
+pub mod light;
+pub mod material;
+#[cfg(feature = "png")]
+mod png;
+mod png_writer;
 pub mod renderer;
+pub mod shader;
 pub mod texture;
 
 #[cfg(feature = "loader_helper")]
@@ -0,0 +1,118 @@
+use crate::geometry::{
+    vector_dot_product, vector_length, vector_mul, vector_normalize, vector_sub, Vec3d,
+};
+
+/// How the renderer lights the triangles it draws.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ShadingMode {
+    /// Triangles are drawn with their texture colors as-is, ignoring the scene lights.
+    #[default]
+    Unlit,
+    /// Each triangle is lit once at its centre and drawn with a single light level.
+    Flat,
+    /// Each vertex is lit separately and the light level is interpolated across the triangle.
+    Gouraud,
+}
+
+/// A light in a 'Renderer' scene. 'color' is a linear rgb color which is scaled by 'intensity'.
+#[derive(Debug, Clone)]
+pub enum Light {
+    /// Light reaching every surface equally, regardless of its position and orientation.
+    Ambient { color: [f32; 3], intensity: f32 },
+    /// Light travelling along 'direction' from infinitely far away, like sunlight.
+    Directional {
+        direction: Vec3d,
+        color: [f32; 3],
+        intensity: f32,
+    },
+    /// Light shining in all directions from 'position', fading out to nothing at 'range'.
+    Point {
+        position: Vec3d,
+        color: [f32; 3],
+        intensity: f32,
+        range: f32,
+    },
+}
+
+impl Light {
+    /// Creates a new white ambient `Light`.
+    pub fn ambient(intensity: f32) -> Light {
+        Light::Ambient {
+            color: [1.0, 1.0, 1.0],
+            intensity,
+        }
+    }
+
+    /// Creates a new white directional `Light` travelling along 'direction'.
+    pub fn directional(direction: Vec3d, intensity: f32) -> Light {
+        Light::Directional {
+            direction,
+            color: [1.0, 1.0, 1.0],
+            intensity,
+        }
+    }
+
+    /// Creates a new white point `Light` at 'position' reaching 'range' world units.
+    pub fn point(position: Vec3d, intensity: f32, range: f32) -> Light {
+        Light::Point {
+            position,
+            color: [1.0, 1.0, 1.0],
+            intensity,
+            range,
+        }
+    }
+
+    /// Returns the light this light contributes to a surface at 'position' facing along the unit
+    /// vector 'normal'.
+    pub fn illuminate(&self, position: &Vec3d, normal: &Vec3d) -> [f32; 3] {
+        let (color, strength) = match self {
+            Light::Ambient { color, intensity } => (color, *intensity),
+            Light::Directional {
+                direction,
+                color,
+                intensity,
+            } => {
+                let to_light = vector_normalize(&vector_mul(direction, -1.0));
+                let diffuse = vector_dot_product(normal, &to_light).max(0.0);
+                (color, intensity * diffuse)
+            }
+            Light::Point {
+                position: light_position,
+                color,
+                intensity,
+                range,
+            } => {
+                let to_light = vector_sub(light_position, position);
+                let distance = vector_length(&to_light);
+                if distance >= *range {
+                    return [0.0, 0.0, 0.0];
+                }
+                let falloff = (1.0 - distance / range).powi(2);
+                let diffuse = if distance > 0.0 {
+                    vector_dot_product(normal, &vector_mul(&to_light, 1.0 / distance)).max(0.0)
+                } else {
+                    1.0
+                };
+                (color, intensity * diffuse * falloff)
+            }
+        };
+        [
+            color[0] * strength,
+            color[1] * strength,
+            color[2] * strength,
+        ]
+    }
+}
+
+/// Returns the total light from 'lights' reaching a surface at 'position' facing along the unit
+/// vector 'normal'.
+pub fn light_surface(lights: &[Light], position: &Vec3d, normal: &Vec3d) -> [f32; 3] {
+    let mut total = [0.0, 0.0, 0.0];
+    for light in lights {
+        let contribution = light.illuminate(position, normal);
+        total[0] += contribution[0];
+        total[1] += contribution[1];
+        total[2] += contribution[2];
+    }
+    total
+}
@@ -0,0 +1,174 @@
+use std::sync::Arc;
+
+use crate::{
+    color::{color_pack, BlendMode},
+    geometry::{parse_component, LoadError, Mesh},
+    texture::{Sampler, Texture},
+};
+
+/// A Material object holding the surface properties of a 'newmtl' entry in an mtl file.
+/// 'blend_mode' sets how triangles using the material are combined with the framebuffer, and
+/// 'sampler' replaces the sampler of the mesh when it is set.
+///
+/// Triangles using the material are drawn with 'diffuse_texture'. 'parse_mtl' multiplies it by
+/// the 'diffuse' color and 'dissolve' alpha, uses a single pixel of that color when there is no
+/// 'map_Kd', and alpha blends materials with a 'dissolve' below 1. 'ambient', 'specular' and
+/// 'shininess' are parsed but not used by the renderer.
+#[derive(Debug, Clone)]
+pub struct Material {
+    pub name: String,
+    pub ambient: [f32; 3],
+    pub diffuse: [f32; 3],
+    pub specular: [f32; 3],
+    pub shininess: f32,
+    pub dissolve: f32,
+    pub diffuse_texture: Option<Arc<Texture>>,
+    pub sampler: Option<Sampler>,
+    pub blend_mode: BlendMode,
+}
+
+impl Material {
+    /// Creates a new `Material` called 'name' with the default mtl properties.
+    pub fn new(name: &str) -> Material {
+        Material {
+            name: name.to_string(),
+            ambient: [0.0, 0.0, 0.0],
+            diffuse: [1.0, 1.0, 1.0],
+            specular: [0.0, 0.0, 0.0],
+            shininess: 0.0,
+            dissolve: 1.0,
+            diffuse_texture: None,
+            sampler: None,
+            blend_mode: BlendMode::Opaque,
+        }
+    }
+}
+
+/// Parse the materials in an mtl file. 'texture_resolver' is called with the file name of every
+/// 'map_Kd' statement and returns the texture to use for it, or 'None' if it can't be found.
+pub fn parse_mtl<F>(file: &[u8], mut texture_resolver: F) -> Result<Vec<Material>, LoadError>
+where
+    F: FnMut(&str) -> Option<Arc<Texture>>,
+{
+    let mut materials: Vec<Material> = Vec::new();
+    for (line_index, line) in Mesh::read_lines_from_file(file)?.iter().enumerate() {
+        let line_number = line_index + 1;
+        let statement = line.split('#').next().unwrap_or_default();
+        let mut parts = statement.split_whitespace();
+        let keyword = match parts.next() {
+            Some(keyword) => keyword,
+            None => continue,
+        };
+        if keyword == "newmtl" {
+            let name = parts.collect::<Vec<&str>>().join(" ");
+            if name.is_empty() {
+                return Err(LoadError::MissingComponent {
+                    line: line_number,
+                    token: keyword.to_string(),
+                });
+            }
+            materials.push(Material::new(&name));
+            continue;
+        }
+        // Statements before the first 'newmtl' have no material to apply to.
+        let material = match materials.last_mut() {
+            Some(material) => material,
+            None => continue,
+        };
+        match keyword {
+            "Ka" => material.ambient = parse_color(&mut parts, line_number, keyword)?,
+            "Kd" => material.diffuse = parse_color(&mut parts, line_number, keyword)?,
+            "Ks" => material.specular = parse_color(&mut parts, line_number, keyword)?,
+            "Ns" => material.shininess = parse_component(parts.next(), line_number, keyword)?,
+            "d" => material.dissolve = parse_component(parts.next(), line_number, keyword)?,
+            "Tr" => {
+                let transparency: f32 = parse_component(parts.next(), line_number, keyword)?;
+                material.dissolve = 1.0 - transparency;
+            }
+            "map_Kd" => {
+                // Texture options may precede the file name, which is always the last token.
+                let file_name = parts.last().ok_or_else(|| LoadError::MissingComponent {
+                    line: line_number,
+                    token: keyword.to_string(),
+                })?;
+                let texture =
+                    texture_resolver(file_name).ok_or_else(|| LoadError::UnresolvedFile {
+                        line: line_number,
+                        token: file_name.to_string(),
+                    })?;
+                material.diffuse_texture = Some(texture);
+            }
+            _ => {}
+        }
+    }
+    for material in materials.iter_mut() {
+        material_apply_color(material);
+    }
+    Ok(materials)
+}
+
+/// Bakes the 'diffuse' color and 'dissolve' alpha of 'material' into its texture and picks its
+/// blend mode from 'dissolve'.
+fn material_apply_color(material: &mut Material) {
+    let alpha = material.dissolve.clamp(0.0, 1.0);
+    if alpha < 1.0 {
+        material.blend_mode = BlendMode::AlphaBlend;
+    }
+    let tint = [
+        material.diffuse[0],
+        material.diffuse[1],
+        material.diffuse[2],
+        alpha,
+    ];
+    material.diffuse_texture = match material.diffuse_texture.take() {
+        Some(texture) if tint == [1.0; 4] => Some(texture),
+        Some(texture) => {
+            let mut tinted = Texture::clone(&texture);
+            texture_tint(&mut tinted, &tint);
+            Some(Arc::new(tinted))
+        }
+        None => {
+            let mut color = Texture {
+                pixels: vec![color_pack(0xff, 0xff, 0xff, 0xff)],
+                width: 1,
+                height: 1,
+                ..Default::default()
+            };
+            texture_tint(&mut color, &tint);
+            Some(Arc::new(color))
+        }
+    };
+}
+
+/// Multiplies every channel of 'texture' and its mipmaps by 'tint'.
+fn texture_tint(texture: &mut Texture, tint: &[f32; 4]) {
+    let format = texture.format;
+    let scale = |channel: u8, k: f32| (channel as f32 * k.clamp(0.0, 1.0)).round() as u8;
+    for pixel in texture.pixels.iter_mut() {
+        let (r, g, b, a) = format.unpack(*pixel);
+        *pixel = format.pack(
+            scale(r, tint[0]),
+            scale(g, tint[1]),
+            scale(b, tint[2]),
+            scale(a, tint[3]),
+        );
+    }
+    for level in texture.mipmaps.iter_mut() {
+        texture_tint(level, tint);
+    }
+}
+
+/// Parse an mtl 'r g b' color, where 'g' and 'b' default to 'r' when omitted.
+fn parse_color<'a>(
+    parts: &mut impl Iterator<Item = &'a str>,
+    line: usize,
+    statement: &str,
+) -> Result<[f32; 3], LoadError> {
+    let r: f32 = parse_component(parts.next(), line, statement)?;
+    let g: f32 = match parts.next() {
+        Some(token) => parse_component(Some(token), line, statement)?,
+        None => return Ok([r, r, r]),
+    };
+    let b: f32 = parse_component(parts.next(), line, statement)?;
+    Ok([r, g, b])
+}
@@ -0,0 +1,851 @@
+use crate::png_writer::{
+    adler32, crc32, paeth, DISTANCE_BASE, DISTANCE_EXTRA, LENGTH_BASE, LENGTH_EXTRA, PNG_SIGNATURE,
+};
+use crate::texture_helper::TextureLoadError;
+
+/// The first column, first row, column step and row step of each of the seven Adam7 passes.
+const ADAM7_PASSES: [(usize, usize, usize, usize); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+/// The header of a PNG file, from its 'IHDR' chunk.
+struct PngHeader {
+    width: usize,
+    height: usize,
+    bit_depth: u8,
+    color_type: u8,
+    interlaced: bool,
+}
+
+impl PngHeader {
+    /// The number of samples held by each pixel.
+    fn channels(&self) -> usize {
+        match self.color_type {
+            2 => 3,
+            4 => 2,
+            6 => 4,
+            _ => 1,
+        }
+    }
+
+    /// The number of bytes in a row of 'width' pixels, not counting the filter type byte.
+    fn row_bytes(&self, width: usize) -> usize {
+        (width * self.channels() * self.bit_depth as usize).div_ceil(8)
+    }
+
+    /// The first column, first row, width and height of each pass of the image that holds any
+    /// pixels, seven for interlaced images and one otherwise.
+    fn passes(&self) -> Vec<(usize, usize, usize, usize, usize, usize)> {
+        let passes: &[(usize, usize, usize, usize)] = if self.interlaced {
+            &ADAM7_PASSES
+        } else {
+            &[(0, 0, 1, 1)]
+        };
+        passes
+            .iter()
+            .map(|&(x0, y0, dx, dy)| {
+                let width = (self.width + dx - 1 - x0) / dx;
+                let height = (self.height + dy - 1 - y0) / dy;
+                (x0, y0, dx, dy, width, height)
+            })
+            .filter(|&(_, _, _, _, width, height)| width > 0 && height > 0)
+            .collect()
+    }
+
+    /// The number of bytes of filtered image data the header describes, the sum of the rows of
+    /// every pass with their filter type bytes, or 'None' if it doesn't fit in a 'usize'.
+    fn data_size(&self) -> Option<usize> {
+        self.passes()
+            .iter()
+            .try_fold(0usize, |total, &(_, _, _, _, width, height)| {
+                let bits = width
+                    .checked_mul(self.channels() * self.bit_depth as usize)?
+                    .checked_add(7)?;
+                (bits / 8 + 1).checked_mul(height)?.checked_add(total)
+            })
+    }
+}
+
+/// The transparency of an image without an alpha channel, from its 'tRNS' chunk.
+enum Transparency {
+    None,
+    /// The alpha of each palette entry. Entries past the end are opaque.
+    Palette(Vec<u8>),
+    /// The gray or rgb sample values of the single fully transparent color.
+    Key([u16; 3]),
+}
+
+/// Decodes a PNG file into its width, height and the rgba color of every pixel in row order.
+pub(crate) fn png_decode(bytes: &[u8]) -> Result<(u32, u32, Vec<[u8; 4]>), TextureLoadError> {
+    if !bytes.starts_with(&PNG_SIGNATURE) {
+        return Err(TextureLoadError::InvalidSignature);
+    }
+    let mut header: Option<PngHeader> = None;
+    let mut palette: Vec<[u8; 3]> = Vec::new();
+    let mut transparency = Transparency::None;
+    let mut compressed: Vec<u8> = Vec::new();
+    let mut position = PNG_SIGNATURE.len();
+    loop {
+        let length = read_u32(bytes, position)? as usize;
+        let end = (position + 8)
+            .checked_add(length)
+            .ok_or(TextureLoadError::UnexpectedEnd)?;
+        let chunk = bytes
+            .get(position + 4..end)
+            .ok_or(TextureLoadError::UnexpectedEnd)?;
+        let checksum = read_u32(bytes, end)?;
+        position = end + 4;
+        let (name, data) = chunk.split_at(4);
+        let name = String::from_utf8_lossy(name).into_owned();
+        if crc32(chunk) != checksum {
+            return Err(corrupt(&format!("chunk '{}' has a bad checksum", name)));
+        }
+        match name.as_str() {
+            "IHDR" => header = Some(png_parse_header(data)?),
+            "PLTE" => {
+                if data.len() % 3 != 0 || data.len() > 256 * 3 {
+                    return Err(corrupt("palette has an invalid length"));
+                }
+                palette = data.chunks(3).map(|c| [c[0], c[1], c[2]]).collect();
+            }
+            "tRNS" => {
+                let header = header
+                    .as_ref()
+                    .ok_or_else(|| corrupt("'tRNS' before 'IHDR'"))?;
+                let sample = |i: usize| -> Result<u16, TextureLoadError> {
+                    Ok(u16::from_be_bytes([
+                        *data.get(i * 2).ok_or(TextureLoadError::UnexpectedEnd)?,
+                        *data.get(i * 2 + 1).ok_or(TextureLoadError::UnexpectedEnd)?,
+                    ]))
+                };
+                transparency = match header.color_type {
+                    0 => Transparency::Key([sample(0)?; 3]),
+                    2 => Transparency::Key([sample(0)?, sample(1)?, sample(2)?]),
+                    3 => Transparency::Palette(data.to_vec()),
+                    _ => return Err(corrupt("'tRNS' in an image with an alpha channel")),
+                };
+            }
+            "IDAT" => compressed.extend_from_slice(data),
+            "IEND" => break,
+            // Chunks with a lowercase first letter are ancillary and safe to ignore.
+            _ if name.as_bytes()[0].is_ascii_lowercase() => {}
+            _ => {
+                return Err(TextureLoadError::Unsupported {
+                    feature: format!("critical chunk '{}'", name),
+                })
+            }
+        }
+    }
+
+    let header = header.ok_or_else(|| corrupt("missing 'IHDR' chunk"))?;
+    if header.color_type == 3 && palette.is_empty() {
+        return Err(corrupt("missing 'PLTE' chunk"));
+    }
+    // The header fixes the size of the image data, so decompression stops as soon as the data
+    // outgrows it rather than filling memory, and the pixels are only allocated once the data
+    // is known to be all there.
+    let data_size = header
+        .data_size()
+        .ok_or_else(|| corrupt("image is too large"))?;
+    let data = zlib_decompress(&compressed, data_size)?;
+    if data.len() < data_size {
+        return Err(TextureLoadError::UnexpectedEnd);
+    }
+    let pixel_count = header
+        .width
+        .checked_mul(header.height)
+        .ok_or_else(|| corrupt("image is too large"))?;
+    let mut pixels = vec![[0u8; 4]; pixel_count];
+    let mut offset = 0;
+    for (x0, y0, dx, dy, width, height) in header.passes() {
+        let stride = header.row_bytes(width);
+        let size = (stride + 1) * height;
+        let pass = data
+            .get(offset..offset + size)
+            .ok_or(TextureLoadError::UnexpectedEnd)?;
+        offset += size;
+        let rows = png_unfilter(&header, pass, stride, height)?;
+        for (y, row) in rows.chunks(stride).enumerate() {
+            for x in 0..width {
+                let pixel = png_pixel(&header, row, x, &palette, &transparency)?;
+                pixels[(y0 + y * dy) * header.width + x0 + x * dx] = pixel;
+            }
+        }
+    }
+    Ok((header.width as u32, header.height as u32, pixels))
+}
+
+/// Parse and validate the contents of an 'IHDR' chunk.
+fn png_parse_header(data: &[u8]) -> Result<PngHeader, TextureLoadError> {
+    if data.len() != 13 {
+        return Err(corrupt("'IHDR' has an invalid length"));
+    }
+    let header = PngHeader {
+        width: read_u32(data, 0)? as usize,
+        height: read_u32(data, 4)? as usize,
+        bit_depth: data[8],
+        color_type: data[9],
+        interlaced: data[12] == 1,
+    };
+    if header.width == 0 || header.height == 0 {
+        return Err(corrupt("image has no pixels"));
+    }
+    let valid_depths: &[u8] = match header.color_type {
+        0 => &[1, 2, 4, 8, 16],
+        3 => &[1, 2, 4, 8],
+        2 | 4 | 6 => &[8, 16],
+        _ => return Err(corrupt("invalid color type")),
+    };
+    if !valid_depths.contains(&header.bit_depth) {
+        return Err(corrupt("invalid bit depth for the color type"));
+    }
+    if data[10] != 0 || data[11] != 0 || data[12] > 1 {
+        return Err(TextureLoadError::Unsupported {
+            feature: "compression, filter or interlace method".to_string(),
+        });
+    }
+    Ok(header)
+}
+
+/// Reverse the filter applied to each of the 'height' rows in 'data', returning the raw rows
+/// without their filter type bytes.
+fn png_unfilter(
+    header: &PngHeader,
+    data: &[u8],
+    stride: usize,
+    height: usize,
+) -> Result<Vec<u8>, TextureLoadError> {
+    // The distance in bytes to the matching byte of the previous pixel, rounded up to one.
+    let left = (header.channels() * header.bit_depth as usize)
+        .div_ceil(8)
+        .max(1);
+    let mut rows = vec![0u8; stride * height];
+    for y in 0..height {
+        let filter = data[y * (stride + 1)];
+        let source = &data[y * (stride + 1) + 1..(y + 1) * (stride + 1)];
+        let (done, rest) = rows.split_at_mut(y * stride);
+        let previous = if y > 0 {
+            &done[(y - 1) * stride..]
+        } else {
+            &[][..]
+        };
+        let row = &mut rest[..stride];
+        for i in 0..stride {
+            let a = if i >= left { row[i - left] } else { 0 };
+            let b = previous.get(i).copied().unwrap_or(0);
+            let c = if i >= left {
+                previous.get(i - left).copied().unwrap_or(0)
+            } else {
+                0
+            };
+            let predictor = match filter {
+                0 => 0,
+                1 => a,
+                2 => b,
+                3 => ((a as u16 + b as u16) / 2) as u8,
+                4 => paeth(a, b, c),
+                _ => return Err(corrupt("invalid row filter type")),
+            };
+            row[i] = source[i].wrapping_add(predictor);
+        }
+    }
+    Ok(rows)
+}
+
+/// Returns the rgba color of pixel 'x' in the unfiltered 'row'.
+fn png_pixel(
+    header: &PngHeader,
+    row: &[u8],
+    x: usize,
+    palette: &[[u8; 3]],
+    transparency: &Transparency,
+) -> Result<[u8; 4], TextureLoadError> {
+    let depth = header.bit_depth as usize;
+    let channels = header.channels();
+    // The raw value of sample 'i' of the pixel, at its original bit depth.
+    let sample = |i: usize| -> u16 {
+        let index = x * channels + i;
+        match depth {
+            16 => u16::from_be_bytes([row[index * 2], row[index * 2 + 1]]),
+            8 => row[index] as u16,
+            _ => {
+                let bit = index * depth;
+                ((row[bit / 8] >> (8 - depth - bit % 8)) & ((1 << depth) - 1) as u8) as u16
+            }
+        }
+    };
+    // Scale a sample to 8 bits.
+    let scale = |value: u16| -> u8 {
+        match depth {
+            16 => (value >> 8) as u8,
+            _ => (value as u32 * 255 / ((1 << depth) - 1)) as u8,
+        }
+    };
+    let pixel = match header.color_type {
+        0 | 2 => {
+            let values = if channels == 1 {
+                [sample(0); 3]
+            } else {
+                [sample(0), sample(1), sample(2)]
+            };
+            let alpha = match transparency {
+                Transparency::Key(key) if *key == values => 0,
+                _ => 0xff,
+            };
+            [scale(values[0]), scale(values[1]), scale(values[2]), alpha]
+        }
+        3 => {
+            let index = sample(0) as usize;
+            let [r, g, b] = *palette
+                .get(index)
+                .ok_or_else(|| corrupt("palette index out of range"))?;
+            let alpha = match transparency {
+                Transparency::Palette(alpha) => alpha.get(index).copied().unwrap_or(0xff),
+                _ => 0xff,
+            };
+            [r, g, b, alpha]
+        }
+        4 => {
+            let gray = scale(sample(0));
+            [gray, gray, gray, scale(sample(1))]
+        }
+        _ => [
+            scale(sample(0)),
+            scale(sample(1)),
+            scale(sample(2)),
+            scale(sample(3)),
+        ],
+    };
+    Ok(pixel)
+}
+
+/// Decompress a zlib stream, checking its header and Adler-32 checksum. Streams that decompress
+/// to more than 'limit' bytes or carry data after the checksum are rejected.
+pub(crate) fn zlib_decompress(data: &[u8], limit: usize) -> Result<Vec<u8>, TextureLoadError> {
+    if data.len() < 6 {
+        return Err(TextureLoadError::UnexpectedEnd);
+    }
+    let (cmf, flg) = (data[0], data[1]);
+    if cmf & 0x0f != 8 || !(cmf as u16 * 256 + flg as u16).is_multiple_of(31) {
+        return Err(corrupt("invalid zlib header"));
+    }
+    if flg & 0x20 != 0 {
+        return Err(TextureLoadError::Unsupported {
+            feature: "zlib preset dictionary".to_string(),
+        });
+    }
+    let mut reader = BitReader {
+        data: &data[2..],
+        position: 0,
+        bit: 0,
+    };
+    let output = inflate(&mut reader, limit)?;
+    let end = 2 + reader.position + (reader.bit > 0) as usize;
+    if read_u32(data, end)? != adler32(&output) {
+        return Err(corrupt("zlib data has a bad checksum"));
+    }
+    if data.len() > end + 4 {
+        return Err(corrupt("data follows the end of the zlib stream"));
+    }
+    Ok(output)
+}
+
+/// The order the code length code lengths are stored in a dynamic block header.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Reads the bits of a deflate stream, least significant bit first.
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    bit: u32,
+}
+
+impl BitReader<'_> {
+    /// Read the next 'count' bits as a number, with the first bit read as the lowest bit.
+    fn bits(&mut self, count: u32) -> Result<u32, TextureLoadError> {
+        let mut value = 0;
+        for i in 0..count {
+            let byte = *self
+                .data
+                .get(self.position)
+                .ok_or(TextureLoadError::UnexpectedEnd)?;
+            value |= (((byte >> self.bit) & 1) as u32) << i;
+            self.bit += 1;
+            if self.bit == 8 {
+                self.bit = 0;
+                self.position += 1;
+            }
+        }
+        Ok(value)
+    }
+
+    /// Skip to the start of the next byte.
+    fn align(&mut self) {
+        if self.bit > 0 {
+            self.bit = 0;
+            self.position += 1;
+        }
+    }
+}
+
+/// A canonical Huffman code, stored as the number of codes of each length and the symbols in
+/// code order.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    /// Creates a new `Huffman` code from the code length of each symbol, where zero means the
+    /// symbol is unused.
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut counts = [0u16; 16];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut offsets = [0u16; 16];
+        for length in 1..15 {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0u16; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Huffman { counts, symbols }
+    }
+
+    /// Read the next symbol from 'reader'.
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, TextureLoadError> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in self.counts[1..].iter() {
+            code |= reader.bits(1)? as i32;
+            let count = count as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(corrupt("invalid Huffman code"))
+    }
+}
+
+/// Decompress the raw deflate blocks read by 'reader', failing if they hold more than 'limit'
+/// bytes.
+fn inflate(reader: &mut BitReader, limit: usize) -> Result<Vec<u8>, TextureLoadError> {
+    let mut output: Vec<u8> = Vec::new();
+    loop {
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => {
+                reader.align();
+                let start = reader.position;
+                let header = reader
+                    .data
+                    .get(start..start + 4)
+                    .ok_or(TextureLoadError::UnexpectedEnd)?;
+                let length = u16::from_le_bytes([header[0], header[1]]);
+                if length != !u16::from_le_bytes([header[2], header[3]]) {
+                    return Err(corrupt("stored block length mismatch"));
+                }
+                let block = reader
+                    .data
+                    .get(start + 4..start + 4 + length as usize)
+                    .ok_or(TextureLoadError::UnexpectedEnd)?;
+                if output.len() + block.len() > limit {
+                    return Err(inflate_overflow());
+                }
+                output.extend_from_slice(block);
+                reader.position = start + 4 + length as usize;
+            }
+            1 => {
+                let mut lengths = [0u8; 288];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..].fill(8);
+                let literals = Huffman::new(&lengths);
+                let distances = Huffman::new(&[5; 30]);
+                inflate_block(reader, &literals, &distances, &mut output, limit)?;
+            }
+            2 => {
+                let (literals, distances) = inflate_dynamic_codes(reader)?;
+                inflate_block(reader, &literals, &distances, &mut output, limit)?;
+            }
+            _ => return Err(corrupt("invalid deflate block type")),
+        }
+        if last {
+            return Ok(output);
+        }
+    }
+}
+
+/// Read the literal/length and distance codes from the header of a dynamic block.
+fn inflate_dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman), TextureLoadError> {
+    let literal_count = reader.bits(5)? as usize + 257;
+    let distance_count = reader.bits(5)? as usize + 1;
+    let code_length_count = reader.bits(4)? as usize + 4;
+    let mut code_lengths = [0u8; 19];
+    for &symbol in CODE_LENGTH_ORDER[..code_length_count].iter() {
+        code_lengths[symbol] = reader.bits(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&code_lengths);
+
+    let mut lengths: Vec<u8> = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let (value, repeat) = match code_length_code.decode(reader)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths
+                    .last()
+                    .ok_or_else(|| corrupt("repeated code length with no previous length"))?;
+                (previous, 3 + reader.bits(2)?)
+            }
+            17 => (0, 3 + reader.bits(3)?),
+            _ => (0, 11 + reader.bits(7)?),
+        };
+        lengths.extend(std::iter::repeat_n(value, repeat as usize));
+    }
+    if lengths.len() > literal_count + distance_count {
+        return Err(corrupt("code lengths overrun the block header"));
+    }
+    if lengths[256] == 0 {
+        return Err(corrupt("block has no end of block code"));
+    }
+    Ok((
+        Huffman::new(&lengths[..literal_count]),
+        Huffman::new(&lengths[literal_count..]),
+    ))
+}
+
+/// Decode the literals and matches of a compressed block into 'output', up to the end of block
+/// symbol, failing if 'output' grows past 'limit' bytes.
+fn inflate_block(
+    reader: &mut BitReader,
+    literals: &Huffman,
+    distances: &Huffman,
+    output: &mut Vec<u8>,
+    limit: usize,
+) -> Result<(), TextureLoadError> {
+    loop {
+        let symbol = literals.decode(reader)? as usize;
+        if symbol < 256 {
+            if output.len() >= limit {
+                return Err(inflate_overflow());
+            }
+            output.push(symbol as u8);
+            continue;
+        } else if symbol == 256 {
+            return Ok(());
+        }
+        let symbol = symbol - 257;
+        if symbol >= LENGTH_BASE.len() {
+            return Err(corrupt("invalid length symbol"));
+        }
+        let length =
+            LENGTH_BASE[symbol] as usize + reader.bits(LENGTH_EXTRA[symbol] as u32)? as usize;
+        let symbol = distances.decode(reader)? as usize;
+        if symbol >= DISTANCE_BASE.len() {
+            return Err(corrupt("invalid distance symbol"));
+        }
+        let distance =
+            DISTANCE_BASE[symbol] as usize + reader.bits(DISTANCE_EXTRA[symbol] as u32)? as usize;
+        if distance > output.len() {
+            return Err(corrupt(
+                "match distance reaches before the start of the data",
+            ));
+        }
+        if output.len() + length > limit {
+            return Err(inflate_overflow());
+        }
+        // Matches may overlap the bytes they produce, so copy one byte at a time.
+        let start = output.len() - distance;
+        for i in 0..length {
+            output.push(output[start + i]);
+        }
+    }
+}
+
+/// The error returned when a deflate stream holds more data than expected.
+fn inflate_overflow() -> TextureLoadError {
+    corrupt("compressed data is larger than the image")
+}
+
+/// Read the big endian u32 at 'position' in 'data'.
+fn read_u32(data: &[u8], position: usize) -> Result<u32, TextureLoadError> {
+    let bytes = data
+        .get(position..position + 4)
+        .ok_or(TextureLoadError::UnexpectedEnd)?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Shorthand for a 'TextureLoadError::Corrupt' error.
+fn corrupt(reason: &str) -> TextureLoadError {
+    TextureLoadError::Corrupt {
+        reason: reason.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::png_writer::{png_encode, png_write_chunk, zlib_compress};
+
+    /// Builds a PNG file from 'data', the image data before compression with every row already
+    /// filtered, and the 'IHDR' fields given. 'extra' chunks are written before the image data.
+    fn png_file(header: &PngHeader, extra: &[(&[u8; 4], &[u8])], data: &[u8]) -> Vec<u8> {
+        let mut ihdr = Vec::new();
+        ihdr.extend_from_slice(&(header.width as u32).to_be_bytes());
+        ihdr.extend_from_slice(&(header.height as u32).to_be_bytes());
+        ihdr.extend_from_slice(&[header.bit_depth, header.color_type, 0, 0]);
+        ihdr.push(header.interlaced as u8);
+        let mut png = PNG_SIGNATURE.to_vec();
+        png_write_chunk(&mut png, b"IHDR", &ihdr);
+        for (kind, chunk) in extra {
+            png_write_chunk(&mut png, kind, chunk);
+        }
+        png_write_chunk(&mut png, b"IDAT", &zlib_compress(data));
+        png_write_chunk(&mut png, b"IEND", &[]);
+        png
+    }
+
+    /// Filters the rows of 'stride' bytes in 'pixels' with the filter types in 'filters', one
+    /// per row, returning the rows with their filter type bytes.
+    fn filter_rows(pixels: &[u8], stride: usize, left: usize, filters: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        for (y, row) in pixels.chunks_exact(stride).enumerate() {
+            let previous = |i: usize| match y {
+                0 => 0,
+                _ => pixels[(y - 1) * stride + i],
+            };
+            output.push(filters[y]);
+            for i in 0..stride {
+                let a = if i >= left { row[i - left] } else { 0 };
+                let c = if i >= left { previous(i - left) } else { 0 };
+                let predicted = match filters[y] {
+                    0 => 0,
+                    1 => a,
+                    2 => previous(i),
+                    3 => ((a as u16 + previous(i) as u16) / 2) as u8,
+                    _ => paeth(a, previous(i), c),
+                };
+                output.push(row[i].wrapping_sub(predicted));
+            }
+        }
+        output
+    }
+
+    /// A non-interlaced header for an image of the given size and type.
+    fn header(width: usize, height: usize, bit_depth: u8, color_type: u8) -> PngHeader {
+        PngHeader {
+            width,
+            height,
+            bit_depth,
+            color_type,
+            interlaced: false,
+        }
+    }
+
+    /// The rgb color used for pixel 'x', 'y' of the test images.
+    fn test_color(x: usize, y: usize) -> [u8; 3] {
+        [(x * 50) as u8, (y * 40) as u8, (x * y * 7) as u8]
+    }
+
+    #[test]
+    fn inflates_stored_blocks() {
+        let (first, second) = (&b"stored "[..], &b"blocks"[..]);
+        let mut stream = vec![0x78, 0x01];
+        for (block, last) in [(first, 0u8), (second, 1u8)] {
+            let length = block.len() as u16;
+            stream.push(last);
+            stream.extend_from_slice(&length.to_le_bytes());
+            stream.extend_from_slice(&(!length).to_le_bytes());
+            stream.extend_from_slice(block);
+        }
+        stream.extend_from_slice(&adler32(b"stored blocks").to_be_bytes());
+        assert_eq!(zlib_decompress(&stream, 13).unwrap(), b"stored blocks");
+    }
+
+    #[test]
+    fn inflates_fixed_blocks() {
+        let data: Vec<u8> = (0..2000).map(|i| (i % 251 / 3) as u8).collect();
+        let stream = zlib_compress(&data);
+        assert_eq!((stream[2] >> 1) & 3, 1, "expected a fixed Huffman block");
+        assert!(stream.len() < data.len() / 4);
+        assert_eq!(zlib_decompress(&stream, data.len()).unwrap(), data);
+    }
+
+    #[test]
+    fn inflates_dynamic_blocks() {
+        // 200 bytes from "aaaaabbbcd" picked by a linear congruential generator, compressed by
+        // zlib at level 9.
+        let stream = [
+            0x78, 0xda, 0x2d, 0x8e, 0x81, 0x11, 0x00, 0x20, 0x08, 0x02, 0x67, 0x15, 0xd8, 0x7f,
+            0x86, 0x7c, 0x29, 0x2f, 0x23, 0x14, 0xd4, 0x1e, 0x6d, 0x64, 0x14, 0x4d, 0x81, 0x64,
+            0xa8, 0xe3, 0xf7, 0xe9, 0x35, 0x9c, 0x80, 0x77, 0xcc, 0x27, 0xa0, 0x78, 0x95, 0x1a,
+            0x23, 0x68, 0x19, 0x93, 0xd3, 0x87, 0x1c, 0x97, 0xd3, 0x99, 0xb9, 0xe2, 0x89, 0xda,
+            0x9d, 0xef, 0x49, 0x23, 0x96, 0x75, 0x61, 0x97, 0x5c, 0x01, 0xd4, 0xc9, 0xb7, 0xc0,
+            0x46, 0x1d, 0xba, 0xa8, 0x3b, 0x8b, 0xf2, 0x03, 0xfd, 0xed, 0x4c, 0x63,
+        ];
+        assert_eq!((stream[2] >> 1) & 3, 2, "expected a dynamic Huffman block");
+        let mut seed = 1u32;
+        let expected: Vec<u8> = (0..200)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345) & 0x7fff_ffff;
+                b"aaaaabbbcd"[(seed >> 16) as usize % 10]
+            })
+            .collect();
+        assert_eq!(zlib_decompress(&stream, 200).unwrap(), expected);
+    }
+
+    #[test]
+    fn rejects_streams_of_the_wrong_size() {
+        let data = vec![7u8; 1000];
+        let stream = zlib_compress(&data);
+        assert!(matches!(
+            zlib_decompress(&stream, 999),
+            Err(TextureLoadError::Corrupt { .. })
+        ));
+        let mut trailing = stream.clone();
+        trailing.push(0);
+        assert!(zlib_decompress(&trailing, 1000).is_err());
+        assert!(zlib_decompress(&stream[..stream.len() - 6], 1000).is_err());
+    }
+
+    #[test]
+    fn decodes_every_filter_type() {
+        let pixels: Vec<u8> = (0..5)
+            .flat_map(|y| (0..5).flat_map(move |x| test_color(x, y)))
+            .collect();
+        let data = filter_rows(&pixels, 15, 3, &[0, 1, 2, 3, 4]);
+        let png = png_file(&header(5, 5, 8, 2), &[], &data);
+        let (width, height, decoded) = png_decode(&png).unwrap();
+        assert_eq!((width, height), (5, 5));
+        for (i, pixel) in decoded.iter().enumerate() {
+            let [r, g, b] = test_color(i % 5, i / 5);
+            assert_eq!(*pixel, [r, g, b, 0xff], "pixel {}", i);
+        }
+    }
+
+    #[test]
+    fn decodes_palette_with_transparency() {
+        // Two rows of four 2 bit palette indices, each row a single byte.
+        let indices = [0b00_01_10_11, 0b01_10_11_00];
+        let data = filter_rows(&indices, 1, 1, &[0, 1]);
+        let palette = [255, 0, 0, 0, 255, 0, 0, 0, 255, 255, 255, 255];
+        let png = png_file(
+            &header(4, 2, 2, 3),
+            &[(b"PLTE", &palette), (b"tRNS", &[0, 128])],
+            &data,
+        );
+        let (_, _, decoded) = png_decode(&png).unwrap();
+        let colors = [
+            [255, 0, 0, 0],
+            [0, 255, 0, 128],
+            [0, 0, 255, 0xff],
+            [255, 255, 255, 0xff],
+        ];
+        for (i, pixel) in decoded.iter().enumerate() {
+            assert_eq!(*pixel, colors[(i % 4 + i / 4) % 4], "pixel {}", i);
+        }
+    }
+
+    #[test]
+    fn decodes_16_bit_samples_to_their_high_byte() {
+        let samples: [u16; 8] = [
+            0x0000, 0x1234, 0xabcd, 0xffff, 0x80ff, 0x7f00, 0x0101, 0xfe01,
+        ];
+        let pixels: Vec<u8> = samples.iter().flat_map(|s| s.to_be_bytes()).collect();
+        let data = filter_rows(&pixels, 16, 8, &[4]);
+        let (_, _, decoded) = png_decode(&png_file(&header(2, 1, 16, 6), &[], &data)).unwrap();
+        assert_eq!(
+            decoded,
+            [[0x00, 0x12, 0xab, 0xff], [0x80, 0x7f, 0x01, 0xfe]]
+        );
+    }
+
+    #[test]
+    fn decodes_interlaced_images() {
+        let header = PngHeader {
+            interlaced: true,
+            ..header(9, 9, 8, 2)
+        };
+        let mut data = Vec::new();
+        for (pass, (x0, y0, dx, dy, width, height)) in header.passes().into_iter().enumerate() {
+            let pixels: Vec<u8> = (0..height)
+                .flat_map(|y| (0..width).flat_map(move |x| test_color(x0 + x * dx, y0 + y * dy)))
+                .collect();
+            let filters: Vec<u8> = (0..height).map(|y| ((pass + y) % 5) as u8).collect();
+            data.extend(filter_rows(&pixels, width * 3, 3, &filters));
+        }
+        assert_eq!(Some(data.len()), header.data_size());
+        let (width, height, decoded) = png_decode(&png_file(&header, &[], &data)).unwrap();
+        assert_eq!((width, height), (9, 9));
+        for (i, pixel) in decoded.iter().enumerate() {
+            let [r, g, b] = test_color(i % 9, i / 9);
+            assert_eq!(*pixel, [r, g, b, 0xff], "pixel {}", i);
+        }
+    }
+
+    #[test]
+    fn encoded_images_decode_to_the_same_pixels() {
+        let (width, height) = (7, 5);
+        for channels in [1u8, 3, 4] {
+            let pixels: Vec<u8> = (0..width * height * channels as usize)
+                .map(|i| (i * 37 % 256) as u8)
+                .collect();
+            let png = png_encode(width as u32, height as u32, channels, &pixels);
+            let (_, _, decoded) = png_decode(&png).unwrap();
+            for (pixel, source) in decoded.iter().zip(pixels.chunks(channels as usize)) {
+                let expected = match source {
+                    [gray] => [*gray, *gray, *gray, 0xff],
+                    [r, g, b] => [*r, *g, *b, 0xff],
+                    _ => [source[0], source[1], source[2], source[3]],
+                };
+                assert_eq!(*pixel, expected, "{} channels", channels);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_a_bad_checksum() {
+        let mut png = png_encode(2, 2, 3, &[0; 12]);
+        // The last byte of the 'IHDR' checksum.
+        png[PNG_SIGNATURE.len() + 8 + 13 + 3] ^= 1;
+        assert!(matches!(
+            png_decode(&png),
+            Err(TextureLoadError::Corrupt { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_truncated_image_data() {
+        let pixels: Vec<u8> = (0..64 * 3).map(|i| (i * 13 % 256) as u8).collect();
+        let data = filter_rows(&pixels, 24, 3, &[0; 8]);
+        let compressed = zlib_compress(&data);
+        let mut png = PNG_SIGNATURE.to_vec();
+        png_write_chunk(&mut png, b"IHDR", &[0, 0, 0, 8, 0, 0, 0, 8, 8, 2, 0, 0, 0]);
+        png_write_chunk(&mut png, b"IDAT", &compressed[..compressed.len() / 2]);
+        png_write_chunk(&mut png, b"IEND", &[]);
+        assert!(png_decode(&png).is_err());
+        // A file cut off part way through a chunk.
+        let whole = png_encode(8, 8, 3, &pixels);
+        assert!(matches!(
+            png_decode(&whole[..whole.len() - 20]),
+            Err(TextureLoadError::UnexpectedEnd)
+        ));
+    }
+}
@@ -0,0 +1,349 @@
+/// The eight bytes every PNG file starts with.
+pub(crate) const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+
+/// The lengths matched by the length symbols 257 to 285, and the number of extra bits stored
+/// after each.
+pub(crate) const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+pub(crate) const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+/// The distances matched by the distance symbols 0 to 29, and the number of extra bits stored
+/// after each.
+pub(crate) const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+pub(crate) const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// The Paeth predictor, choosing whichever of 'a', 'b' and 'c' is closest to a + b - c.
+pub(crate) fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = (
+        (p - a as i16).abs(),
+        (p - b as i16).abs(),
+        (p - c as i16).abs(),
+    );
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+/// Encodes an image into a PNG file. 'pixels' holds the 'height' rows of 'width' pixels, top row
+/// first, with 'channels' bytes per pixel: 1 for grayscale, 3 for rgb or 4 for rgba.
+pub(crate) fn png_encode(width: u32, height: u32, channels: u8, pixels: &[u8]) -> Vec<u8> {
+    let color_type = match channels {
+        1 => 0,
+        3 => 2,
+        _ => 6,
+    };
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    header.extend_from_slice(&[8, color_type, 0, 0, 0]);
+
+    let mut png = PNG_SIGNATURE.to_vec();
+    png_write_chunk(&mut png, b"IHDR", &header);
+    let filtered = png_filter(
+        width as usize * channels as usize,
+        channels as usize,
+        pixels,
+    );
+    png_write_chunk(&mut png, b"IDAT", &zlib_compress(&filtered));
+    png_write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+/// Append a chunk of type 'kind' holding 'data' to 'png', with its length and CRC.
+pub(crate) fn png_write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Filter each row of 'stride' bytes in 'pixels', returning the rows with their filter type
+/// bytes. Each row uses whichever filter gives the smallest sum of absolute differences, the
+/// usual guess at which filter compresses best. Rows with no bytes have nothing to filter.
+fn png_filter(stride: usize, channels: usize, pixels: &[u8]) -> Vec<u8> {
+    if stride == 0 {
+        return Vec::new();
+    }
+    let mut output = Vec::with_capacity(pixels.len() + pixels.len() / stride);
+    let mut candidate = vec![0u8; stride];
+    let mut best = Vec::with_capacity(stride);
+    let zero_row = vec![0u8; stride];
+    for (y, row) in pixels.chunks_exact(stride).enumerate() {
+        let previous = match y {
+            0 => &zero_row[..],
+            _ => &pixels[(y - 1) * stride..y * stride],
+        };
+        let mut best_score = u64::MAX;
+        let mut best_filter = 0;
+        for filter in 0..5u8 {
+            for (i, byte) in candidate.iter_mut().enumerate() {
+                let a = if i >= channels { row[i - channels] } else { 0 };
+                let b = previous[i];
+                let c = if i >= channels {
+                    previous[i - channels]
+                } else {
+                    0
+                };
+                let predicted = match filter {
+                    0 => 0,
+                    1 => a,
+                    2 => b,
+                    3 => ((a as u16 + b as u16) / 2) as u8,
+                    _ => paeth(a, b, c),
+                };
+                *byte = row[i].wrapping_sub(predicted);
+            }
+            let score = candidate
+                .iter()
+                .map(|&byte| (byte as i8).unsigned_abs() as u64)
+                .sum();
+            if score < best_score {
+                best_score = score;
+                best_filter = filter;
+                best.clear();
+                best.extend_from_slice(&candidate);
+            }
+        }
+        output.push(best_filter);
+        output.extend_from_slice(&best);
+    }
+    output
+}
+
+/// The largest distance back a deflate match can reach.
+const WINDOW_SIZE: usize = 32768;
+
+/// How many earlier positions with the same hash are tried when looking for a match.
+const MAX_CHAIN: usize = 64;
+
+/// Compress 'data' into a zlib stream, as a single deflate block using the fixed Huffman codes.
+pub(crate) fn zlib_compress(data: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter {
+        output: vec![0x78, 0x9c],
+        value: 0,
+        count: 0,
+    };
+    writer.bits(1, 1);
+    writer.bits(1, 2);
+
+    let mut finder = MatchFinder {
+        head: vec![usize::MAX; 1 << 15],
+        previous: vec![usize::MAX; data.len()],
+    };
+    let mut i = 0;
+    while i < data.len() {
+        let (length, distance) = finder.longest_match(data, i);
+        if length >= 3 {
+            writer.fixed_match(length, distance);
+            for k in i..i + length {
+                finder.insert(data, k);
+            }
+            i += length;
+        } else {
+            writer.fixed_symbol(data[i] as u16);
+            finder.insert(data, i);
+            i += 1;
+        }
+    }
+    writer.fixed_symbol(256);
+    writer.flush();
+
+    let mut output = writer.output;
+    output.extend_from_slice(&adler32(data).to_be_bytes());
+    output
+}
+
+/// Finds earlier copies of the bytes at a position for deflate matches. 'head' holds the most
+/// recent position of each hash of three bytes, and 'previous' the position before each position
+/// with the same hash.
+struct MatchFinder {
+    head: Vec<usize>,
+    previous: Vec<usize>,
+}
+
+impl MatchFinder {
+    /// The hash of the three bytes at 'i'.
+    fn hash(data: &[u8], i: usize) -> usize {
+        ((data[i] as usize) << 10 ^ (data[i + 1] as usize) << 5 ^ data[i + 2] as usize) & 0x7fff
+    }
+
+    /// Record the bytes at 'i' so later positions can match them.
+    fn insert(&mut self, data: &[u8], i: usize) {
+        if i + 2 < data.len() {
+            let hash = MatchFinder::hash(data, i);
+            self.previous[i] = self.head[hash];
+            self.head[hash] = i;
+        }
+    }
+
+    /// Returns the length and distance of the longest earlier copy of the bytes at 'i', or a
+    /// length of zero if there is none.
+    fn longest_match(&self, data: &[u8], i: usize) -> (usize, usize) {
+        let (mut length, mut distance) = (0, 0);
+        if i + 2 >= data.len() {
+            return (length, distance);
+        }
+        let limit = (data.len() - i).min(258);
+        let mut candidate = self.head[MatchFinder::hash(data, i)];
+        for _ in 0..MAX_CHAIN {
+            if candidate == usize::MAX || i - candidate > WINDOW_SIZE {
+                break;
+            }
+            let matched = (0..limit)
+                .take_while(|&k| data[candidate + k] == data[i + k])
+                .count();
+            if matched > length {
+                (length, distance) = (matched, i - candidate);
+                if matched == limit {
+                    break;
+                }
+            }
+            candidate = self.previous[candidate];
+        }
+        (length, distance)
+    }
+}
+
+/// Writes the bits of a deflate stream, least significant bit first.
+struct BitWriter {
+    output: Vec<u8>,
+    value: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    /// Write the lowest 'count' bits of 'value', lowest bit first.
+    fn bits(&mut self, value: u32, count: u32) {
+        self.value |= value << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.output.push(self.value as u8);
+            self.value >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Write a Huffman code of 'length' bits, which are stored highest bit first.
+    fn code(&mut self, code: u32, length: u32) {
+        self.bits(code.reverse_bits() >> (32 - length), length);
+    }
+
+    /// Write the literal or length 'symbol' with the fixed literal/length code.
+    fn fixed_symbol(&mut self, symbol: u16) {
+        let symbol = symbol as u32;
+        match symbol {
+            0..=143 => self.code(0x30 + symbol, 8),
+            144..=255 => self.code(0x190 + symbol - 144, 9),
+            256..=279 => self.code(symbol - 256, 7),
+            _ => self.code(0xc0 + symbol - 280, 8),
+        }
+    }
+
+    /// Write a match of 'length' bytes from 'distance' bytes back with the fixed codes.
+    fn fixed_match(&mut self, length: usize, distance: usize) {
+        let index = LENGTH_BASE
+            .iter()
+            .rposition(|&base| base as usize <= length)
+            .unwrap();
+        self.fixed_symbol(257 + index as u16);
+        self.bits(
+            (length - LENGTH_BASE[index] as usize) as u32,
+            LENGTH_EXTRA[index] as u32,
+        );
+        let index = DISTANCE_BASE
+            .iter()
+            .rposition(|&base| base as usize <= distance)
+            .unwrap();
+        self.code(index as u32, 5);
+        self.bits(
+            (distance - DISTANCE_BASE[index] as usize) as u32,
+            DISTANCE_EXTRA[index] as u32,
+        );
+    }
+
+    /// Write out any bits left over in the last byte.
+    fn flush(&mut self) {
+        if self.count > 0 {
+            self.output.push(self.value as u8);
+            self.value = 0;
+            self.count = 0;
+        }
+    }
+}
+
+/// The CRC-32 checksum of 'data', as used by PNG chunks.
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// The Adler-32 checksum of 'data', as used by zlib streams.
+pub(crate) fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framebuffer::Framebuffer;
+
+    #[test]
+    fn checksums_match_reference_values() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+    }
+
+    #[test]
+    fn encoded_images_have_a_header_and_end_chunk() {
+        let png = png_encode(3, 2, 4, &[0; 24]);
+        assert!(png.starts_with(&PNG_SIGNATURE));
+        assert_eq!(png[8..16], [0, 0, 0, 13, b'I', b'H', b'D', b'R']);
+        assert_eq!(png[16..29], [0, 0, 0, 3, 0, 0, 0, 2, 8, 6, 0, 0, 0]);
+        assert!(png.ends_with(&[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]));
+    }
+
+    #[test]
+    fn empty_images_are_rejected_without_panicking() {
+        assert!(png_filter(0, 3, &[]).is_empty());
+        for (width, height) in [(0, 5), (5, 0), (0, 0)] {
+            let mut png = Vec::new();
+            let error = Framebuffer::new(width, height)
+                .write_png(&mut png)
+                .unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+            assert!(png.is_empty());
+        }
+    }
+}
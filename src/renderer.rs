@@ -1,110 +1,419 @@
-use std::collections::VecDeque;
+use std::{collections::VecDeque, sync::Arc};
+
+use embedded_graphics::{draw_target::DrawTarget, pixelcolor::Rgb888};
 
 use crate::{
-    draw::draw_textured_triangle,
+    camera::{Camera, Projection},
+    color::{color_pack, BlendMode, PixelFormat},
+    draw::{
+        fill_depth_tested_line, fill_depth_tested_point, fill_triangle, RasterTarget,
+        RasteriserMode,
+    },
     framebuffer::Framebuffer,
     geometry::{
-        matrix_make_projection, matrix_make_rotation_x, matrix_make_rotation_y,
-        matrix_make_rotation_z, matrix_make_translation, matrix_multiply_matrix,
-        matrix_multiply_vector, matrix_point_at, matrix_quick_inverse, triangle_clip_against_plane,
-        vector_add, vector_cross_product, vector_div, vector_dot_product, vector_mul, vector_sub,
-        Mat4x4, Mesh, Triangle, Vec3d, AnimatedMesh,
+        line_clip_against_plane, matrix_multiply_vector, triangle_clip_against_plane, vector_add,
+        vector_cross_product, vector_div, vector_dot_product, vector_length, vector_normalize,
+        vector_sub, AnimatedMesh, Mat4x4, Mesh, Transform, Triangle, Vec3d,
     },
+    light::{light_surface, Light, ShadingMode},
+    shader::{Fragment, FragmentShader, Vertex, VertexShader},
+    texture::{Sampler, Texture},
 };
 
+/// What a 'Renderer' draws for each triangle. Every mode other than 'Textured' and
+/// 'TexturedWireframe' is for debugging, and ignores textures, lighting, blend modes and fragment
+/// shaders.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RenderMode {
+    /// Textured and lit triangles, colored by any fragment shaders.
+    #[default]
+    Textured,
+    /// Just the edges of the triangles on the clear color, hiding edges behind nearer triangles.
+    Wireframe,
+    /// Textured triangles with their edges drawn over them.
+    TexturedWireframe,
+    /// Every triangle in its own solid color, to pick out the individual triangles.
+    FlatColor,
+    /// Every pixel colored by its world space normal, with the x, y and z axes mapped from -1 to 1
+    /// onto red, green and blue. Triangles without vertex normals use their face normal.
+    Normals,
+    /// Every pixel shaded by its distance from the camera, from white for the nearest point in the
+    /// frame to black for the furthest.
+    Depth,
+}
+
+/// A stable handle to a mesh or animated mesh in a 'Renderer' scene. Handles are never reused, so
+/// a handle to a removed mesh stays invalid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MeshHandle(u64);
+
 /// A Renderer object. This is the object responsible for performing the geometric calculations
 /// to output a 2d camera perspective of the 3D environment passed in via the mesh lists.
-pub struct Renderer<'a> {
-    meshes: Vec<Mesh<'a>>,
-    animated_meshes: Vec<AnimatedMesh<'a>>,
+pub struct Renderer {
+    meshes: Vec<(MeshHandle, Mesh)>,
+    animated_meshes: Vec<(MeshHandle, AnimatedMesh)>,
+    next_handle: u64,
     view_width: usize,
     view_height: usize,
     framebuffer_clear_color: u32,
-    mat_projection: Mat4x4,
-    vec_camera: Vec3d,
-    vec_target: Vec3d,
-    vec_look_dir: Vec3d,
-    vec_up: Vec3d,
-    yaw: f32,
+    camera: Camera,
     depth_buffer: Vec<f32>,
+    lights: Vec<Light>,
+    shading_mode: ShadingMode,
+    vertex_shader: Option<Arc<dyn VertexShader>>,
+    fragment_shader: Option<Arc<dyn FragmentShader>>,
+    rasteriser_mode: RasteriserMode,
+    render_mode: RenderMode,
+    wireframe_color: u32,
+    debug_primitives: Vec<DebugPrimitive>,
+    target_framebuffer: Option<Framebuffer>,
+    converted_textures: Vec<(Arc<Texture>, Vec<Texture>)>,
 }
 
-impl Renderer<'_> {
-    
-    /// Creates a new `Renderer`.
-    pub fn new<'a>(
-        meshes: Vec<Mesh<'a>>,
-        animated_meshes: Vec<AnimatedMesh<'a>>,
+impl Renderer {
+    /// Creates a new `Renderer`. 'framebuffer_clear_color' is in the 'PixelFormat' of the
+    /// framebuffers it renders into.
+    pub fn new(
+        meshes: Vec<Mesh>,
+        animated_meshes: Vec<AnimatedMesh>,
         view_width: usize,
         view_height: usize,
         framebuffer_clear_color: u32,
-    ) -> Renderer<'a> {
-        //For first draft lets make some defaults for the projection matrix.
-        let mat_projection =
-            matrix_make_projection(90.0, view_height as f32 / view_width as f32, 0.1, 1000.0);
-        let vec_camera: Vec3d = Vec3d::default();
-        let vec_target: Vec3d = Vec3d {
-            x: 0.0,
-            y: 0.0,
-            z: 1.0,
-            ..Default::default()
-        };
-        let vec_look_dir: Vec3d = Vec3d {
-            x: 0.0,
-            y: 0.0,
-            z: 1.0,
-            ..Default::default()
-        };
-        let vec_up: Vec3d = Vec3d {
-            x: 0.0,
-            y: 1.0,
-            z: 0.0,
-            ..Default::default()
-        };
-        let yaw: f32 = 0.0;
+    ) -> Renderer {
+        let camera: Camera = Camera::new(view_width, view_height);
         let depth_buffer: Vec<f32> = vec![0.0; view_width * view_height];
-        Renderer {
-            meshes,
-            animated_meshes,
+        let mut renderer = Renderer {
+            meshes: Vec::new(),
+            animated_meshes: Vec::new(),
+            next_handle: 0,
             view_width,
             view_height,
             framebuffer_clear_color,
-            mat_projection,
-            vec_camera,
-            vec_look_dir,
-            vec_target,
-            vec_up,
-            yaw,
+            camera,
             depth_buffer,
+            lights: Vec::new(),
+            shading_mode: ShadingMode::default(),
+            vertex_shader: None,
+            fragment_shader: None,
+            rasteriser_mode: RasteriserMode::default(),
+            render_mode: RenderMode::default(),
+            wireframe_color: color_pack(0xff, 0xff, 0xff, 0xff),
+            debug_primitives: Vec::new(),
+            target_framebuffer: None,
+            converted_textures: Vec::new(),
+        };
+        for mesh in meshes {
+            renderer.add_mesh(mesh);
         }
+        for animated_mesh in animated_meshes {
+            renderer.add_animated_mesh(animated_mesh);
+        }
+        renderer
+    }
+
+    /// Returns a reference to the renderer camera.
+    pub fn camera(&self) -> &Camera {
+        &self.camera
+    }
+
+    /// Returns a mutable reference to the renderer camera.
+    pub fn camera_mut(&mut self) -> &mut Camera {
+        &mut self.camera
+    }
+
+    /// Replaces the renderer camera with 'camera'.
+    pub fn set_camera(&mut self, camera: Camera) {
+        self.camera = camera;
+    }
+
+    /// Returns the depth buffer of the last frame rendered, one value per pixel in row order. Each
+    /// value is 1 minus the normalised depth from the camera's projection matrix, so larger values
+    /// are nearer the camera and 0.0 is left where nothing was drawn. See 'Camera::linear_depth'.
+    pub fn depth_buffer(&self) -> &[f32] {
+        &self.depth_buffer
+    }
+
+    /// Returns the depth buffer of the last frame converted to the distance of each pixel in front
+    /// of the renderer camera, in world units. Pixels where nothing was drawn are at the far clip
+    /// plane. Frames drawn with 'render_with_camera' should be converted with that camera instead.
+    pub fn linear_depth_buffer(&self) -> Vec<f32> {
+        self.depth_buffer
+            .iter()
+            .map(|depth| self.camera.linear_depth(*depth))
+            .collect()
+    }
+
+    /// Returns the depth buffer of the last frame as a grayscale image, ready to save with
+    /// 'Framebuffer::write_png' or 'write_ppm'. Pixels 'min_depth' or less in front of the
+    /// renderer camera are white and pixels 'max_depth' or further are black, with the distances
+    /// between shaded linearly. Pixels where nothing was drawn are black.
+    pub fn depth_image(&self, min_depth: f32, max_depth: f32) -> Framebuffer {
+        let mut image =
+            Framebuffer::with_format(self.view_width, self.view_height, PixelFormat::Gray8);
+        let pixels = image.get_framebuffer_mut().iter_mut();
+        for (pixel, depth) in pixels.zip(self.depth_buffer.iter()) {
+            if *depth > 0.0 {
+                let distance = self.camera.linear_depth(*depth);
+                let brightness = (max_depth - distance) / (max_depth - min_depth);
+                *pixel = (brightness.clamp(0.0, 1.0) * 255.0).round() as u32;
+            }
+        }
+        image
     }
 
     /// Translates the renderer camera yaw by 'yaw_adjustment'
     pub fn translate_yaw(&mut self, yaw_adjustment: f32) {
-        self.yaw += yaw_adjustment;
+        self.camera.rotate_yaw(yaw_adjustment);
     }
-    
+
     /// Translates the renderer camera yaw by 'x_adjustment'
     pub fn translate_camera_x(&mut self, x_adjustment: f32) {
-        self.vec_camera.x += x_adjustment;
+        self.camera.translate(x_adjustment, 0.0, 0.0);
     }
     /// Translates the renderer camera yaw by 'y_adjustment'
     pub fn translate_camera_y(&mut self, y_adjustment: f32) {
-        self.vec_camera.y += y_adjustment;
+        self.camera.translate(0.0, y_adjustment, 0.0);
     }
     /// Translates the renderer camera forward location by 'forward_adjustment' * 'time_elapsed'
     pub fn translate_camera_forward(&mut self, forward_adjustment: f32, time_elapsed: f32) {
-        let vec_forward = vector_mul(&self.vec_look_dir, forward_adjustment * time_elapsed);
-        self.vec_camera = vector_add(&self.vec_camera, &vec_forward);
+        self.camera.move_forward(forward_adjustment * time_elapsed);
     }
     /// Translates the renderer camera backward location by 'backward_adjustment' * 'time_elapsed'
     pub fn translate_camera_backward(&mut self, backward_adjustment: f32, time_elapsed: f32) {
-        let vec_backward = vector_mul(&self.vec_look_dir, backward_adjustment * time_elapsed);
-        self.vec_camera = vector_sub(&self.vec_camera, &vec_backward);
+        self.camera
+            .move_backward(backward_adjustment * time_elapsed);
+    }
+
+    /// Adds 'light' to the scene.
+    pub fn add_light(&mut self, light: Light) {
+        self.lights.push(light);
+    }
+
+    /// Returns the lights in the scene.
+    pub fn lights(&self) -> &[Light] {
+        &self.lights
+    }
+
+    /// Returns a mutable reference to the list of lights in the scene.
+    pub fn lights_mut(&mut self) -> &mut Vec<Light> {
+        &mut self.lights
+    }
+
+    /// Removes every light from the scene.
+    pub fn clear_lights(&mut self) {
+        self.lights.clear();
+    }
+
+    /// Returns how the renderer lights the scene.
+    pub fn shading_mode(&self) -> ShadingMode {
+        self.shading_mode
+    }
+
+    /// Sets how the renderer lights the scene. Meshes are drawn unlit by default.
+    pub fn set_shading_mode(&mut self, shading_mode: ShadingMode) {
+        self.shading_mode = shading_mode;
+    }
+
+    /// Sets the vertex shader run on every point of every mesh, or removes it with 'None'.
+    pub fn set_vertex_shader(&mut self, vertex_shader: Option<Arc<dyn VertexShader>>) {
+        self.vertex_shader = vertex_shader;
+    }
+
+    /// Sets the fragment shader used for meshes without a 'fragment_shader' of their own, or
+    /// returns to plain texture sampling with 'None'.
+    pub fn set_fragment_shader(&mut self, fragment_shader: Option<Arc<dyn FragmentShader>>) {
+        self.fragment_shader = fragment_shader;
+    }
+
+    /// Returns the algorithm the renderer fills triangles with.
+    pub fn rasteriser_mode(&self) -> RasteriserMode {
+        self.rasteriser_mode
+    }
+
+    /// Sets the algorithm the renderer fills triangles with. The scanline rasteriser is used by
+    /// default.
+    pub fn set_rasteriser_mode(&mut self, rasteriser_mode: RasteriserMode) {
+        self.rasteriser_mode = rasteriser_mode;
+    }
+
+    /// Returns what the renderer draws for each triangle.
+    pub fn render_mode(&self) -> RenderMode {
+        self.render_mode
+    }
+
+    /// Sets what the renderer draws for each triangle. Triangles are drawn textured by default.
+    pub fn set_render_mode(&mut self, render_mode: RenderMode) {
+        self.render_mode = render_mode;
+    }
+
+    /// Returns the color of the wireframe render modes' lines.
+    pub fn wireframe_color(&self) -> u32 {
+        self.wireframe_color
+    }
+
+    /// Sets the color of the wireframe render modes' lines, in the default 'PixelFormat' whatever
+    /// the format of the framebuffers the renderer draws into, see 'color_pack'. Lines are white
+    /// by default.
+    pub fn set_wireframe_color(&mut self, wireframe_color: u32) {
+        self.wireframe_color = wireframe_color;
+    }
+
+    /// Queues a line from 'a' to 'b' in world space to be drawn over the next frame in 'color',
+    /// in the default 'PixelFormat' like 'set_wireframe_color'. The line is clipped against the camera's near
+    /// plane and hidden behind nearer triangles, and the queue is emptied once the frame is drawn.
+    pub fn draw_line_3d(&mut self, a: Vec3d, b: Vec3d, color: u32) {
+        self.debug_primitives
+            .push(DebugPrimitive::Line { a, b, color });
+    }
+
+    /// Queues a 'size' pixel wide square at 'point' in world space to be drawn over the next frame
+    /// in 'color', like 'draw_line_3d'. Points in front of the camera's near plane are skipped.
+    pub fn draw_point_3d(&mut self, point: Vec3d, size: u32, color: u32) {
+        self.debug_primitives
+            .push(DebugPrimitive::Point { point, size, color });
+    }
+
+    /// Empties the queue of lines and points waiting to be drawn over the next frame.
+    pub fn clear_debug_draws(&mut self) {
+        self.debug_primitives.clear();
+    }
+
+    /// Adds 'mesh' to the scene and returns its handle.
+    pub fn add_mesh(&mut self, mesh: Mesh) -> MeshHandle {
+        let handle = self.allocate_handle();
+        self.meshes.push((handle, mesh));
+        handle
+    }
+
+    /// Adds 'animated_mesh' to the scene and returns its handle.
+    pub fn add_animated_mesh(&mut self, animated_mesh: AnimatedMesh) -> MeshHandle {
+        let handle = self.allocate_handle();
+        self.animated_meshes.push((handle, animated_mesh));
+        handle
+    }
+
+    /// Removes the mesh or animated mesh with 'handle' from the scene. Returns false if the handle
+    /// does not refer to anything in the scene.
+    pub fn remove(&mut self, handle: MeshHandle) -> bool {
+        if let Ok(index) = self.meshes.binary_search_by_key(&handle, |(h, _)| *h) {
+            self.meshes.remove(index);
+            true
+        } else if let Ok(index) = self
+            .animated_meshes
+            .binary_search_by_key(&handle, |(h, _)| *h)
+        {
+            self.animated_meshes.remove(index);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns a reference to the mesh with 'handle'.
+    pub fn get(&self, handle: MeshHandle) -> Option<&Mesh> {
+        let index = self
+            .meshes
+            .binary_search_by_key(&handle, |(h, _)| *h)
+            .ok()?;
+        Some(&self.meshes[index].1)
+    }
+
+    /// Returns a mutable reference to the mesh with 'handle', for example to update its transform.
+    pub fn get_mut(&mut self, handle: MeshHandle) -> Option<&mut Mesh> {
+        let index = self
+            .meshes
+            .binary_search_by_key(&handle, |(h, _)| *h)
+            .ok()?;
+        Some(&mut self.meshes[index].1)
+    }
+
+    /// Returns a reference to the animated mesh with 'handle'.
+    pub fn get_animated(&self, handle: MeshHandle) -> Option<&AnimatedMesh> {
+        let index = self
+            .animated_meshes
+            .binary_search_by_key(&handle, |(h, _)| *h)
+            .ok()?;
+        Some(&self.animated_meshes[index].1)
+    }
+
+    /// Returns a mutable reference to the animated mesh with 'handle', for example to update its
+    /// transform.
+    pub fn get_animated_mut(&mut self, handle: MeshHandle) -> Option<&mut AnimatedMesh> {
+        let index = self
+            .animated_meshes
+            .binary_search_by_key(&handle, |(h, _)| *h)
+            .ok()?;
+        Some(&mut self.animated_meshes[index].1)
+    }
+
+    /// Iterates over the meshes in the scene along with their handles, in the order they were added.
+    pub fn meshes(&self) -> impl Iterator<Item = (MeshHandle, &Mesh)> {
+        self.meshes.iter().map(|(handle, mesh)| (*handle, mesh))
+    }
+
+    /// Iterates mutably over the meshes in the scene along with their handles.
+    pub fn meshes_mut(&mut self) -> impl Iterator<Item = (MeshHandle, &mut Mesh)> {
+        self.meshes.iter_mut().map(|(handle, mesh)| (*handle, mesh))
+    }
+
+    /// Iterates over the animated meshes in the scene along with their handles, in the order they
+    /// were added.
+    pub fn animated_meshes(&self) -> impl Iterator<Item = (MeshHandle, &AnimatedMesh)> {
+        self.animated_meshes
+            .iter()
+            .map(|(handle, mesh)| (*handle, mesh))
+    }
+
+    /// Iterates mutably over the animated meshes in the scene along with their handles.
+    pub fn animated_meshes_mut(&mut self) -> impl Iterator<Item = (MeshHandle, &mut AnimatedMesh)> {
+        self.animated_meshes
+            .iter_mut()
+            .map(|(handle, mesh)| (*handle, mesh))
+    }
+
+    /// Returns the next unused handle. Handles increase monotonically so each list stays sorted.
+    fn allocate_handle(&mut self) -> MeshHandle {
+        let handle = MeshHandle(self.next_handle);
+        self.next_handle += 1;
+        handle
     }
 
     /// Performs the render function, translating the world meshes and camera location into a 2D frame.
+    /// Textures not in the 'PixelFormat' of 'framebuffer' are drawn from a copy converted to it,
+    /// see 'Texture::convert'. The copy is made the first time the texture is drawn in that format
+    /// and kept while the texture is in the scene, leaving the mesh and material textures as they
+    /// were.
     pub fn render(&mut self, time_elapsed: f32, framebuffer: &mut Framebuffer) {
+        let camera = self.camera.clone();
+        self.render_with_camera(time_elapsed, &camera, framebuffer);
+    }
+
+    /// Performs the render function and draws the frame into the embedded-graphics 'target' at its
+    /// top left corner, converting each pixel to the target's color type. The frame is rendered
+    /// into a framebuffer in the default 'PixelFormat' kept by the renderer, then sent to the
+    /// target in one go, see 'Framebuffer::draw_to_target'.
+    pub fn render_to_target<D>(&mut self, time_elapsed: f32, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget,
+        D::Color: From<Rgb888>,
+    {
+        let mut framebuffer = self
+            .target_framebuffer
+            .take()
+            .unwrap_or_else(|| Framebuffer::new(self.view_width, self.view_height));
+        self.render(time_elapsed, &mut framebuffer);
+        let result = framebuffer.draw_to_target(target);
+        self.target_framebuffer = Some(framebuffer);
+        result
+    }
+
+    /// Performs the render function as seen from 'camera' rather than the renderer camera.
+    pub fn render_with_camera(
+        &mut self,
+        time_elapsed: f32,
+        camera: &Camera,
+        framebuffer: &mut Framebuffer,
+    ) {
         //Clear the depth buffer and frame buffer for pixel rendering
         for i in 0..self.view_width * self.view_height {
             self.depth_buffer[i] = 0.0;
@@ -112,306 +421,1008 @@ impl Renderer<'_> {
 
         framebuffer.clear_buffer_color(&self.framebuffer_clear_color);
 
-        for i in 0..self.animated_meshes.len()
-        {
-            self.animated_meshes.get_mut(i).unwrap().tick(time_elapsed);
+        for (_, animated_mesh) in self.animated_meshes.iter_mut() {
+            animated_mesh.tick(time_elapsed);
+        }
+
+        let mut render_list: Vec<Mesh> = self.meshes.iter().map(|(_, mesh)| mesh.clone()).collect();
+
+        for (_, animated_mesh) in self.animated_meshes.iter() {
+            let mut frame = animated_mesh.current_frame.clone();
+            frame.transform = animated_mesh.transform.clone();
+            frame.fragment_shader = animated_mesh.fragment_shader.clone();
+            frame.sampler = animated_mesh.sampler;
+            frame.blend_mode = animated_mesh.blend_mode;
+            render_list.push(frame);
+        }
+
+        let format = framebuffer.format();
+        let mut converted_textures = std::mem::take(&mut self.converted_textures);
+        convert_textures(&mut converted_textures, &render_list, format);
+
+        let mut raster_list: Vec<RasterBatch> = Vec::new();
+        for mesh in render_list.iter() {
+            let fragment_shader: Option<Arc<dyn FragmentShader>> = mesh
+                .fragment_shader
+                .clone()
+                .or_else(|| self.fragment_shader.clone());
+            for batch in mesh.batches() {
+                raster_list.push(RasterBatch {
+                    tris: self.project_triangles(batch.tris, &mesh.transform, camera),
+                    texture: converted_texture(batch.texture, format, &converted_textures),
+                    sampler: batch.sampler,
+                    shader: fragment_shader.clone(),
+                    blend: batch.blend_mode,
+                });
+            }
+        }
+        if !matches!(
+            self.render_mode,
+            RenderMode::Textured | RenderMode::TexturedWireframe
+        ) {
+            self.apply_debug_shader(&mut raster_list, camera, format);
+        }
+        let draw_order = draw_order(&raster_list);
+        self.rasterise(&raster_list, &draw_order, framebuffer);
+
+        if matches!(
+            self.render_mode,
+            RenderMode::Wireframe | RenderMode::TexturedWireframe
+        ) {
+            self.draw_wireframe(&raster_list, framebuffer);
+        }
+        drop(raster_list);
+        self.converted_textures = converted_textures;
+        self.draw_debug_primitives(camera, framebuffer);
+    }
+
+    /// Replaces the shader and blend mode of every batch with the 'DebugShader' for the render
+    /// mode, storing the flat color or face normal of each triangle in its attributes.
+    fn apply_debug_shader(
+        &self,
+        batches: &mut [RasterBatch],
+        camera: &Camera,
+        format: PixelFormat,
+    ) {
+        // Larger depths are nearer the camera.
+        let points = batches
+            .iter()
+            .flat_map(|batch| batch.tris.iter())
+            .flat_map(|tri| tri.points.iter());
+        let (furthest, nearest) = points
+            .fold((f32::MAX, f32::MIN), |(furthest, nearest), point| {
+                (furthest.min(point.z), nearest.max(point.z))
+            });
+        // An empty frame has nothing to shade, so use the whole depth range of the camera.
+        let (nearest, furthest) = if furthest > nearest {
+            (1.0, 0.0)
+        } else {
+            (nearest, furthest)
+        };
+        let shader: Arc<dyn FragmentShader> = Arc::new(DebugShader {
+            mode: self.render_mode,
+            format,
+            nearest: camera.linear_depth(nearest),
+            furthest: camera.linear_depth(furthest),
+            camera: camera.clone(),
+        });
+
+        let (r, g, b, _) = format.unpack(self.framebuffer_clear_color);
+        let clear_color = [r, g, b].map(|channel| channel as f32 / 255.0);
+        let mut index: u32 = 0;
+        for batch in batches.iter_mut() {
+            for tri in batch.tris.iter_mut() {
+                let attributes = match self.render_mode {
+                    RenderMode::Wireframe => clear_color,
+                    RenderMode::FlatColor => {
+                        // Scatter the triangle indices over the colors, keeping them bright.
+                        let hash = index.wrapping_mul(0x9e37_79b9).to_be_bytes();
+                        [hash[0], hash[1], hash[2]].map(|channel| (channel | 0x40) as f32 / 255.0)
+                    }
+                    _ => {
+                        let normal = vector_normalize(&vector_cross_product(
+                            &vector_sub(&tri.world_points[1], &tri.world_points[0]),
+                            &vector_sub(&tri.world_points[2], &tri.world_points[0]),
+                        ));
+                        [normal.x, normal.y, normal.z]
+                    }
+                };
+                for point_attributes in tri.attributes.iter_mut() {
+                    point_attributes[..3].copy_from_slice(&attributes);
+                }
+                index += 1;
+            }
+            batch.shader = Some(shader.clone());
+            batch.blend = BlendMode::Opaque;
         }
+    }
 
-        let mat_rot_z: Mat4x4 = matrix_make_rotation_z(&(0.0));
-        let mat_rot_x: Mat4x4 = matrix_make_rotation_x(&0.0);
-        let mat_trans: Mat4x4 = matrix_make_translation(0.0, 0.0, 5.0);
-        let mut mat_world = matrix_multiply_matrix(&mat_rot_z, &mat_rot_x);
-        mat_world = matrix_multiply_matrix(&mat_world, &mat_trans);
+    /// Draws the edges of every triangle in 'batches' over the framebuffer, hiding edges behind
+    /// the depth buffer.
+    fn draw_wireframe(&mut self, batches: &[RasterBatch], framebuffer: &mut Framebuffer) {
+        let color = PixelFormat::default().convert(self.wireframe_color, framebuffer.format());
+        let mut target =
+            RasterTarget::full(framebuffer, &mut self.depth_buffer, self.view_width as i64);
+        for tri in batches.iter().flat_map(|batch| batch.tris.iter()) {
+            for (a, b) in [(0, 1), (1, 2), (2, 0)] {
+                fill_depth_tested_line(&tri.points[a], &tri.points[b], color, &mut target);
+            }
+        }
+    }
 
-        self.vec_look_dir = Vec3d {
+    /// Draws and empties the queue of lines and points from 'draw_line_3d' and 'draw_point_3d' as
+    /// seen from 'camera', hiding them behind the depth buffer.
+    fn draw_debug_primitives(&mut self, camera: &Camera, framebuffer: &mut Framebuffer) {
+        if self.debug_primitives.is_empty() {
+            return;
+        }
+        let mat_view: Mat4x4 = camera.view_matrix();
+        let mat_projection: Mat4x4 = camera.projection_matrix();
+        let near_plane = Vec3d {
             x: 0.0,
             y: 0.0,
-            z: 1.0,
+            z: camera.near,
             ..Default::default()
         };
-        self.vec_target = Vec3d {
+        let near_normal = Vec3d {
             x: 0.0,
             y: 0.0,
             z: 1.0,
             ..Default::default()
         };
+        let (view_width, view_height) = (self.view_width, self.view_height);
+        let project =
+            |point: &Vec3d| project_point(point, &mat_projection, view_width, view_height);
+        let format = framebuffer.format();
+        let convert = |color: u32| PixelFormat::default().convert(color, format);
+        let mut target =
+            RasterTarget::full(framebuffer, &mut self.depth_buffer, self.view_width as i64);
+        for primitive in self.debug_primitives.iter() {
+            match primitive {
+                DebugPrimitive::Line { a, b, color } => {
+                    let a_viewed = matrix_multiply_vector(&mat_view, a);
+                    let b_viewed = matrix_multiply_vector(&mat_view, b);
+                    if let Some((a_clipped, b_clipped)) =
+                        line_clip_against_plane(&near_plane, &near_normal, &a_viewed, &b_viewed)
+                    {
+                        fill_depth_tested_line(
+                            &project(&a_clipped),
+                            &project(&b_clipped),
+                            convert(*color),
+                            &mut target,
+                        );
+                    }
+                }
+                DebugPrimitive::Point { point, size, color } => {
+                    let viewed = matrix_multiply_vector(&mat_view, point);
+                    if viewed.z >= camera.near {
+                        fill_depth_tested_point(
+                            &project(&viewed),
+                            *size,
+                            convert(*color),
+                            &mut target,
+                        );
+                    }
+                }
+            }
+        }
+        self.debug_primitives.clear();
+    }
 
-        let mat_camera_rot = matrix_make_rotation_y(&self.yaw);
-        self.vec_look_dir = matrix_multiply_vector(&mat_camera_rot, &self.vec_target);
-        self.vec_target = vector_add(&self.vec_camera, &self.vec_look_dir);
-
-        let mat_camera: Mat4x4 = matrix_point_at(&self.vec_camera, &self.vec_target, &self.vec_up);
+    /// Transforms, lights, clips and projects 'tris', returning the screen space triangles to
+    /// rasterise.
+    fn project_triangles(
+        &self,
+        tris: &[Triangle],
+        transform: &Transform,
+        camera: &Camera,
+    ) -> Vec<Triangle> {
+        let mat_world: Mat4x4 = transform.matrix();
+        let mat_normal: Mat4x4 = transform.normal_matrix();
+        let mat_view: Mat4x4 = camera.view_matrix();
+        let mat_projection: Mat4x4 = camera.projection_matrix();
+        let mut vec_triangles_to_raster: Vec<Triangle> = Vec::new();
+        let mut screen_triangles: Vec<Triangle> = Vec::new();
+        for tri in tris.iter() {
+            let mut tri_transformed: Triangle = tri.clone();
+            for i in 0..3 {
+                tri_transformed.points[i] = matrix_multiply_vector(&mat_world, &tri.points[i]);
+                tri_transformed.normals[i] = matrix_multiply_vector(&mat_normal, &tri.normals[i]);
+            }
 
-        let mat_view: Mat4x4 = matrix_quick_inverse(&mat_camera);
+            if let Some(vertex_shader) = &self.vertex_shader {
+                for i in 0..3 {
+                    let mut vertex = Vertex {
+                        position: tri_transformed.points[i].clone(),
+                        normal: tri_transformed.normals[i].clone(),
+                        texture_point: tri_transformed.texture_points[i].clone(),
+                        attributes: tri_transformed.attributes[i],
+                    };
+                    vertex_shader.shade(&mut vertex);
+                    tri_transformed.points[i] = vertex.position;
+                    tri_transformed.normals[i] = vertex.normal;
+                    tri_transformed.texture_points[i] = vertex.texture_point;
+                    tri_transformed.attributes[i] = vertex.attributes;
+                }
+            }
+            tri_transformed.world_points = tri_transformed.points.clone();
 
-        let mut render_list: Vec<Mesh<'_>> = self.meshes.clone();     
+            let line1 = vector_sub(&tri_transformed.points[1], &tri_transformed.points[0]);
+            let line2 = vector_sub(&tri_transformed.points[2], &tri_transformed.points[0]);
+            let normal = vector_cross_product(&line1, &line2);
 
-        for i in 0..self.animated_meshes.len()
-        {
-            render_list.push(self.animated_meshes.get(i).unwrap().current_frame.clone());
-        }
-        
+            // An orthographic camera looks along the same ray from every point on the screen.
+            let v_camera_ray: Vec3d = match camera.projection {
+                Projection::Perspective => vector_sub(&tri_transformed.points[0], &camera.position),
+                Projection::Orthographic { .. } => camera.look_direction(),
+            };
 
-        for mesh in render_list.iter() {
-            let mut vec_triangles_to_raster: Vec<Triangle> = Vec::new();
-            for tri in mesh.tris.iter() {
-                let mut tri_projected: Triangle = Triangle::default();
-                let mut tri_transformed: Triangle = Triangle::default();
-                let mut tri_viewed: Triangle = Triangle::default();
-
-                tri_transformed.points[0] = matrix_multiply_vector(&mat_world, &tri.points[0]);
-                tri_transformed.points[1] = matrix_multiply_vector(&mat_world, &tri.points[1]);
-                tri_transformed.points[2] = matrix_multiply_vector(&mat_world, &tri.points[2]);
-                tri_transformed.texture_points[0] = tri.texture_points[0].clone();
-                tri_transformed.texture_points[1] = tri.texture_points[1].clone();
-                tri_transformed.texture_points[2] = tri.texture_points[2].clone();
-
-                let line1 = vector_sub(&tri_transformed.points[1], &tri_transformed.points[0]);
-                let line2 = vector_sub(&tri_transformed.points[2], &tri_transformed.points[0]);
-                let normal = vector_cross_product(&line1, &line2);
-
-                let v_camera_ray: Vec3d = vector_sub(&tri_transformed.points[0], &self.vec_camera);
-
-                if vector_dot_product(&normal, &v_camera_ray) < 0.0 {
-                    tri_viewed.points[0] =
-                        matrix_multiply_vector(&mat_view, &tri_transformed.points[0]);
-                    tri_viewed.points[1] =
-                        matrix_multiply_vector(&mat_view, &tri_transformed.points[1]);
-                    tri_viewed.points[2] =
-                        matrix_multiply_vector(&mat_view, &tri_transformed.points[2]);
-                    tri_viewed.texture_points[0] = tri_transformed.texture_points[0].clone();
-                    tri_viewed.texture_points[1] = tri_transformed.texture_points[1].clone();
-                    tri_viewed.texture_points[2] = tri_transformed.texture_points[2].clone();
-                    let mut clipped_1: Triangle = Triangle::default();
-                    let mut clipped_2: Triangle = Triangle::default();
-
-                    let n_clipped_triangles = triangle_clip_against_plane(
-                        &mut Vec3d {
-                            x: 0.0,
-                            y: 0.0,
-                            z: 0.1,
-                            ..Default::default()
-                        },
-                        &mut Vec3d {
-                            x: 0.0,
-                            y: 0.0,
-                            z: 1.0,
-                            ..Default::default()
-                        },
-                        &mut tri_viewed,
-                        &mut clipped_1,
-                        &mut clipped_2,
-                    );
-
-                    for i in 0..n_clipped_triangles {
-                        if i == 0 {
-                            // Project triangles
-                            tri_projected.points[0] =
-                                matrix_multiply_vector(&self.mat_projection, &clipped_1.points[0]);
-                            tri_projected.points[1] =
-                                matrix_multiply_vector(&self.mat_projection, &clipped_1.points[1]);
-                            tri_projected.points[2] =
-                                matrix_multiply_vector(&self.mat_projection, &clipped_1.points[2]);
-                            tri_projected.texture_points[0] = clipped_1.texture_points[0].clone();
-                            tri_projected.texture_points[1] = clipped_1.texture_points[1].clone();
-                            tri_projected.texture_points[2] = clipped_1.texture_points[2].clone();
-                        } else if i == 1 {
-                            // Project triangles
-                            tri_projected.points[0] =
-                                matrix_multiply_vector(&self.mat_projection, &clipped_2.points[0]);
-                            tri_projected.points[1] =
-                                matrix_multiply_vector(&self.mat_projection, &clipped_2.points[1]);
-                            tri_projected.points[2] =
-                                matrix_multiply_vector(&self.mat_projection, &clipped_2.points[2]);
-                            tri_projected.texture_points[0] = clipped_2.texture_points[0].clone();
-                            tri_projected.texture_points[1] = clipped_2.texture_points[1].clone();
-                            tri_projected.texture_points[2] = clipped_2.texture_points[2].clone();
+            if vector_dot_product(&normal, &v_camera_ray) < 0.0 {
+                // Light the triangle in world space, where the lights are positioned.
+                match self.shading_mode {
+                    ShadingMode::Unlit => {}
+                    ShadingMode::Flat => {
+                        let unit_normal = vector_normalize(&normal);
+                        let centre = vector_div(
+                            &vector_add(
+                                &vector_add(&tri_transformed.points[0], &tri_transformed.points[1]),
+                                &tri_transformed.points[2],
+                            ),
+                            3.0,
+                        );
+                        let light = light_surface(&self.lights, &centre, &unit_normal);
+                        tri_transformed.light = [light; 3];
+                    }
+                    ShadingMode::Gouraud => {
+                        // Points without a vertex normal fall back to the face normal.
+                        let face_normal = vector_normalize(&normal);
+                        for i in 0..3 {
+                            let vertex_normal = &tri_transformed.normals[i];
+                            let unit_normal = if vector_length(vertex_normal) > 0.0 {
+                                vector_normalize(vertex_normal)
+                            } else {
+                                face_normal.clone()
+                            };
+                            tri_transformed.light[i] = light_surface(
+                                &self.lights,
+                                &tri_transformed.points[i],
+                                &unit_normal,
+                            );
                         }
+                    }
+                }
 
-                        tri_projected.texture_points[0].u /= tri_projected.points[0].w;
-                        tri_projected.texture_points[1].u /= tri_projected.points[1].w;
-                        tri_projected.texture_points[2].u /= tri_projected.points[2].w;
-
-                        tri_projected.texture_points[0].v /= tri_projected.points[0].w;
-                        tri_projected.texture_points[1].v /= tri_projected.points[1].w;
-                        tri_projected.texture_points[2].v /= tri_projected.points[2].w;
+                let mut tri_viewed: Triangle = tri_transformed.clone();
+                tri_viewed.points[0] =
+                    matrix_multiply_vector(&mat_view, &tri_transformed.points[0]);
+                tri_viewed.points[1] =
+                    matrix_multiply_vector(&mat_view, &tri_transformed.points[1]);
+                tri_viewed.points[2] =
+                    matrix_multiply_vector(&mat_view, &tri_transformed.points[2]);
+                let mut clipped_1: Triangle = Triangle::default();
+                let mut clipped_2: Triangle = Triangle::default();
 
-                        tri_projected.texture_points[0].w = 1.0 / tri_projected.points[0].w;
-                        tri_projected.texture_points[1].w = 1.0 / tri_projected.points[1].w;
-                        tri_projected.texture_points[2].w = 1.0 / tri_projected.points[2].w;
+                let n_clipped_triangles = triangle_clip_against_plane(
+                    &Vec3d {
+                        x: 0.0,
+                        y: 0.0,
+                        z: camera.near,
+                        ..Default::default()
+                    },
+                    &Vec3d {
+                        x: 0.0,
+                        y: 0.0,
+                        z: 1.0,
+                        ..Default::default()
+                    },
+                    &tri_viewed,
+                    &mut clipped_1,
+                    &mut clipped_2,
+                );
 
-                        //Scale/Normalize
+                for i in 0..n_clipped_triangles {
+                    let mut tri_projected: Triangle = Triangle::default();
+                    if i == 0 {
+                        // Project triangles
+                        tri_projected = clipped_1.clone();
                         tri_projected.points[0] =
-                            vector_div(&tri_projected.points[0], tri_projected.points[0].w);
+                            matrix_multiply_vector(&mat_projection, &clipped_1.points[0]);
                         tri_projected.points[1] =
-                            vector_div(&tri_projected.points[1], tri_projected.points[1].w);
+                            matrix_multiply_vector(&mat_projection, &clipped_1.points[1]);
                         tri_projected.points[2] =
-                            vector_div(&tri_projected.points[2], tri_projected.points[2].w);
-
-                        //X/Y are inverted so put them back
-                        tri_projected.points[0].x *= -1.0;
-                        tri_projected.points[1].x *= -1.0;
-                        tri_projected.points[2].x *= -1.0;
-                        tri_projected.points[0].y *= -1.0;
-                        tri_projected.points[1].y *= -1.0;
-                        tri_projected.points[2].y *= -1.0;
-
-                        //offset Vertices
-                        let v_offset_view: Vec3d = Vec3d {
-                            x: 1.0,
-                            y: 1.0,
-                            ..Default::default()
-                        };
+                            matrix_multiply_vector(&mat_projection, &clipped_1.points[2]);
+                    } else if i == 1 {
+                        // Project triangles
+                        tri_projected = clipped_2.clone();
                         tri_projected.points[0] =
-                            vector_add(&tri_projected.points[0], &v_offset_view);
+                            matrix_multiply_vector(&mat_projection, &clipped_2.points[0]);
                         tri_projected.points[1] =
-                            vector_add(&tri_projected.points[1], &v_offset_view);
+                            matrix_multiply_vector(&mat_projection, &clipped_2.points[1]);
                         tri_projected.points[2] =
-                            vector_add(&tri_projected.points[2], &v_offset_view);
-
-                        tri_projected.points[0].x *= 0.5 * (self.view_width as f32);
-                        tri_projected.points[0].y *= 0.5 * (self.view_height as f32);
-                        tri_projected.points[1].x *= 0.5 * (self.view_width as f32);
-                        tri_projected.points[1].y *= 0.5 * (self.view_height as f32);
-                        tri_projected.points[2].x *= 0.5 * (self.view_width as f32);
-                        tri_projected.points[2].y *= 0.5 * (self.view_height as f32);
-                        vec_triangles_to_raster.push(tri_projected.clone());
+                            matrix_multiply_vector(&mat_projection, &clipped_2.points[2]);
                     }
+
+                    tri_projected.texture_points[0].u /= tri_projected.points[0].w;
+                    tri_projected.texture_points[1].u /= tri_projected.points[1].w;
+                    tri_projected.texture_points[2].u /= tri_projected.points[2].w;
+
+                    tri_projected.texture_points[0].v /= tri_projected.points[0].w;
+                    tri_projected.texture_points[1].v /= tri_projected.points[1].w;
+                    tri_projected.texture_points[2].v /= tri_projected.points[2].w;
+
+                    tri_projected.texture_points[0].w = 1.0 / tri_projected.points[0].w;
+                    tri_projected.texture_points[1].w = 1.0 / tri_projected.points[1].w;
+                    tri_projected.texture_points[2].w = 1.0 / tri_projected.points[2].w;
+
+                    //Scale/Normalize
+                    tri_projected.points[0] =
+                        vector_div(&tri_projected.points[0], tri_projected.points[0].w);
+                    tri_projected.points[1] =
+                        vector_div(&tri_projected.points[1], tri_projected.points[1].w);
+                    tri_projected.points[2] =
+                        vector_div(&tri_projected.points[2], tri_projected.points[2].w);
+
+                    //Reverse the depth so nearer pixels have larger depth values. Unlike 1/w this
+                    //is also meaningful for orthographic projections, where w is always 1.
+                    tri_projected.points[0].z = 1.0 - tri_projected.points[0].z;
+                    tri_projected.points[1].z = 1.0 - tri_projected.points[1].z;
+                    tri_projected.points[2].z = 1.0 - tri_projected.points[2].z;
+
+                    //X/Y are inverted so put them back
+                    tri_projected.points[0].x *= -1.0;
+                    tri_projected.points[1].x *= -1.0;
+                    tri_projected.points[2].x *= -1.0;
+                    tri_projected.points[0].y *= -1.0;
+                    tri_projected.points[1].y *= -1.0;
+                    tri_projected.points[2].y *= -1.0;
+
+                    //offset Vertices
+                    let v_offset_view: Vec3d = Vec3d {
+                        x: 1.0,
+                        y: 1.0,
+                        ..Default::default()
+                    };
+                    tri_projected.points[0] = vector_add(&tri_projected.points[0], &v_offset_view);
+                    tri_projected.points[1] = vector_add(&tri_projected.points[1], &v_offset_view);
+                    tri_projected.points[2] = vector_add(&tri_projected.points[2], &v_offset_view);
+
+                    tri_projected.points[0].x *= 0.5 * (self.view_width as f32);
+                    tri_projected.points[0].y *= 0.5 * (self.view_height as f32);
+                    tri_projected.points[1].x *= 0.5 * (self.view_width as f32);
+                    tri_projected.points[1].y *= 0.5 * (self.view_height as f32);
+                    tri_projected.points[2].x *= 0.5 * (self.view_width as f32);
+                    tri_projected.points[2].y *= 0.5 * (self.view_height as f32);
+                    vec_triangles_to_raster.push(tri_projected.clone());
                 }
             }
-            for tri_to_raster in vec_triangles_to_raster {
-                let mut clipped_1: Triangle = Triangle::default();
-                let mut clipped_2: Triangle = Triangle::default();
+        }
+        for tri_to_raster in vec_triangles_to_raster {
+            let mut clipped_1: Triangle = Triangle::default();
+            let mut clipped_2: Triangle = Triangle::default();
+
+            let mut list_triangles: VecDeque<Triangle> = VecDeque::new();
 
-                let mut list_triangles: VecDeque<Triangle> = VecDeque::new();
-
-                list_triangles.push_back(tri_to_raster);
-                let mut n_new_triangles: usize = 1;
-
-                for p in 0..4 {
-                    let mut n_tris_to_add: u64 = 0;
-                    while n_new_triangles > 0 {
-                        // Take triangle from front of list
-                        let mut test: Triangle = list_triangles.front().unwrap().clone();
-                        list_triangles.pop_front();
-                        n_new_triangles -= 1;
-
-                        // Clip it against a plane.
-                        match p {
-                            0 => {
-                                n_tris_to_add = triangle_clip_against_plane(
-                                    &mut Vec3d {
-                                        x: 0.0,
-                                        y: 0.0,
-                                        z: 0.0,
-                                        ..Default::default()
-                                    },
-                                    &mut Vec3d {
-                                        x: 0.0,
-                                        y: 1.0,
-                                        z: 0.0,
-                                        ..Default::default()
-                                    },
-                                    &mut test,
-                                    &mut (clipped_1),
-                                    &mut (clipped_2),
-                                );
-                            }
-                            1 => {
-                                n_tris_to_add = triangle_clip_against_plane(
-                                    &mut Vec3d {
-                                        x: 0.0,
-                                        y: self.view_height as f32 - 1.0,
-                                        z: 0.0,
-                                        ..Default::default()
-                                    },
-                                    &mut Vec3d {
-                                        x: 0.0,
-                                        y: -1.0,
-                                        z: 0.0,
-                                        ..Default::default()
-                                    },
-                                    &mut test,
-                                    &mut (clipped_1),
-                                    &mut (clipped_2),
-                                );
-                            }
-                            2 => {
-                                n_tris_to_add = triangle_clip_against_plane(
-                                    &mut Vec3d {
-                                        x: 0.0,
-                                        y: 0.0,
-                                        z: 0.0,
-                                        ..Default::default()
-                                    },
-                                    &mut Vec3d {
-                                        x: 1.0,
-                                        y: 0.0,
-                                        z: 0.0,
-                                        ..Default::default()
-                                    },
-                                    &mut test,
-                                    &mut (clipped_1),
-                                    &mut (clipped_2),
-                                );
-                            }
-                            3 => {
-                                n_tris_to_add = triangle_clip_against_plane(
-                                    &mut Vec3d {
-                                        x: self.view_width as f32 - 1.0,
-                                        y: 0.0,
-                                        z: 0.0,
-                                        ..Default::default()
-                                    },
-                                    &mut Vec3d {
-                                        x: -1.0,
-                                        y: 0.0,
-                                        z: 0.0,
-                                        ..Default::default()
-                                    },
-                                    &mut test,
-                                    &mut (clipped_1),
-                                    &mut (clipped_2),
-                                );
-                            }
-                            _ => {}
+            list_triangles.push_back(tri_to_raster);
+            let mut n_new_triangles: usize = 1;
+
+            for p in 0..4 {
+                let mut n_tris_to_add: u64 = 0;
+                while n_new_triangles > 0 {
+                    // Take triangle from front of list
+                    let test: Triangle = list_triangles.front().unwrap().clone();
+                    list_triangles.pop_front();
+                    n_new_triangles -= 1;
+
+                    // Clip it against a plane.
+                    match p {
+                        0 => {
+                            n_tris_to_add = triangle_clip_against_plane(
+                                &Vec3d {
+                                    x: 0.0,
+                                    y: 0.0,
+                                    z: 0.0,
+                                    ..Default::default()
+                                },
+                                &Vec3d {
+                                    x: 0.0,
+                                    y: 1.0,
+                                    z: 0.0,
+                                    ..Default::default()
+                                },
+                                &test,
+                                &mut (clipped_1),
+                                &mut (clipped_2),
+                            );
+                        }
+                        1 => {
+                            n_tris_to_add = triangle_clip_against_plane(
+                                &Vec3d {
+                                    x: 0.0,
+                                    y: self.view_height as f32 - 1.0,
+                                    z: 0.0,
+                                    ..Default::default()
+                                },
+                                &Vec3d {
+                                    x: 0.0,
+                                    y: -1.0,
+                                    z: 0.0,
+                                    ..Default::default()
+                                },
+                                &test,
+                                &mut (clipped_1),
+                                &mut (clipped_2),
+                            );
+                        }
+                        2 => {
+                            n_tris_to_add = triangle_clip_against_plane(
+                                &Vec3d {
+                                    x: 0.0,
+                                    y: 0.0,
+                                    z: 0.0,
+                                    ..Default::default()
+                                },
+                                &Vec3d {
+                                    x: 1.0,
+                                    y: 0.0,
+                                    z: 0.0,
+                                    ..Default::default()
+                                },
+                                &test,
+                                &mut (clipped_1),
+                                &mut (clipped_2),
+                            );
                         }
+                        3 => {
+                            n_tris_to_add = triangle_clip_against_plane(
+                                &Vec3d {
+                                    x: self.view_width as f32 - 1.0,
+                                    y: 0.0,
+                                    z: 0.0,
+                                    ..Default::default()
+                                },
+                                &Vec3d {
+                                    x: -1.0,
+                                    y: 0.0,
+                                    z: 0.0,
+                                    ..Default::default()
+                                },
+                                &test,
+                                &mut (clipped_1),
+                                &mut (clipped_2),
+                            );
+                        }
+                        _ => {}
+                    }
 
-                        // Clipping may create more triangles, so add these to the queue for further clipping
-                        for w in 0..n_tris_to_add {
-                            if w == 0 {
-                                list_triangles.push_back(clipped_1.clone());
-                            } else if w == 1 {
-                                list_triangles.push_back(clipped_2.clone());
-                            }
+                    // Clipping may create more triangles, so add these to the queue for further clipping
+                    for w in 0..n_tris_to_add {
+                        if w == 0 {
+                            list_triangles.push_back(clipped_1.clone());
+                        } else if w == 1 {
+                            list_triangles.push_back(clipped_2.clone());
                         }
                     }
-                    n_new_triangles = list_triangles.len();
                 }
-                for t in list_triangles {
-                    draw_textured_triangle(
-                        t.points[0].x.round() as i64,
-                        t.points[0].y.round() as i64,
-                        t.texture_points[0].u,
-                        t.texture_points[0].v,
-                        t.texture_points[0].w,
-                        t.points[1].x.round() as i64,
-                        t.points[1].y.round() as i64,
-                        t.texture_points[1].u,
-                        t.texture_points[1].v,
-                        t.texture_points[1].w,
-                        t.points[2].x.round() as i64,
-                        t.points[2].y.round() as i64,
-                        t.texture_points[2].u,
-                        t.texture_points[2].v,
-                        t.texture_points[2].w,
-                        mesh.texture,
-                        framebuffer,
-                        &mut self.depth_buffer,
-                        &(self.view_width as i64),
-                    );
+                n_new_triangles = list_triangles.len();
+            }
+            screen_triangles.extend(list_triangles);
+        }
+        screen_triangles
+    }
+
+    /// Rasterises the triangles of 'batches' into the framebuffer and depth buffer, in the order
+    /// given by the batch and triangle indices in 'order'. With the 'parallel' feature the rows are
+    /// shared between threads, see 'rasterise_bands'.
+    fn rasterise(
+        &mut self,
+        batches: &[RasterBatch],
+        order: &[(usize, usize)],
+        framebuffer: &mut Framebuffer,
+    ) {
+        #[cfg(feature = "parallel")]
+        {
+            let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+            if threads > 1 {
+                self.rasterise_bands(batches, order, framebuffer, threads);
+                return;
+            }
+        }
+        self.rasterise_single_threaded(batches, order, framebuffer);
+    }
+
+    /// Rasterises the triangles of 'batches' in 'order' on the calling thread, see 'rasterise'.
+    fn rasterise_single_threaded(
+        &mut self,
+        batches: &[RasterBatch],
+        order: &[(usize, usize)],
+        framebuffer: &mut Framebuffer,
+    ) {
+        let mut target =
+            RasterTarget::full(framebuffer, &mut self.depth_buffer, self.view_width as i64);
+        for &(batch_index, tri_index) in order {
+            let batch = &batches[batch_index];
+            fill_triangle(
+                self.rasteriser_mode,
+                &batch.tris[tri_index],
+                batch.texture,
+                batch.sampler,
+                batch.shader.as_deref(),
+                batch.blend,
+                &mut target,
+            );
+        }
+    }
+
+    /// Rasterises the triangles of 'batches' into the framebuffer and depth buffer in 'order',
+    /// splitting the screen into bands of rows which are filled on 'threads' threads.
+    ///
+    /// Each band draws the triangles that overlap it in the same order as the single threaded
+    /// renderer, and every pixel is calculated the same way whichever band it falls in, so the
+    /// output is identical.
+    ///
+    /// The screen is split into whole rows rather than tiles because a band of rows is one
+    /// contiguous run of the framebuffer and depth buffer, so 'chunks_mut' hands each thread its
+    /// own slices without copying or unsafe code. The rasterisers already clip to the rows of a
+    /// 'RasterTarget', and the scanline rasteriser walks whole rows, so tiles would also need
+    /// column clipping in every rasteriser. Dealing the narrow bands out in turn spreads busy
+    /// parts of the screen over the threads about as well as tiles would.
+    #[cfg(feature = "parallel")]
+    fn rasterise_bands(
+        &mut self,
+        batches: &[RasterBatch],
+        order: &[(usize, usize)],
+        framebuffer: &mut Framebuffer,
+        threads: usize,
+    ) {
+        let width = self.view_width as i64;
+        let band_size = BAND_HEIGHT * self.view_width;
+        let mode = self.rasteriser_mode;
+        let format = framebuffer.format();
+
+        let bands: Vec<RasterTarget> = framebuffer
+            .get_framebuffer_mut()
+            .chunks_mut(band_size)
+            .zip(self.depth_buffer.chunks_mut(band_size))
+            .enumerate()
+            .map(|(band, (pixels, depth))| {
+                let first_row = (band * BAND_HEIGHT) as i64;
+                let rows = first_row..first_row + depth.len() as i64 / width;
+                RasterTarget::new(pixels, format, depth, width, rows)
+            })
+            .collect();
+        if bands.is_empty() {
+            return;
+        }
+
+        // Bin each triangle into every band its rows might touch, keeping the draw order.
+        let mut bins: Vec<Vec<(usize, usize)>> = vec![Vec::new(); bands.len()];
+        for &(batch_index, tri_index) in order {
+            let ys = batches[batch_index].tris[tri_index]
+                .points
+                .iter()
+                .map(|p| p.y);
+            let min_y = ys.clone().fold(f32::INFINITY, f32::min).floor().max(0.0) as usize;
+            let max_y = ys.fold(f32::NEG_INFINITY, f32::max).ceil().max(0.0) as usize;
+            let last_band = (max_y / BAND_HEIGHT).min(bins.len() - 1);
+            for bin in bins[(min_y / BAND_HEIGHT).min(last_band)..=last_band].iter_mut() {
+                bin.push((batch_index, tri_index));
+            }
+        }
+
+        // Deal the bands out to the threads in turn so each gets a share of the busy rows.
+        let mut jobs: Vec<Vec<(usize, RasterTarget)>> = (0..threads).map(|_| Vec::new()).collect();
+        for (band, target) in bands.into_iter().enumerate() {
+            jobs[band % threads].push((band, target));
+        }
+
+        let bins = &bins;
+        std::thread::scope(|scope| {
+            for job in jobs {
+                scope.spawn(move || {
+                    for (band, mut target) in job {
+                        for &(batch_index, tri_index) in bins[band].iter() {
+                            let batch = &batches[batch_index];
+                            fill_triangle(
+                                mode,
+                                &batch.tris[tri_index],
+                                batch.texture,
+                                batch.sampler,
+                                batch.shader.as_deref(),
+                                batch.blend,
+                                &mut target,
+                            );
+                        }
+                    }
+                });
+            }
+        });
+    }
+}
+
+/// Adds a copy of the texture of every mesh and material in 'meshes' converted to 'format' to
+/// 'converted', where the texture isn't already in 'format' and hasn't been converted to it
+/// before, so the rasterisers write texture colors straight into the framebuffer. Each texture
+/// has one entry holding its copies in every format. Entries for textures no longer in the scene
+/// are dropped.
+fn convert_textures(
+    converted: &mut Vec<(Arc<Texture>, Vec<Texture>)>,
+    meshes: &[Mesh],
+    format: PixelFormat,
+) {
+    // The entry holds one reference to the original texture and the scene holds the rest.
+    converted.retain(|(original, _)| Arc::strong_count(original) > 1);
+    for texture in meshes.iter().flat_map(|mesh| mesh.textures()) {
+        if texture.format == format {
+            continue;
+        }
+        let copies = match converted
+            .iter()
+            .position(|(original, _)| Arc::ptr_eq(original, texture))
+        {
+            Some(index) => &mut converted[index].1,
+            None => {
+                converted.push((texture.clone(), Vec::new()));
+                &mut converted.last_mut().unwrap().1
+            }
+        };
+        if copies.iter().all(|copy| copy.format != format) {
+            let mut copy = Texture::clone(texture);
+            copy.convert(format);
+            copies.push(copy);
+        }
+    }
+}
+
+/// Returns the copy of 'texture' in 'format' from 'converted', or 'texture' itself if it has no
+/// copy, see 'convert_textures'.
+fn converted_texture<'a>(
+    texture: &'a Texture,
+    format: PixelFormat,
+    converted: &'a [(Arc<Texture>, Vec<Texture>)],
+) -> &'a Texture {
+    converted
+        .iter()
+        .find(|(original, _)| std::ptr::eq(Arc::as_ptr(original), texture))
+        .and_then(|(_, copies)| copies.iter().find(|copy| copy.format == format))
+        .unwrap_or(texture)
+}
+
+/// The screen space triangles of one batch of a mesh, ready to be rasterised with 'texture',
+/// 'sampler' and 'shader' and combined with the framebuffer using 'blend'.
+struct RasterBatch<'a> {
+    tris: Vec<Triangle>,
+    texture: &'a Texture,
+    sampler: Sampler,
+    shader: Option<Arc<dyn FragmentShader>>,
+    blend: BlendMode,
+}
+
+/// A world space line or point queued by 'Renderer::draw_line_3d' or 'Renderer::draw_point_3d'.
+enum DebugPrimitive {
+    Line { a: Vec3d, b: Vec3d, color: u32 },
+    Point { point: Vec3d, size: u32, color: u32 },
+}
+
+/// Projects the view space 'point' onto the screen of a 'view_width' by 'view_height' renderer
+/// like the points of a triangle, with the reversed depth in z.
+fn project_point(
+    point: &Vec3d,
+    mat_projection: &Mat4x4,
+    view_width: usize,
+    view_height: usize,
+) -> Vec3d {
+    let projected = matrix_multiply_vector(mat_projection, point);
+    let projected = vector_div(&projected, projected.w);
+    Vec3d {
+        x: (1.0 - projected.x) * 0.5 * view_width as f32,
+        y: (1.0 - projected.y) * 0.5 * view_height as f32,
+        z: 1.0 - projected.z,
+        ..Default::default()
+    }
+}
+
+/// The fragment shader used by the debug render modes. The flat color of each triangle, or its
+/// face normal, is held in the first three attributes. 'nearest' and 'furthest' are the distances
+/// from 'camera' of the nearest and furthest points in the frame. Colors are packed in 'format',
+/// the format of the framebuffer.
+struct DebugShader {
+    mode: RenderMode,
+    format: PixelFormat,
+    camera: Camera,
+    nearest: f32,
+    furthest: f32,
+}
+
+impl FragmentShader for DebugShader {
+    fn shade(&self, fragment: &Fragment) -> Option<u32> {
+        let [r, g, b] = match self.mode {
+            RenderMode::Normals => {
+                let normal = fragment.normal.clone();
+                let normal = if vector_length(&normal) > 0.0 {
+                    vector_normalize(&normal)
+                } else {
+                    let [x, y, z, _] = fragment.attributes;
+                    Vec3d {
+                        x,
+                        y,
+                        z,
+                        ..Default::default()
+                    }
+                };
+                [normal.x, normal.y, normal.z].map(|axis| (axis + 1.0) * 0.5)
+            }
+            RenderMode::Depth => {
+                let distance = self.camera.linear_depth(fragment.depth);
+                let range = self.furthest - self.nearest;
+                // Every point of a flat frame is as near as the nearest, so is white.
+                if range > 0.0 {
+                    [1.0 - (distance - self.nearest) / range; 3]
+                } else {
+                    [1.0; 3]
                 }
             }
+            _ => [
+                fragment.attributes[0],
+                fragment.attributes[1],
+                fragment.attributes[2],
+            ],
+        };
+        let channel = |value: f32| (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+        Some(self.format.pack(channel(r), channel(g), channel(b), 0xff))
+    }
+}
+
+/// Returns the batch and triangle indices of every triangle in 'batches' in the order they should
+/// be drawn. Triangles with a transparent blend mode come after all the others, sorted
+/// back-to-front by their average depth so the triangles behind them are already drawn.
+fn draw_order(batches: &[RasterBatch]) -> Vec<(usize, usize)> {
+    let mut order: Vec<(usize, usize)> = Vec::new();
+    let mut transparent: Vec<(f32, (usize, usize))> = Vec::new();
+    for (batch_index, batch) in batches.iter().enumerate() {
+        for (tri_index, tri) in batch.tris.iter().enumerate() {
+            if batch.blend.is_transparent() {
+                let depth = tri.points.iter().map(|p| p.z).sum::<f32>() / 3.0;
+                transparent.push((depth, (batch_index, tri_index)));
+            } else {
+                order.push((batch_index, tri_index));
+            }
+        }
+    }
+    // Smaller depth values are further from the camera. The sort is stable, so triangles at the
+    // same depth keep their mesh order.
+    transparent.sort_by(|a, b| a.0.total_cmp(&b.0));
+    order.extend(transparent.into_iter().map(|(_, index)| index));
+    order
+}
+
+/// The number of rows in each band of the screen rasterised by a single thread.
+#[cfg(feature = "parallel")]
+const BAND_HEIGHT: usize = 16;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "parallel")]
+    use crate::geometry::Vec2d;
+
+    /// A 4x4 texture with a different color in every texel.
+    fn pattern_texture() -> Texture {
+        let format = PixelFormat::default();
+        Texture {
+            pixels: (0..16u8)
+                .map(|i| format.pack(i * 16, 255 - i * 8, i * 5, 0xff - i))
+                .collect(),
+            width: 4,
+            height: 4,
+            mipmaps: Vec::new(),
+            format,
+        }
+    }
+
+    /// A screen space triangle with its texture stretched over it.
+    #[cfg(feature = "parallel")]
+    fn screen_triangle(points: [(f32, f32, f32); 3], light: [f32; 3]) -> Triangle {
+        let uv = |u, v| Vec2d {
+            u,
+            v,
+            ..Default::default()
+        };
+        Triangle {
+            points: points.map(|(x, y, z)| Vec3d {
+                x,
+                y,
+                z,
+                ..Default::default()
+            }),
+            texture_points: [uv(0.0, 0.0), uv(1.0, 0.0), uv(0.5, 1.0)],
+            light: [light; 3],
+            ..Default::default()
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn banded_rasterising_matches_single_threaded() {
+        let (width, height) = (48, 60);
+        let texture = pattern_texture();
+        // Corners on, just above and just below the band edges at rows 16, 32 and 48, with the
+        // last band only 12 rows high.
+        let opaque = vec![
+            screen_triangle(
+                [(3.2, 15.5, 0.4), (44.7, 16.0, 0.6), (20.1, 47.9, 0.5)],
+                [1.0; 3],
+            ),
+            screen_triangle(
+                [(40.0, 1.0, 0.3), (46.5, 59.0, 0.7), (5.5, 32.0, 0.55)],
+                [0.8, 0.5, 0.3],
+            ),
+            screen_triangle(
+                [(-4.0, 31.99, 0.45), (52.0, 32.01, 0.45), (24.0, 70.0, 0.45)],
+                [1.0; 3],
+            ),
+        ];
+        let transparent = vec![
+            screen_triangle(
+                [(0.0, 0.0, 0.65), (47.0, 48.0, 0.65), (10.0, 60.0, 0.65)],
+                [1.0; 3],
+            ),
+            screen_triangle(
+                [(30.0, 15.9, 0.8), (12.0, 16.1, 0.8), (24.0, 48.5, 0.8)],
+                [0.5; 3],
+            ),
+        ];
+        let batch = |tris, blend| RasterBatch {
+            tris,
+            texture: &texture,
+            sampler: Sampler::default(),
+            shader: None,
+            blend,
+        };
+        let batches = vec![
+            batch(opaque, BlendMode::Opaque),
+            batch(transparent, BlendMode::Additive),
+        ];
+        let order = draw_order(&batches);
+
+        for mode in [RasteriserMode::Scanline, RasteriserMode::EdgeFunction] {
+            let mut renderer = Renderer::new(Vec::new(), Vec::new(), width, height, 0);
+            renderer.set_rasteriser_mode(mode);
+            let mut single = Framebuffer::new(width, height);
+            renderer.rasterise_single_threaded(&batches, &order, &mut single);
+            let single_depth = renderer.depth_buffer.clone();
+
+            renderer.depth_buffer.fill(0.0);
+            let mut banded = Framebuffer::new(width, height);
+            renderer.rasterise_bands(&batches, &order, &mut banded, 3);
+
+            assert!(single_depth.iter().filter(|&&depth| depth > 0.0).count() > width * 20);
+            assert!(banded.get_framebuffer() == single.get_framebuffer());
+            assert!(renderer.depth_buffer == single_depth);
+        }
+    }
+
+    #[test]
+    fn rendering_in_other_formats_leaves_the_scene_textures_unchanged() {
+        let mut pattern = pattern_texture();
+        pattern.convert(PixelFormat::Argb8888);
+        let texture = Arc::new(pattern.clone());
+        let obj = b"v -1 -1 1.5\nv 1 -1 1.5\nv 1 1 1.5\nv -1 1 1.5\n\
+                    vt 0 0\nvt 1 0\nvt 1 1\nvt 0 1\nf 4/4 3/3 2/2 1/1\n";
+        let mesh = Mesh::new(texture.clone(), obj);
+        let mut renderer = Renderer::new(vec![mesh], Vec::new(), 32, 32, 0);
+        let mut render = |format| {
+            let mut framebuffer = Framebuffer::with_format(32, 32, format);
+            renderer.render(0.0, &mut framebuffer);
+            framebuffer
+        };
+
+        let before = render(PixelFormat::Argb8888);
+        let drawn = before.get_framebuffer().iter().filter(|&&color| color != 0);
+        assert!(drawn.count() > 32 * 8);
+        for format in [PixelFormat::Gray8, PixelFormat::Rgb565] {
+            render(format);
+            let after = render(PixelFormat::Argb8888);
+            assert!(after.get_framebuffer() == before.get_framebuffer());
+        }
+
+        let mesh = &renderer.meshes[0].1;
+        assert!(Arc::ptr_eq(&mesh.texture, &texture));
+        assert!(texture.pixels == pattern.pixels);
+        assert_eq!(texture.format, PixelFormat::Argb8888);
+
+        // Each format is converted once and the copy reused by later frames.
+        let copies = |renderer: &Renderer| {
+            renderer
+                .converted_textures
+                .iter()
+                .flat_map(|(_, copies)| copies.iter())
+                .map(|copy| (copy.format, copy.pixels.as_ptr()))
+                .collect::<Vec<_>>()
+        };
+        let first = copies(&renderer);
+        assert_eq!(first.len(), 2);
+        renderer.render(
+            0.0,
+            &mut Framebuffer::with_format(32, 32, PixelFormat::Gray8),
+        );
+        assert_eq!(copies(&renderer), first);
+
+        // Copies of textures removed from the scene are dropped.
+        let handle = renderer.meshes[0].0;
+        renderer.remove(handle);
+        drop(texture);
+        renderer.render(
+            0.0,
+            &mut Framebuffer::with_format(32, 32, PixelFormat::Gray8),
+        );
+        assert!(renderer.converted_textures.is_empty());
+    }
+
+    /// A square facing the camera, 'half' units either side of its centre at 'z' in front of it.
+    fn facing_square(half: f32, z: f32) -> Mesh {
+        let obj = format!(
+            "v {0} {0} {2}\nv {1} {0} {2}\nv {1} {1} {2}\nv {0} {1} {2}\nf 4 3 2 1\n",
+            -half, half, z
+        );
+        Mesh::new(Arc::new(Texture::default()), obj.as_bytes())
+    }
+
+    #[test]
+    fn depth_mode_spans_the_nearest_to_the_furthest_point() {
+        let depth_frame = |meshes: Vec<Mesh>| {
+            let mut renderer = Renderer::new(meshes, Vec::new(), 32, 32, 0);
+            renderer.set_render_mode(RenderMode::Depth);
+            let mut framebuffer = Framebuffer::new(32, 32);
+            renderer.render(0.0, &mut framebuffer);
+            let format = framebuffer.format();
+            let gray = |x: u32, y: u32| {
+                let (r, g, b, _) = format.unpack(*framebuffer.get_color_at(&x, &y));
+                assert!(r == g && g == b);
+                r
+            };
+            [gray(16, 16), gray(1, 1)]
+        };
+
+        // A small square in front of a large one covering the whole screen.
+        let frame = depth_frame(vec![facing_square(0.3, 1.5), facing_square(8.0, 4.0)]);
+        assert_eq!(frame, [255, 0]);
+
+        // A single square is all at the nearest depth.
+        assert_eq!(depth_frame(vec![facing_square(8.0, 4.0)]), [255, 255]);
+
+        // Nothing drawn leaves the clear color.
+        assert_eq!(depth_frame(Vec::new()), [0, 0]);
+    }
+
+    #[test]
+    fn debug_draws_take_colors_in_the_default_format() {
+        let color = color_pack(0x20, 0x80, 0xe0, 0xff);
+        for format in [
+            PixelFormat::Argb8888,
+            PixelFormat::Abgr8888,
+            PixelFormat::Rgb565,
+        ] {
+            let mut renderer = Renderer::new(Vec::new(), Vec::new(), 32, 32, 0);
+            let ahead = |x, y| Vec3d {
+                x,
+                y,
+                z: 2.0,
+                ..Default::default()
+            };
+            renderer.draw_line_3d(ahead(-1.0, 0.5), ahead(1.0, 0.5), color);
+            renderer.draw_point_3d(ahead(0.0, -0.5), 3, color);
+            let mut framebuffer = Framebuffer::with_format(32, 32, format);
+            renderer.render(0.0, &mut framebuffer);
+
+            let expected = PixelFormat::default().convert(color, format);
+            let drawn: Vec<u32> = framebuffer
+                .get_framebuffer()
+                .iter()
+                .copied()
+                .filter(|&pixel| pixel != 0)
+                .collect();
+            assert!(drawn.len() > 9);
+            assert!(drawn.iter().all(|&pixel| pixel == expected));
         }
     }
 }
@@ -0,0 +1,75 @@
+use crate::{
+    geometry::{Vec2d, Vec3d},
+    texture::{Sampler, Texture},
+};
+
+/// The number of custom attributes carried by each point of a 'Triangle'.
+pub const ATTRIBUTE_COUNT: usize = 4;
+
+/// A single point of a triangle as seen by a 'VertexShader'. 'position' and 'normal' are in world
+/// space, after the mesh transform has been applied.
+#[derive(Debug, Clone)]
+pub struct Vertex {
+    pub position: Vec3d,
+    pub normal: Vec3d,
+    pub texture_point: Vec2d,
+    pub attributes: [f32; ATTRIBUTE_COUNT],
+}
+
+/// The values interpolated across a triangle for a single pixel, as seen by a 'FragmentShader'.
+/// 'depth' is the value stored in the depth buffer, where larger values are nearer the camera.
+/// 'normal' is the world space vertex normal, which is not normalised after interpolation and is
+/// zero if the triangle has no vertex normals. 'texture' is read through 'sampler', and 'lod' is
+/// the mip level of detail estimated for the pixel, see 'Sampler::sample_lod'. Colors are laid
+/// out in the 'PixelFormat' of 'texture', both when sampled and when returned by the shader,
+/// which the renderer converts textures to match the framebuffer before drawing them.
+pub struct Fragment<'a> {
+    pub x: u32,
+    pub y: u32,
+    pub u: f32,
+    pub v: f32,
+    pub depth: f32,
+    pub normal: Vec3d,
+    pub world_position: Vec3d,
+    pub light: [f32; 3],
+    pub attributes: [f32; ATTRIBUTE_COUNT],
+    pub texture: &'a Texture,
+    pub sampler: Sampler,
+    pub lod: f32,
+}
+
+impl Fragment<'_> {
+    /// Returns the texture color at the fragment's texture coordinates, read through the
+    /// fragment's sampler at the fragment's level of detail.
+    pub fn sample(&self) -> u32 {
+        self.sampler
+            .sample_lod(self.texture, self.u, self.v, self.lod)
+    }
+}
+
+/// A hook run on every point of every triangle before it is lit, culled and projected. It may
+/// move the point, bend its normal or fill in custom attributes for the 'FragmentShader'.
+pub trait VertexShader: Send + Sync {
+    /// Modifies 'vertex' in place.
+    fn shade(&self, vertex: &mut Vertex);
+}
+
+/// A program run on every pixel of a triangle that passes the depth test, in place of the
+/// default texture sampling.
+pub trait FragmentShader: Send + Sync {
+    /// Returns the color of 'fragment', or 'None' to discard the pixel and leave the framebuffer
+    /// and depth buffer untouched.
+    fn shade(&self, fragment: &Fragment) -> Option<u32>;
+}
+
+/// The fragment shader matching the renderer's default behaviour, sampling the texture and
+/// multiplying it by the light level. Useful as a starting point for other shaders.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TexturedShader;
+
+impl FragmentShader for TexturedShader {
+    fn shade(&self, fragment: &Fragment) -> Option<u32> {
+        let format = fragment.texture.format;
+        Some(format.modulate(fragment.sample(), &fragment.light))
+    }
+}
@@ -1,17 +1,360 @@
+use crate::color::PixelFormat;
+
 /// A Texture object holding the width and height of a texture as well as the pixel data for the texture.
+/// 'mipmaps' holds the smaller levels of the mip chain built by 'generate_mipmaps', and is empty
+/// if the texture has no mipmaps. The pixels are laid out in 'format', see 'convert'.
 #[derive(Default, Debug, Clone)]
 pub struct Texture {
     pub pixels: Vec<u32>,
     pub width: u32,
     pub height: u32,
+    pub mipmaps: Vec<Texture>,
+    pub format: PixelFormat,
 }
 
 impl Texture {
-    /// Returns the color of the pixel in the texture object at 'x_coord', 'y_coord'.
+    /// Returns the color of the pixel in the texture object at 'x_coord', 'y_coord'. Coordinates
+    /// outside 0 to 1 are clamped to the edge of the texture.
     pub fn get_color_at_normalized_coord(&self, x_coord: &f32, y_coord: &f32) -> &u32 {
+        let x_coord = x_coord.clamp(0.0, 1.0);
+        let y_coord = y_coord.clamp(0.0, 1.0);
         let texture_coord_x = (x_coord * ((self.width - 1) as f32)).round() as u32;
         let texture_coord_y =
             (self.height - 1) - (y_coord * ((self.height - 1) as f32)).round() as u32;
         &self.pixels[(texture_coord_x + (texture_coord_y * (self.width))) as usize]
     }
+
+    /// Builds the mip chain of the texture, replacing any existing mipmaps. Each level is a box
+    /// filtered copy of the level before at half its width and height, down to a single pixel.
+    pub fn generate_mipmaps(&mut self) {
+        self.mipmaps.clear();
+        while let Some(level) = texture_downsample(self.mipmaps.last().unwrap_or(self)) {
+            self.mipmaps.push(level);
+        }
+    }
+
+    /// Converts the pixels of the texture and its mipmaps to 'format'. The renderer converts the
+    /// textures of its meshes to the format of the framebuffer once, so it can write their pixels
+    /// without converting each one. Note that converting to a format without alpha makes the
+    /// texture fully opaque.
+    pub fn convert(&mut self, format: PixelFormat) {
+        for pixel in self.pixels.iter_mut() {
+            *pixel = self.format.convert(*pixel, format);
+        }
+        for level in self.mipmaps.iter_mut() {
+            level.convert(format);
+        }
+        self.format = format;
+    }
+
+    /// Returns mip level 'level' of the texture, where level 0 is the texture itself.
+    pub fn mip_level(&self, level: usize) -> &Texture {
+        match level {
+            0 => self,
+            _ => &self.mipmaps[level - 1],
+        }
+    }
+}
+
+/// Returns a copy of 'texture' at half its width and height, with each pixel the average of the
+/// pixels it covers, or 'None' if the texture is a single pixel already.
+fn texture_downsample(texture: &Texture) -> Option<Texture> {
+    let (width, height) = (texture.width as usize, texture.height as usize);
+    let format = texture.format;
+    if width * height <= 1 {
+        return None;
+    }
+    let (new_width, new_height) = ((width / 2).max(1), (height / 2).max(1));
+    let mut pixels = Vec::with_capacity(new_width * new_height);
+    for y in 0..new_height {
+        for x in 0..new_width {
+            let mut sum = [0u32; 4];
+            let mut count = 0;
+            for source_y in y * height / new_height..(y + 1) * height / new_height {
+                for source_x in x * width / new_width..(x + 1) * width / new_width {
+                    let (r, g, b, a) = format.unpack(texture.pixels[source_y * width + source_x]);
+                    for (total, channel) in sum.iter_mut().zip([r, g, b, a]) {
+                        *total += channel as u32;
+                    }
+                    count += 1;
+                }
+            }
+            let [r, g, b, a] = sum.map(|total| ((total + count / 2) / count) as u8);
+            pixels.push(format.pack(r, g, b, a));
+        }
+    }
+    Some(Texture {
+        pixels,
+        width: new_width as u32,
+        height: new_height as u32,
+        mipmaps: Vec::new(),
+        format,
+    })
+}
+
+/// How a 'Sampler' picks the color between the pixels of a texture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FilterMode {
+    /// Use the color of the nearest pixel, with 0 and 1 on the centres of the edge pixels rather
+    /// than their outer edges, as 'Texture::get_color_at_normalized_coord' reads. Coordinates
+    /// outside 0 to 1 are brought back into it by the wrap modes first.
+    #[default]
+    NearestCentred,
+    /// Use the color of the nearest pixel, giving sharp blocky pixels when magnified.
+    Nearest,
+    /// Blend the colors of the four nearest pixels by distance, giving smooth gradients when
+    /// magnified.
+    Bilinear,
+}
+
+/// How a 'Sampler' treats texture coordinates outside 0 to 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WrapMode {
+    /// Tile the texture, so 1.25 samples the same place as 0.25.
+    Repeat,
+    /// Stretch the pixels along the edge of the texture outwards.
+    #[default]
+    ClampToEdge,
+    /// Tile the texture, flipping every other tile so the edges line up.
+    MirroredRepeat,
+}
+
+/// How a 'Sampler' uses the mipmaps of a texture drawn smaller than its full size. Textures
+/// without mipmaps are always read at their full size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MipmapMode {
+    /// Ignore the mipmaps and always read the full size texture.
+    #[default]
+    None,
+    /// Read the mip level nearest the size the texture is drawn at.
+    Nearest,
+    /// Blend the two mip levels either side of the size the texture is drawn at. Combined with
+    /// bilinear filtering this is trilinear filtering.
+    Linear,
+}
+
+/// The filtering and wrapping used to read colors from a 'Texture'. 'wrap_u' applies across the
+/// texture and 'wrap_v' applies up it. 'lod_bias' is added to the mip level picked for each pixel,
+/// so positive values give blurrier, smaller levels and negative values sharper, larger ones.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Sampler {
+    pub filter: FilterMode,
+    pub wrap_u: WrapMode,
+    pub wrap_v: WrapMode,
+    pub mipmap: MipmapMode,
+    pub lod_bias: f32,
+}
+
+impl Sampler {
+    /// Creates a new nearest pixel `Sampler` using 'wrap' in both directions.
+    pub fn nearest(wrap: WrapMode) -> Sampler {
+        Sampler {
+            filter: FilterMode::Nearest,
+            wrap_u: wrap,
+            wrap_v: wrap,
+            ..Default::default()
+        }
+    }
+
+    /// Creates a new bilinear `Sampler` using 'wrap' in both directions.
+    pub fn bilinear(wrap: WrapMode) -> Sampler {
+        Sampler {
+            filter: FilterMode::Bilinear,
+            wrap_u: wrap,
+            wrap_v: wrap,
+            ..Default::default()
+        }
+    }
+
+    /// Creates a new trilinear `Sampler` using 'wrap' in both directions, which blends the
+    /// bilinear samples of the two nearest mip levels.
+    pub fn trilinear(wrap: WrapMode) -> Sampler {
+        Sampler {
+            mipmap: MipmapMode::Linear,
+            ..Sampler::bilinear(wrap)
+        }
+    }
+
+    /// Returns true if the sampler reads the mipmaps of 'texture', so the rasteriser needs to work
+    /// out the mip level of each pixel.
+    pub fn uses_mipmaps(&self, texture: &Texture) -> bool {
+        self.mipmap != MipmapMode::None && !texture.mipmaps.is_empty()
+    }
+
+    /// Returns the color of 'texture' at the texture coordinates 'u', 'v' as if it were drawn at
+    /// its full size, see 'sample_lod'.
+    pub fn sample(&self, texture: &Texture, u: f32, v: f32) -> u32 {
+        self.sample_lod(texture, u, v, 0.0)
+    }
+
+    /// Returns the color of 'texture' at the texture coordinates 'u', 'v', where 0, 0 is the
+    /// bottom left corner and 1, 1 is the top right corner. 'lod' is the level of detail the
+    /// texture is drawn at, the base 2 logarithm of the number of texture pixels covered by each
+    /// screen pixel, which picks the mip level to read. Empty textures are transparent black.
+    pub fn sample_lod(&self, texture: &Texture, u: f32, v: f32, lod: f32) -> u32 {
+        let lod = lod + self.lod_bias;
+        // Textures drawn at or above their full size are read from the full size level.
+        if !self.uses_mipmaps(texture) || lod.is_nan() || lod <= 0.0 {
+            return self.sample_level(texture, u, v);
+        }
+        let lod = lod.min(texture.mipmaps.len() as f32);
+        match self.mipmap {
+            MipmapMode::Linear if lod.fract() > 0.0 => {
+                let level = lod.floor() as usize;
+                color_lerp(
+                    texture.format,
+                    self.sample_level(texture.mip_level(level), u, v),
+                    self.sample_level(texture.mip_level(level + 1), u, v),
+                    lod.fract(),
+                )
+            }
+            _ => self.sample_level(texture.mip_level(lod.round() as usize), u, v),
+        }
+    }
+
+    /// Returns the color of a single mip level at the texture coordinates 'u', 'v'.
+    fn sample_level(&self, texture: &Texture, u: f32, v: f32) -> u32 {
+        let (width, height) = (texture.width as i64, texture.height as i64);
+        if width == 0 || height == 0 {
+            return 0;
+        }
+        // Pixel coordinates measured from the bottom left corner, so pixel centres lie on halves.
+        let x = u * width as f32;
+        let y = v * height as f32;
+        // The color of pixel column 'i' and row 'j', counting rows up from the bottom.
+        let texel = |i: i64, j: i64| -> u32 {
+            let i = wrap_index(i, width, self.wrap_u);
+            let j = height - 1 - wrap_index(j, height, self.wrap_v);
+            texture.pixels[(j * width + i) as usize]
+        };
+        match self.filter {
+            FilterMode::NearestCentred => *texture.get_color_at_normalized_coord(
+                &wrap_coord(u, self.wrap_u),
+                &wrap_coord(v, self.wrap_v),
+            ),
+            FilterMode::Nearest => texel(x.floor() as i64, y.floor() as i64),
+            FilterMode::Bilinear => {
+                let (x, y) = (x - 0.5, y - 0.5);
+                let (i, j) = (x.floor() as i64, y.floor() as i64);
+                let (fx, fy) = (x - x.floor(), y - y.floor());
+                let bottom = color_lerp(texture.format, texel(i, j), texel(i + 1, j), fx);
+                let top = color_lerp(texture.format, texel(i, j + 1), texel(i + 1, j + 1), fx);
+                color_lerp(texture.format, bottom, top, fy)
+            }
+        }
+    }
+}
+
+/// Map the texture coordinate 'c' into 0 to 1 using 'wrap'.
+fn wrap_coord(c: f32, wrap: WrapMode) -> f32 {
+    match wrap {
+        WrapMode::Repeat => c - c.floor(),
+        WrapMode::ClampToEdge => c.clamp(0.0, 1.0),
+        WrapMode::MirroredRepeat => {
+            let c = c.rem_euclid(2.0);
+            if c <= 1.0 {
+                c
+            } else {
+                2.0 - c
+            }
+        }
+    }
+}
+
+/// Map the pixel index 'i' into a row or column of 'size' pixels using 'wrap'.
+fn wrap_index(i: i64, size: i64, wrap: WrapMode) -> i64 {
+    match wrap {
+        WrapMode::Repeat => i.rem_euclid(size),
+        WrapMode::ClampToEdge => i.clamp(0, size - 1),
+        WrapMode::MirroredRepeat => {
+            let i = i.rem_euclid(size * 2);
+            if i < size {
+                i
+            } else {
+                size * 2 - 1 - i
+            }
+        }
+    }
+}
+
+/// Blend every channel of the colors 'a' and 'b' in 'format', moving a fraction 't' of the way
+/// from 'a' to 'b'.
+fn color_lerp(format: PixelFormat, a: u32, b: u32, t: f32) -> u32 {
+    if a == b {
+        return a;
+    }
+    let (ar, ag, ab, aa) = format.unpack(a);
+    let (br, bg, bb, ba) = format.unpack(b);
+    let lerp = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t).round() as u8;
+    format.pack(lerp(ar, br), lerp(ag, bg), lerp(ab, bb), lerp(aa, ba))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 4 by 2 texture whose pixels hold their own index, top row first.
+    fn numbered_texture() -> Texture {
+        Texture {
+            pixels: (0..8).collect(),
+            width: 4,
+            height: 2,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn default_sampler_reads_like_get_color_at_normalized_coord() {
+        let texture = numbered_texture();
+        let sampler = Sampler::default();
+        for u in [-0.5, 0.0, 0.1, 0.17, 0.5, 0.83, 0.9, 1.0, 1.5] {
+            for v in [-0.2, 0.0, 0.24, 0.26, 0.5, 0.74, 0.76, 1.0, 1.2] {
+                assert_eq!(
+                    sampler.sample(&texture, u, v),
+                    *texture.get_color_at_normalized_coord(&u, &v),
+                    "u {} v {}",
+                    u,
+                    v
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn nearest_sampler_wrap_modes() {
+        let texture = numbered_texture();
+        // Columns 0 to 3 are centred on u of 0.125, 0.375, 0.625 and 0.875, and the bottom row
+        // holds pixels 4 to 7.
+        let sample = |wrap, u| Sampler::nearest(wrap).sample(&texture, u, 0.25);
+        assert_eq!(sample(WrapMode::Repeat, 1.125), 4);
+        assert_eq!(sample(WrapMode::Repeat, -0.125), 7);
+        assert_eq!(sample(WrapMode::ClampToEdge, 1.125), 7);
+        assert_eq!(sample(WrapMode::ClampToEdge, -0.125), 4);
+        assert_eq!(sample(WrapMode::MirroredRepeat, 1.125), 7);
+        assert_eq!(sample(WrapMode::MirroredRepeat, -0.125), 4);
+    }
+
+    #[test]
+    fn nearest_centred_sampler_wrap_modes() {
+        let texture = numbered_texture();
+        // Columns 0 to 3 are centred on u of 0, 1/3, 2/3 and 1, and the rows on v of 1 and 0.
+        let sample = |wrap, u, v| {
+            let sampler = Sampler {
+                wrap_u: wrap,
+                wrap_v: wrap,
+                ..Default::default()
+            };
+            sampler.sample(&texture, u, v)
+        };
+        assert_eq!(sample(WrapMode::Repeat, 1.25, 0.0), 5);
+        assert_eq!(sample(WrapMode::Repeat, -0.25, 0.0), 6);
+        assert_eq!(sample(WrapMode::Repeat, 0.5, 1.2), 6);
+        assert_eq!(sample(WrapMode::Repeat, 2.0, -0.9), 4);
+        assert_eq!(sample(WrapMode::MirroredRepeat, 1.25, 0.0), 6);
+        assert_eq!(sample(WrapMode::MirroredRepeat, -0.25, 0.0), 5);
+        assert_eq!(sample(WrapMode::MirroredRepeat, 0.5, 1.2), 2);
+        assert_eq!(sample(WrapMode::MirroredRepeat, 2.0, -0.9), 0);
+        assert_eq!(sample(WrapMode::ClampToEdge, 1.25, 1.2), 3);
+        assert_eq!(sample(WrapMode::ClampToEdge, -0.25, -0.9), 4);
+    }
 }
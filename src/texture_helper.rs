@@ -1,34 +1,722 @@
 use embedded_graphics::pixelcolor::Rgb888;
 use embedded_graphics::prelude::*;
-use tinybmp::Bmp;
+use tinybmp::{Bmp, Bpp, ParseError, RawBmp, RawPixel};
 
-use crate::texture::Texture;
+#[cfg(feature = "png")]
+use crate::png::png_decode;
+use crate::{color::color_pack, texture::Texture};
 
-/// Creates a new Texture object from a BMP file.
+/// The error returned when an image file can not be loaded into a 'Texture'.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TextureLoadError {
+    /// The data does not start with the signature of the expected image format.
+    InvalidSignature,
+    /// The data ends part way through the image.
+    UnexpectedEnd,
+    /// The image uses a 'feature' of its format which is not supported.
+    Unsupported { feature: String },
+    /// The image data is malformed, 'reason' describes the first problem found.
+    Corrupt { reason: String },
+}
+
+impl std::fmt::Display for TextureLoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TextureLoadError::InvalidSignature => write!(f, "unrecognised image signature"),
+            TextureLoadError::UnexpectedEnd => write!(f, "image data ends unexpectedly"),
+            TextureLoadError::Unsupported { feature } => {
+                write!(f, "unsupported image feature: {}", feature)
+            }
+            TextureLoadError::Corrupt { reason } => write!(f, "corrupt image: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for TextureLoadError {}
+
+/// Creates a new Texture object from a BMP file. 32 bit BMP files keep the alpha channel of every
+/// pixel, all other formats are fully opaque. Like every loader here the texture is in the default
+/// 'PixelFormat', see 'Texture::convert'.
+///
+/// Panics if the BMP file can not be decoded, see 'try_get_texture_from_bmp' for a fallible
+/// version.
 pub fn get_texture_from_bmp(bmp_bytes: &[u8]) -> Texture {
+    try_get_texture_from_bmp(bmp_bytes).unwrap()
+}
+
+/// Creates a new Texture object from a BMP file, returning a 'TextureLoadError' if it can not be
+/// decoded.
+pub fn try_get_texture_from_bmp(bmp_bytes: &[u8]) -> Result<Texture, TextureLoadError> {
     let mut texture: Texture;
-    let bmp = Bmp::<Rgb888>::from_slice(bmp_bytes).unwrap();
+    let bmp = Bmp::<Rgb888>::from_slice(bmp_bytes).map_err(bmp_error)?;
+    let alpha = bmp_alpha(bmp.as_raw());
     texture = Texture {
         width: 0,
         height: 0,
         pixels: Vec::new(),
+        ..Default::default()
     };
+    let width = bmp.as_raw().header().image_size.width as usize;
     let mut max_x = 0;
     let mut max_y = 0;
     for Pixel(position, color) in bmp.pixels() {
-        let (r, g, b) = (color.r() as u32, color.g() as u32, color.b() as u32);
-        #[cfg(feature = "web")]
-        {
-            texture.pixels.push((0xff) << 24 | (b << 16) | (g << 8) | r); //ABGR little endian going from WASM to JS
-        }
-        #[cfg(not(feature = "web"))]
-        {
-            texture.pixels.push((0xff) << 24 | (r << 16) | (g << 8) | b); //ARGB
-        }
+        let a = alpha.as_ref().map_or(0xff, |alpha| {
+            alpha[position.y as usize * width + position.x as usize]
+        });
+        texture
+            .pixels
+            .push(color_pack(color.r(), color.g(), color.b(), a));
         max_x = max_x.max(position.x);
         max_y = max_y.max(position.y);
     }
     texture.height = (max_y + 1) as u32;
     texture.width = (max_x + 1) as u32;
-    texture
+    Ok(texture)
+}
+
+/// Creates a new Texture object from a PNG file.
+///
+/// Panics if the PNG file can not be decoded, see 'try_get_texture_from_png' for a fallible
+/// version.
+#[cfg(feature = "png")]
+pub fn get_texture_from_png(png_bytes: &[u8]) -> Texture {
+    try_get_texture_from_png(png_bytes).unwrap()
+}
+
+/// Creates a new Texture object from a PNG file, returning a 'TextureLoadError' if it can not be
+/// decoded. Grayscale, rgb and palette images of every bit depth are supported, with or without
+/// an alpha channel or 'tRNS' transparency, and interlaced images are deinterlaced.
+#[cfg(feature = "png")]
+pub fn try_get_texture_from_png(png_bytes: &[u8]) -> Result<Texture, TextureLoadError> {
+    let (width, height, pixels) = png_decode(png_bytes)?;
+    Ok(texture_from_rgba(width as usize, height as usize, &pixels))
+}
+
+/// Creates a new Texture object from a TGA file.
+///
+/// Panics if the TGA file can not be decoded, see 'try_get_texture_from_tga' for a fallible
+/// version.
+pub fn get_texture_from_tga(tga_bytes: &[u8]) -> Texture {
+    try_get_texture_from_tga(tga_bytes).unwrap()
+}
+
+/// Creates a new Texture object from a TGA file, returning a 'TextureLoadError' if it can not be
+/// decoded. Color mapped, true color and grayscale images are supported, both uncompressed and
+/// run length encoded, in any of the four pixel orders.
+pub fn try_get_texture_from_tga(tga_bytes: &[u8]) -> Result<Texture, TextureLoadError> {
+    let header = tga_bytes.get(..18).ok_or(TextureLoadError::UnexpectedEnd)?;
+    let u16_at = |i: usize| u16::from_le_bytes([header[i], header[i + 1]]) as usize;
+    let (color_map_type, image_type) = (header[1], header[2]);
+    let (map_first, map_length, map_depth) = (u16_at(3), u16_at(5), header[7]);
+    let (width, height, depth, descriptor) = (u16_at(12), u16_at(14), header[16], header[17]);
+    if color_map_type > 1 || !matches!(image_type, 1..=3 | 9..=11) {
+        return Err(TextureLoadError::InvalidSignature);
+    }
+    if width == 0 || height == 0 {
+        return Err(no_pixels());
+    }
+    let alpha_bits = descriptor & 0x0f;
+    let valid_depth = match image_type & 3 {
+        1 => color_map_type == 1 && matches!(depth, 8 | 16),
+        2 => matches!(depth, 15 | 16 | 24 | 32),
+        _ => matches!(depth, 8 | 16),
+    };
+    if !valid_depth || (color_map_type == 1 && !matches!(map_depth, 15 | 16 | 24 | 32)) {
+        return Err(TextureLoadError::Unsupported {
+            feature: format!("image type {} at {} bits per pixel", image_type, depth),
+        });
+    }
+
+    let mut position = 18 + header[0] as usize;
+    let mut palette: Vec<[u8; 4]> = Vec::new();
+    if color_map_type == 1 {
+        let entry_size = (map_depth as usize).div_ceil(8);
+        let entries = tga_bytes
+            .get(position..position + map_length * entry_size)
+            .ok_or(TextureLoadError::UnexpectedEnd)?;
+        palette = entries
+            .chunks(entry_size)
+            .map(|entry| tga_color(entry, map_depth, alpha_bits))
+            .collect();
+        position += entries.len();
+    }
+    let read_pixel = |bytes: &[u8]| -> Result<[u8; 4], TextureLoadError> {
+        match image_type & 3 {
+            1 => {
+                let index = if depth == 8 {
+                    bytes[0] as usize
+                } else {
+                    u16::from_le_bytes([bytes[0], bytes[1]]) as usize
+                };
+                index
+                    .checked_sub(map_first)
+                    .and_then(|i| palette.get(i))
+                    .copied()
+                    .ok_or_else(|| TextureLoadError::Corrupt {
+                        reason: "color map index out of range".to_string(),
+                    })
+            }
+            2 => Ok(tga_color(bytes, depth, alpha_bits)),
+            _ => Ok([
+                bytes[0],
+                bytes[0],
+                bytes[0],
+                if depth == 16 { bytes[1] } else { 0xff },
+            ]),
+        }
+    };
+
+    let pixel_size = (depth as usize).div_ceil(8);
+    let data = &tga_bytes[position.min(tga_bytes.len())..];
+    // A run length packet of two bytes or more can hold at most 128 pixels, so sizes the data
+    // can't hold are rejected before decoding.
+    let count = width * height;
+    let limit = if image_type < 9 {
+        data.len() / pixel_size
+    } else {
+        data.len() / 2 * 128
+    };
+    if count > limit {
+        return Err(TextureLoadError::UnexpectedEnd);
+    }
+    let mut pixels: Vec<[u8; 4]> = Vec::new();
+    let mut position = 0;
+    // Read the next 'length' bytes of the image data.
+    let mut next = |length: usize| -> Result<&[u8], TextureLoadError> {
+        let bytes = data
+            .get(position..position + length)
+            .ok_or(TextureLoadError::UnexpectedEnd)?;
+        position += length;
+        Ok(bytes)
+    };
+    while pixels.len() < count {
+        if image_type < 9 {
+            pixels.push(read_pixel(next(pixel_size)?)?);
+            continue;
+        }
+        // Run length packets repeat one pixel, raw packets hold every pixel, and either may run
+        // past the end of a row.
+        let packet = next(1)?[0];
+        let length = ((packet & 0x7f) as usize + 1).min(count - pixels.len());
+        if packet & 0x80 != 0 {
+            let pixel = read_pixel(next(pixel_size)?)?;
+            pixels.extend(std::iter::repeat_n(pixel, length));
+        } else {
+            for _ in 0..length {
+                pixels.push(read_pixel(next(pixel_size)?)?);
+            }
+        }
+    }
+
+    // Images are stored bottom-to-top and left-to-right unless the descriptor says otherwise.
+    if descriptor & 0x20 == 0 {
+        let rows: Vec<&[[u8; 4]]> = pixels.chunks(width).rev().collect();
+        pixels = rows.concat();
+    }
+    if descriptor & 0x10 != 0 {
+        pixels.chunks_mut(width).for_each(|row| row.reverse());
+    }
+    Ok(texture_from_rgba(width, height, &pixels))
+}
+
+/// Creates a new Texture object from a PPM or PGM file.
+///
+/// Panics if the PPM or PGM file can not be decoded, see 'try_get_texture_from_ppm' for a fallible
+/// version.
+pub fn get_texture_from_ppm(ppm_bytes: &[u8]) -> Texture {
+    try_get_texture_from_ppm(ppm_bytes).unwrap()
+}
+
+/// Creates a new Texture object from a binary or plain text PPM or PGM file, returning a
+/// 'TextureLoadError' if it can not be decoded.
+pub fn try_get_texture_from_ppm(ppm_bytes: &[u8]) -> Result<Texture, TextureLoadError> {
+    let (channels, binary) = match ppm_bytes.get(..2) {
+        Some(b"P2") => (1, false),
+        Some(b"P3") => (3, false),
+        Some(b"P5") => (1, true),
+        Some(b"P6") => (3, true),
+        Some([b'P', kind @ (b'1' | b'4' | b'7')]) => {
+            return Err(TextureLoadError::Unsupported {
+                feature: format!("netpbm format 'P{}'", *kind as char),
+            })
+        }
+        Some(_) => return Err(TextureLoadError::InvalidSignature),
+        None => return Err(TextureLoadError::UnexpectedEnd),
+    };
+    let mut position = 2;
+    let width = ppm_number(ppm_bytes, &mut position)?;
+    let height = ppm_number(ppm_bytes, &mut position)?;
+    let max_value = ppm_number(ppm_bytes, &mut position)?;
+    if width == 0 || height == 0 {
+        return Err(no_pixels());
+    }
+    if max_value == 0 || max_value > 65535 {
+        return Err(TextureLoadError::Corrupt {
+            reason: format!("invalid maximum value {}", max_value),
+        });
+    }
+    let count = width
+        .checked_mul(height)
+        .and_then(|count| count.checked_mul(channels))
+        .ok_or(TextureLoadError::UnexpectedEnd)?;
+
+    let samples: Vec<usize> = if binary {
+        // A single whitespace character separates the header from the samples.
+        let sample_size = if max_value > 255 { 2 } else { 1 };
+        let data = count
+            .checked_mul(sample_size)
+            .and_then(|size| ppm_bytes.get(position + 1..position + 1 + size))
+            .ok_or(TextureLoadError::UnexpectedEnd)?;
+        data.chunks(sample_size)
+            .map(|sample| {
+                sample
+                    .iter()
+                    .fold(0, |value, &byte| value << 8 | byte as usize)
+            })
+            .collect()
+    } else {
+        let mut samples = Vec::new();
+        for _ in 0..count {
+            samples.push(ppm_number(ppm_bytes, &mut position)?);
+        }
+        samples
+    };
+    if samples.iter().any(|&sample| sample > max_value) {
+        return Err(TextureLoadError::Corrupt {
+            reason: "sample larger than the maximum value".to_string(),
+        });
+    }
+
+    let scale = |sample: usize| (sample * 255 / max_value) as u8;
+    let pixels: Vec<[u8; 4]> = samples
+        .chunks(channels)
+        .map(|pixel| match pixel {
+            [gray] => [scale(*gray), scale(*gray), scale(*gray), 0xff],
+            _ => [scale(pixel[0]), scale(pixel[1]), scale(pixel[2]), 0xff],
+        })
+        .collect();
+    Ok(texture_from_rgba(width, height, &pixels))
+}
+
+/// Creates a new Texture object from a QOI file.
+///
+/// Panics if the QOI file can not be decoded, see 'try_get_texture_from_qoi' for a fallible
+/// version.
+pub fn get_texture_from_qoi(qoi_bytes: &[u8]) -> Texture {
+    try_get_texture_from_qoi(qoi_bytes).unwrap()
+}
+
+/// Creates a new Texture object from a QOI file, returning a 'TextureLoadError' if it can not be
+/// decoded.
+pub fn try_get_texture_from_qoi(qoi_bytes: &[u8]) -> Result<Texture, TextureLoadError> {
+    if !qoi_bytes.starts_with(b"qoif") {
+        return Err(TextureLoadError::InvalidSignature);
+    }
+    let header = qoi_bytes.get(..14).ok_or(TextureLoadError::UnexpectedEnd)?;
+    let width = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    let height = u32::from_be_bytes([header[8], header[9], header[10], header[11]]) as usize;
+    if !matches!(header[12], 3 | 4) || header[13] > 1 {
+        return Err(TextureLoadError::Corrupt {
+            reason: "invalid channels or colorspace".to_string(),
+        });
+    }
+    if width == 0 || height == 0 {
+        return Err(no_pixels());
+    }
+    // A single byte can encode a run of at most 62 pixels, so sizes the data can't hold are
+    // rejected before allocating the pixels.
+    let count = width
+        .checked_mul(height)
+        .filter(|count| count / 62 <= qoi_bytes.len())
+        .ok_or(TextureLoadError::UnexpectedEnd)?;
+
+    let mut pixels: Vec<[u8; 4]> = Vec::with_capacity(count);
+    let mut seen = [[0u8; 4]; 64];
+    let mut pixel = [0u8, 0, 0, 0xff];
+    let mut position = 14;
+    let mut next = || -> Result<u8, TextureLoadError> {
+        let byte = *qoi_bytes
+            .get(position)
+            .ok_or(TextureLoadError::UnexpectedEnd)?;
+        position += 1;
+        Ok(byte)
+    };
+    while pixels.len() < count {
+        let op = next()?;
+        let mut run = 1;
+        match op {
+            0xfe => pixel = [next()?, next()?, next()?, pixel[3]],
+            0xff => pixel = [next()?, next()?, next()?, next()?],
+            _ => match op >> 6 {
+                0 => pixel = seen[op as usize],
+                1 => {
+                    for (channel, shift) in [4, 2, 0].into_iter().enumerate() {
+                        let difference = ((op >> shift) & 3).wrapping_sub(2);
+                        pixel[channel] = pixel[channel].wrapping_add(difference);
+                    }
+                }
+                2 => {
+                    let green = (op & 0x3f).wrapping_sub(32);
+                    let byte = next()?;
+                    pixel[0] = pixel[0].wrapping_add(green.wrapping_add(byte >> 4).wrapping_sub(8));
+                    pixel[1] = pixel[1].wrapping_add(green);
+                    pixel[2] =
+                        pixel[2].wrapping_add(green.wrapping_add(byte & 0x0f).wrapping_sub(8));
+                }
+                _ => run = (op & 0x3f) as usize + 1,
+            },
+        }
+        let [r, g, b, a] = pixel;
+        let hash = (r as usize * 3 + g as usize * 5 + b as usize * 7 + a as usize * 11) % 64;
+        seen[hash] = pixel;
+        pixels.extend(std::iter::repeat_n(pixel, run.min(count - pixels.len())));
+    }
+    Ok(texture_from_rgba(width, height, &pixels))
+}
+
+/// Creates a new Texture object from a BMP, PNG, TGA, PPM, PGM or QOI file, choosing the decoder
+/// from the magic bytes at the start of the file. PNG files need the 'png' feature.
+///
+/// TGA files have no magic bytes, so any file not recognised as another format is decoded as TGA
+/// and rejected with 'TextureLoadError::InvalidSignature' if its header doesn't look like one.
+pub fn load_texture_auto(bytes: &[u8]) -> Result<Texture, TextureLoadError> {
+    match bytes {
+        [b'B', b'M', ..] => try_get_texture_from_bmp(bytes),
+        #[cfg(feature = "png")]
+        [0x89, b'P', b'N', b'G', ..] => try_get_texture_from_png(bytes),
+        #[cfg(not(feature = "png"))]
+        [0x89, b'P', b'N', b'G', ..] => Err(TextureLoadError::Unsupported {
+            feature: "PNG files without the 'png' feature".to_string(),
+        }),
+        [b'q', b'o', b'i', b'f', ..] => try_get_texture_from_qoi(bytes),
+        [b'P', b'1'..=b'7', ..] => try_get_texture_from_ppm(bytes),
+        _ => try_get_texture_from_tga(bytes),
+    }
+}
+
+/// Build a 'Texture' from the rgba color of every pixel in row order, top row first.
+fn texture_from_rgba(width: usize, height: usize, pixels: &[[u8; 4]]) -> Texture {
+    Texture {
+        width: width as u32,
+        height: height as u32,
+        pixels: pixels
+            .iter()
+            .map(|&[r, g, b, a]| color_pack(r, g, b, a))
+            .collect(),
+        ..Default::default()
+    }
+}
+
+/// The error for an image with a width or height of zero.
+fn no_pixels() -> TextureLoadError {
+    TextureLoadError::Corrupt {
+        reason: "image has no pixels".to_string(),
+    }
+}
+
+/// Convert a 'TinyBMP' parse error into a 'TextureLoadError'.
+fn bmp_error(error: ParseError) -> TextureLoadError {
+    let unsupported = |feature: String| TextureLoadError::Unsupported { feature };
+    match error {
+        ParseError::UnexpectedEndOfFile => TextureLoadError::UnexpectedEnd,
+        ParseError::InvalidFileSignature(_) => TextureLoadError::InvalidSignature,
+        ParseError::UnsupportedBpp(bpp) => unsupported(format!("{} bits per pixel", bpp)),
+        ParseError::UnsupportedCompressionMethod(method) => {
+            unsupported(format!("compression method {}", method))
+        }
+        ParseError::UnsupportedHeaderLength(length) => {
+            unsupported(format!("header length {}", length))
+        }
+        ParseError::UnsupportedChannelMasks => unsupported("channel masks".to_string()),
+        ParseError::InvalidImageDimensions => TextureLoadError::Corrupt {
+            reason: "invalid image dimensions".to_string(),
+        },
+    }
+}
+
+/// Convert a 15, 16, 24 or 32 bit TGA color into rgba. The alpha of 16 and 32 bit colors is only
+/// used when the image descriptor gives the image 'alpha_bits'.
+fn tga_color(bytes: &[u8], depth: u8, alpha_bits: u8) -> [u8; 4] {
+    match depth {
+        15 | 16 => {
+            let value = u16::from_le_bytes([bytes[0], bytes[1]]);
+            let channel = |shift: u16| (((value >> shift) & 0x1f) as u32 * 255 / 31) as u8;
+            let alpha = if depth == 16 && alpha_bits > 0 && value & 0x8000 == 0 {
+                0
+            } else {
+                0xff
+            };
+            [channel(10), channel(5), channel(0), alpha]
+        }
+        24 => [bytes[2], bytes[1], bytes[0], 0xff],
+        _ => [
+            bytes[2],
+            bytes[1],
+            bytes[0],
+            if alpha_bits > 0 { bytes[3] } else { 0xff },
+        ],
+    }
+}
+
+/// Read the next number of a netpbm header or plain text image at 'position', skipping any
+/// whitespace and comments before it.
+fn ppm_number(bytes: &[u8], position: &mut usize) -> Result<usize, TextureLoadError> {
+    while let Some(&byte) = bytes.get(*position) {
+        if byte == b'#' {
+            while bytes.get(*position).is_some_and(|&byte| byte != b'\n') {
+                *position += 1;
+            }
+        } else if byte.is_ascii_whitespace() {
+            *position += 1;
+        } else {
+            break;
+        }
+    }
+    let start = *position;
+    while bytes.get(*position).is_some_and(u8::is_ascii_digit) {
+        *position += 1;
+    }
+    if start == *position {
+        return Err(match bytes.get(start) {
+            Some(_) => TextureLoadError::Corrupt {
+                reason: "expected a number".to_string(),
+            },
+            None => TextureLoadError::UnexpectedEnd,
+        });
+    }
+    std::str::from_utf8(&bytes[start..*position])
+        .unwrap_or_default()
+        .parse()
+        .map_err(|_| TextureLoadError::Corrupt {
+            reason: "number too large".to_string(),
+        })
+}
+
+/// Reads the 8 bit alpha of every pixel of a 32 bit BMP file in row order, or 'None' if the image
+/// has no alpha channel.
+fn bmp_alpha(bmp: &RawBmp) -> Option<Vec<u8>> {
+    let header = bmp.header();
+    if header.bpp != Bpp::Bits32 {
+        return None;
+    }
+    // Without channel masks the alpha is held in the fourth byte of each pixel.
+    let mask = header
+        .channel_masks
+        .map_or(0xff00_0000, |masks| masks.alpha);
+    if mask == 0 {
+        return None;
+    }
+    let shift = mask.trailing_zeros();
+    let max = mask >> shift;
+    let width = header.image_size.width as usize;
+    let mut alpha = vec![0xff; width * header.image_size.height as usize];
+    let mut any_visible = false;
+    for RawPixel { position, color } in bmp.pixels() {
+        let a = (((color & mask) >> shift) as u64 * 255 / max as u64) as u8;
+        alpha[position.y as usize * width + position.x as usize] = a;
+        any_visible |= a != 0;
+    }
+    // Plain 32 bit BMP files usually leave the fourth byte unused and zeroed, so an image without
+    // channel masks that would be entirely invisible is treated as opaque instead.
+    if !any_visible && header.channel_masks.is_none() {
+        return None;
+    }
+    Some(alpha)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a TGA file with no image id from the header fields given, followed by 'data'.
+    fn tga_file(
+        image_type: u8,
+        color_map: Option<(u16, u8)>,
+        size: (u16, u16),
+        depth: u8,
+        descriptor: u8,
+        data: &[u8],
+    ) -> Vec<u8> {
+        let (map_length, map_depth) = color_map.unwrap_or((0, 0));
+        let mut tga = vec![0, color_map.is_some() as u8, image_type, 0, 0];
+        tga.extend_from_slice(&map_length.to_le_bytes());
+        tga.push(map_depth);
+        tga.extend_from_slice(&[0; 4]);
+        tga.extend_from_slice(&size.0.to_le_bytes());
+        tga.extend_from_slice(&size.1.to_le_bytes());
+        tga.extend_from_slice(&[depth, descriptor]);
+        tga.extend_from_slice(data);
+        tga
+    }
+
+    /// Packs each of the rgba 'colors' as the loaders do, for comparing with texture pixels.
+    fn packed(colors: &[[u8; 4]]) -> Vec<u32> {
+        colors
+            .iter()
+            .map(|&[r, g, b, a]| color_pack(r, g, b, a))
+            .collect()
+    }
+
+    const RED: [u8; 4] = [255, 0, 0, 0xff];
+    const GREEN: [u8; 4] = [0, 255, 0, 0xff];
+    const BLUE: [u8; 4] = [0, 0, 255, 0xff];
+
+    #[test]
+    fn tga_run_length_packets_cross_rows() {
+        // A run of four red pixels fills the bottom row and spills into the top row, then a raw
+        // packet holds a green and a blue pixel. Rows are stored bottom row first.
+        let data = [0x83, 0, 0, 255, 0x01, 0, 255, 0, 255, 0, 0];
+        let tga = tga_file(10, None, (3, 2), 24, 0, &data);
+        let texture = try_get_texture_from_tga(&tga).unwrap();
+        assert_eq!((texture.width, texture.height), (3, 2));
+        assert_eq!(texture.pixels, packed(&[RED, GREEN, BLUE, RED, RED, RED]));
+    }
+
+    #[test]
+    fn tga_color_mapped() {
+        let mut data = vec![0, 0, 255, 0, 255, 0, 255, 0, 0];
+        data.extend_from_slice(&[0, 1, 2, 1]);
+        let tga = tga_file(1, Some((3, 24)), (2, 2), 8, 0x20, &data);
+        let texture = try_get_texture_from_tga(&tga).unwrap();
+        assert_eq!(texture.pixels, packed(&[RED, GREEN, BLUE, GREEN]));
+
+        let out_of_range = tga_file(1, Some((1, 24)), (1, 1), 8, 0x20, &[0, 0, 255, 1]);
+        assert!(matches!(
+            try_get_texture_from_tga(&out_of_range),
+            Err(TextureLoadError::Corrupt { .. })
+        ));
+    }
+
+    #[test]
+    fn tga_top_left_origin_with_alpha() {
+        // 32 bit pixels stored top row first, with 8 alpha bits in the descriptor.
+        let data = [0, 0, 255, 255, 0, 255, 0, 128, 255, 0, 0, 64, 10, 20, 30, 0];
+        let tga = tga_file(2, None, (2, 2), 32, 0x28, &data);
+        let texture = try_get_texture_from_tga(&tga).unwrap();
+        assert_eq!(
+            texture.pixels,
+            packed(&[RED, [0, 255, 0, 128], [0, 0, 255, 64], [30, 20, 10, 0]])
+        );
+        assert_eq!(
+            try_get_texture_from_tga(&tga[..tga.len() - 1]).unwrap_err(),
+            TextureLoadError::UnexpectedEnd
+        );
+    }
+
+    /// A QOI file of eight pixels in a row, using every op.
+    fn qoi_file() -> Vec<u8> {
+        let mut qoi = b"qoif".to_vec();
+        qoi.extend_from_slice(&8u32.to_be_bytes());
+        qoi.extend_from_slice(&1u32.to_be_bytes());
+        qoi.extend_from_slice(&[4, 0]);
+        qoi.extend_from_slice(&[
+            0xfe, 10, 20, 30, // RGB
+            0xff, 50, 60, 70, 128,  // RGBA
+            0x09, // INDEX of the first pixel, which hashes to 9
+            0x5b, // DIFF of -1, 0, +1
+            0xaa, 0xa3, // LUMA with a green difference of 10, red 12 and blue 5
+            0xc2, // RUN of three more
+        ]);
+        qoi.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+        qoi
+    }
+
+    #[test]
+    fn qoi_every_op() {
+        let texture = try_get_texture_from_qoi(&qoi_file()).unwrap();
+        assert_eq!((texture.width, texture.height), (8, 1));
+        let last = [21, 30, 36, 0xff];
+        assert_eq!(
+            texture.pixels,
+            packed(&[
+                [10, 20, 30, 0xff],
+                [50, 60, 70, 128],
+                [10, 20, 30, 0xff],
+                [9, 20, 31, 0xff],
+                last,
+                last,
+                last,
+                last,
+            ])
+        );
+        let qoi = qoi_file();
+        assert_eq!(
+            try_get_texture_from_qoi(&qoi[..20]).unwrap_err(),
+            TextureLoadError::UnexpectedEnd
+        );
+    }
+
+    #[test]
+    fn ppm_plain_text_with_large_maximum() {
+        let texture = try_get_texture_from_ppm(b"P2\n2 1\n1000\n0 1000\n").unwrap();
+        assert_eq!(
+            texture.pixels,
+            packed(&[[0, 0, 0, 0xff], [255, 255, 255, 0xff]])
+        );
+        let texture =
+            try_get_texture_from_ppm(b"P3 # comment\n1 1 65535\n65535 32768 0\n").unwrap();
+        assert_eq!(texture.pixels, packed(&[[255, 127, 0, 0xff]]));
+        assert!(matches!(
+            try_get_texture_from_ppm(b"P2 1 1 1000 1001"),
+            Err(TextureLoadError::Corrupt { .. })
+        ));
+    }
+
+    #[test]
+    fn ppm_binary_with_two_byte_samples() {
+        let mut pgm = b"P5\n2 1\n300\n".to_vec();
+        pgm.extend_from_slice(&[0x01, 0x2c, 0x00, 0x96]);
+        let texture = try_get_texture_from_ppm(&pgm).unwrap();
+        assert_eq!(
+            texture.pixels,
+            packed(&[[255, 255, 255, 0xff], [127, 127, 127, 0xff]])
+        );
+        let mut ppm = b"P6\n1 1\n1023\n".to_vec();
+        ppm.extend_from_slice(&[0x03, 0xff, 0x00, 0x00, 0x02, 0x00]);
+        let texture = try_get_texture_from_ppm(&ppm).unwrap();
+        assert_eq!(texture.pixels, packed(&[[255, 0, 127, 0xff]]));
+        assert_eq!(
+            try_get_texture_from_ppm(&ppm[..ppm.len() - 1]).unwrap_err(),
+            TextureLoadError::UnexpectedEnd
+        );
+    }
+
+    #[test]
+    fn load_texture_auto_picks_the_decoder_from_the_magic_bytes() {
+        let qoi = load_texture_auto(&qoi_file()).unwrap();
+        assert_eq!(qoi.width, 8);
+        let ppm = load_texture_auto(b"P3 1 1 255 1 2 3").unwrap();
+        assert_eq!(ppm.pixels, packed(&[[1, 2, 3, 0xff]]));
+        let tga = load_texture_auto(&tga_file(2, None, (1, 1), 24, 0, &[3, 2, 1])).unwrap();
+        assert_eq!(tga.pixels, packed(&[[1, 2, 3, 0xff]]));
+
+        let mut frame = crate::framebuffer::Framebuffer::new(2, 1);
+        frame.set_color_at(&1, &0, color_pack(1, 2, 3, 0xff));
+        let mut bmp = Vec::new();
+        frame.write_bmp(&mut bmp).unwrap();
+        let bmp = load_texture_auto(&bmp).unwrap();
+        assert_eq!(bmp.pixels, packed(&[[0, 0, 0, 0xff], [1, 2, 3, 0xff]]));
+
+        let png = crate::png_writer::png_encode(1, 1, 3, &[1, 2, 3]);
+        #[cfg(feature = "png")]
+        assert_eq!(
+            load_texture_auto(&png).unwrap().pixels,
+            packed(&[[1, 2, 3, 0xff]])
+        );
+        #[cfg(not(feature = "png"))]
+        assert!(matches!(
+            load_texture_auto(&png),
+            Err(TextureLoadError::Unsupported { .. })
+        ));
+
+        assert!(matches!(
+            load_texture_auto(b"P4\n1 1\n\x00"),
+            Err(TextureLoadError::Unsupported { .. })
+        ));
+        assert_eq!(
+            load_texture_auto(b"GIF89a\x01\x00\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00")
+                .unwrap_err(),
+            TextureLoadError::InvalidSignature
+        );
+    }
 }